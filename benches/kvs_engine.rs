@@ -2,16 +2,20 @@
 extern crate criterion;
 extern crate kvs;
 
-use criterion::{black_box, BatchSize, BenchmarkId, Criterion};
+use criterion::{black_box, BatchSize, BenchmarkId, Criterion, Throughput};
 use rand::prelude::*;
 use rand::{Rng, SeedableRng};
 use std::path::Path;
+use std::thread;
 use tempfile::TempDir;
 
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
 
 static SET_ITERATION_COUNT: usize = 100;
 static GET_ITERATION_COUNT: usize = 100;
+static CONCURRENT_GET_KEY_COUNT: usize = 100;
+static CONCURRENT_GET_PER_THREAD: usize = 1000;
+static CONCURRENT_SET_PER_THREAD: usize = 100;
 // static MAX_KEY_SIZE: usize = 100000;
 // static MAX_VALUE_SIZE: usize = 100000;
 static MAX_KEY_SIZE: usize = 1000;
@@ -177,5 +181,115 @@ pub fn kvs_get_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, kvs_set_benchmark, kvs_get_benchmark);
+/// Throughput of many threads reading from one store at once, which
+/// should scale with the thread count now that gets don't serialize
+pub fn kvs_concurrent_get_benchmark(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv_store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    for i in 0..CONCURRENT_GET_KEY_COUNT {
+        kv_store
+            .set(format!("key{}", i), format!("value{}", i))
+            .expect("KvStore set failed");
+    }
+
+    let mut group = c.benchmark_group("concurrent get");
+    group.sample_size(20);
+
+    for threads in [1, 2, 4, 8, 16].iter() {
+        group.throughput(Throughput::Elements(
+            (threads * CONCURRENT_GET_PER_THREAD) as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::new("kv get", threads),
+            threads,
+            |b, &threads| {
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|thread_id| {
+                            let store = kv_store.clone();
+                            thread::spawn(move || {
+                                for i in 0..CONCURRENT_GET_PER_THREAD {
+                                    let key_id = (i + thread_id) % CONCURRENT_GET_KEY_COUNT;
+                                    store
+                                        .get(black_box(format!("key{}", key_id)))
+                                        .expect("failed to fetch key");
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Set distinct keys from `threads` threads at once
+fn concurrent_set<E: KvsEngine>(engine: &E, threads: usize) {
+    let handles: Vec<_> = (0..threads)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..CONCURRENT_SET_PER_THREAD {
+                    engine
+                        .set(
+                            black_box(format!("key{}-{}", thread_id, i)),
+                            black_box(format!("value{}", i)),
+                        )
+                        .expect("set failed");
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// Throughput of many threads writing at once with every write synced,
+/// which should scale with the thread count as writers share commits
+pub fn kvs_concurrent_set_benchmark(c: &mut Criterion) {
+    let kv_temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kv_store = KvStore::open_with(
+        kv_temp_dir.path(),
+        KvStoreOptions::new().sync_policy(SyncPolicy::Always),
+    )
+    .expect("can't open KvStore");
+    let sled_temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_store = SledKvsEngine::open_with(sled_temp_dir.path(), SyncPolicy::Always)
+        .expect("can't open sled db");
+
+    let mut group = c.benchmark_group("concurrent set");
+    group.sample_size(10);
+
+    for threads in [1, 2, 4, 8, 16].iter() {
+        group.throughput(Throughput::Elements(
+            (threads * CONCURRENT_SET_PER_THREAD) as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::new("kv set", threads),
+            threads,
+            |b, &threads| b.iter(|| concurrent_set(&kv_store, threads)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("sled set", threads),
+            threads,
+            |b, &threads| b.iter(|| concurrent_set(&sled_store, threads)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    kvs_set_benchmark,
+    kvs_get_benchmark,
+    kvs_concurrent_get_benchmark,
+    kvs_concurrent_set_benchmark
+);
 criterion_main!(benches);
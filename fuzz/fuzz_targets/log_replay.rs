@@ -0,0 +1,40 @@
+#![no_main]
+use kvs::testing::SimulatedFileSystem;
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The files of a store with one sealed log and the active one
+#[derive(Arbitrary, Debug)]
+struct Files {
+    sealed_log: Vec<u8>,
+    sealed_hint: Vec<u8>,
+    active_log: Vec<u8>,
+}
+
+fuzz_target!(|files: Files| {
+    let dirpath = Path::new("/store");
+    let file_system = Arc::new(SimulatedFileSystem::new());
+    file_system.write_file(&dirpath.join("MANIFEST"), b"kvs-manifest 1\n1\n2\n");
+    file_system.write_file(&dirpath.join("1.log"), &files.sealed_log);
+    file_system.write_file(&dirpath.join("1.hint"), &files.sealed_hint);
+    file_system.write_file(&dirpath.join("2.log"), &files.active_log);
+
+    let options = KvStoreOptions::new().file_system(file_system);
+    let store = match KvStore::open_with(dirpath, options) {
+        Ok(store) => store,
+        Err(_) => return,
+    };
+
+    // Whatever was recovered can be read back, written to and compacted
+    if let Ok(pairs) = store.scan_bytes(..) {
+        for (key, _) in pairs.flatten() {
+            let _ = store.get_bytes(&key);
+            let _ = store.ttl_bytes(&key);
+        }
+    }
+    let _ = store.set_bytes(b"key".to_vec(), b"value".to_vec());
+    let _ = store.compact();
+});
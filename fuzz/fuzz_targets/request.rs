@@ -0,0 +1,11 @@
+#![no_main]
+use kvs::Command;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Anything the binary protocol accepts has exactly one encoding
+    if let Ok(command) = Command::decode(data) {
+        assert_eq!(command.encode(), data);
+    }
+    let _ = Command::parse_text(data);
+});
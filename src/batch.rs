@@ -0,0 +1,80 @@
+/// One write in a `WriteBatch`
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    /// Set a key to a value
+    Set(Vec<u8>, Vec<u8>),
+    /// Remove a key. Unlike `KvsEngine::remove` it's fine if the key doesn't exist
+    Remove(Vec<u8>),
+}
+
+/// Writes which `KvsEngine::write_batch` applies all at once: after a crash
+/// either every one of them is there or none are
+/// ```rust
+/// extern crate kvs;
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+/// # use std::error::Error;
+/// #
+/// # fn main() -> Result<(), Box<Error>> {
+/// let temp_dir = TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "10".to_owned())?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove("from".to_owned());
+/// batch.set("to".to_owned(), "10".to_owned());
+/// store.write_batch(batch)?;
+///
+/// assert_eq!(store.get("from".to_owned())?, None);
+/// assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// An empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a key to a value once the batch is written
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+    }
+
+    /// Remove a key once the batch is written
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) {
+        self.ops.push(BatchOp::Remove(key.into()));
+    }
+
+    /// The writes in the order they were added. Later writes to a key win
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no writes in it
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Take the writes out of the batch
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> Self {
+        Self { ops }
+    }
+}
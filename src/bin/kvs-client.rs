@@ -1,12 +1,15 @@
 extern crate clap;
 extern crate kvs;
 
+use std::fs;
 use std::io;
+use std::io::prelude::*;
 use std::process;
+use std::time::Duration;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
-use kvs::{Command, KvsClient};
+use kvs::{Command, KvStoreError, KvsClient, Response, Ttl};
 
 fn main() -> io::Result<()> {
     let addr_arg = Arg::with_name("addr")
@@ -14,6 +17,18 @@ fn main() -> io::Result<()> {
         .long("addr")
         .help("address to connect to in IP:PORT format")
         .takes_value(true);
+    let hex_arg = Arg::with_name("hex")
+        .long("hex")
+        .help("values are given and shown in hex")
+        .conflicts_with("base64");
+    let base64_arg = Arg::with_name("base64")
+        .long("base64")
+        .help("values are given and shown in base64");
+    let file_arg = Arg::with_name("file")
+        .long("file")
+        .help("read the value to set from a file")
+        .takes_value(true)
+        .conflicts_with("value");
 
     let matches = App::new("KvStore")
         .about("key value store")
@@ -28,6 +43,8 @@ fn main() -> io::Result<()> {
                         .index(1)
                         .required(true),
                 )
+                .arg(hex_arg.clone())
+                .arg(base64_arg.clone())
                 .arg(addr_arg.clone()),
         )
         .subcommand(
@@ -43,6 +60,26 @@ fn main() -> io::Result<()> {
                     Arg::with_name("value")
                         .help("the value to set to")
                         .index(2)
+                        .required_unless("file"),
+                )
+                .arg(hex_arg.clone())
+                .arg(base64_arg.clone())
+                .arg(file_arg.clone())
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .help("remove the key after this many seconds")
+                        .takes_value(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("show how many seconds a key has left")
+                .arg(
+                    Arg::with_name("key")
+                        .help("the key to inspect")
+                        .index(1)
                         .required(true),
                 )
                 .arg(addr_arg.clone()),
@@ -58,6 +95,53 @@ fn main() -> io::Result<()> {
                 )
                 .arg(addr_arg.clone()),
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("set or remove a key only if it has the expected value")
+                .arg(
+                    Arg::with_name("key")
+                        .help("the key to swap")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .help("the value to set to, removing the key if left out")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .help("the value the key must have, otherwise it mustn't exist")
+                        .takes_value(true),
+                )
+                .arg(hex_arg.clone())
+                .arg(base64_arg.clone())
+                .arg(file_arg.clone())
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("list keys and their values in key order")
+                .arg(
+                    Arg::with_name("prefix")
+                        .help("only list keys starting with this")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .help("list at most this many keys, printing a cursor if there are more")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .long("cursor")
+                        .help("continue a limited scan from a cursor it printed")
+                        .takes_value(true),
+                )
+                .arg(addr_arg.clone()),
+        )
         .subcommand(
             SubCommand::with_name("exit")
                 .about("causes the server to exit")
@@ -71,26 +155,58 @@ fn main() -> io::Result<()> {
 
     let default_addr = "127.0.0.1:4000";
 
+    if let Some(matches) = matches.subcommand_matches("scan") {
+        let addr = matches.value_of("addr").unwrap_or(default_addr);
+        let limit = match matches.value_of("limit").map(str::parse) {
+            None => None,
+            Some(Ok(limit)) => Some(limit),
+            Some(Err(_)) => {
+                eprintln!("Invalid limit");
+                process::exit(1);
+            }
+        };
+        let prefix = matches.value_of("prefix").unwrap_or("").into();
+        let cursor = matches.value_of("cursor").map(Vec::from);
+        return scan(addr, prefix, cursor, limit);
+    }
+
+    let mut encoding = Encoding::Raw;
     let arg_results = if let Some(matches) = matches.subcommand_matches("get") {
         let addr = matches.value_of("addr").unwrap_or(default_addr);
-        Some((
-            addr,
-            Command::Get(matches.value_of("key").unwrap().to_owned()),
-        ))
+        encoding = Encoding::of(matches);
+        Some((addr, Command::Get(key_arg(matches))))
     } else if let Some(matches) = matches.subcommand_matches("set") {
         let addr = matches.value_of("addr").unwrap_or(default_addr);
-        Some((
-            addr,
-            Command::Set(
-                matches.value_of("key").unwrap().to_owned(),
-                matches.value_of("value").unwrap().to_owned(),
-            ),
-        ))
+        let key = key_arg(matches);
+        let value = value_arg(matches).unwrap_or_default();
+        let command = match matches.value_of("ttl").map(str::parse) {
+            None => Command::Set(key, value),
+            Some(Ok(seconds)) => Command::SetWithTtl(key, value, Duration::from_secs(seconds)),
+            Some(Err(_)) => {
+                eprintln!("Invalid TTL");
+                process::exit(1);
+            }
+        };
+        Some((addr, command))
+    } else if let Some(matches) = matches.subcommand_matches("ttl") {
+        let addr = matches.value_of("addr").unwrap_or(default_addr);
+        Some((addr, Command::Ttl(key_arg(matches))))
     } else if let Some(matches) = matches.subcommand_matches("rm") {
         let addr = matches.value_of("addr").unwrap_or(default_addr);
+        Some((addr, Command::Remove(key_arg(matches))))
+    } else if let Some(matches) = matches.subcommand_matches("cas") {
+        let addr = matches.value_of("addr").unwrap_or(default_addr);
+        encoding = Encoding::of(matches);
+        let expected = matches
+            .value_of("expected")
+            .map(|expected| encoding.decode(expected));
         Some((
             addr,
-            Command::Remove(matches.value_of("key").unwrap().to_owned()),
+            Command::Cas {
+                key: key_arg(matches),
+                expected,
+                new: value_arg(matches),
+            },
         ))
     } else if let Some(matches) = matches.subcommand_matches("exit") {
         let addr = matches.value_of("addr").unwrap_or(default_addr);
@@ -101,20 +217,27 @@ fn main() -> io::Result<()> {
 
     match arg_results {
         Some((addr, command)) => {
-            let mut client = KvsClient::new(addr.to_owned())?;
+            let mut client = KvsClient::new(addr.to_owned()).unwrap_or_else(|err| fail(err));
             let result = client.send(command);
             match result {
-                Err(err) => {
-                    eprintln!("Error: {}", err);
-                    process::exit(1);
+                Err(err) => fail(err),
+                Ok(Response::Value(Some(value))) => print_value(&encoding.encode(value))?,
+                Ok(Response::Value(None)) => println!("Key not found"),
+                Ok(Response::Ttl(None)) => println!("Key not found"),
+                Ok(Response::Ttl(Some(Ttl::Forever))) => println!("No expiry"),
+                Ok(Response::Ttl(Some(Ttl::Remaining(remaining)))) => {
+                    // Rounded up so a key which is still there never shows 0
+                    let millis = remaining.as_millis() as u64;
+                    println!("{}", millis.div_ceil(1000));
                 }
-                Ok(response) => {
-                    if response == "NONE" {
-                        println!("Key not found");
-                    } else if response.trim() != "" {
-                        println!("{}", response);
+                Ok(Response::Mismatch(current)) => {
+                    match current {
+                        Some(current) => print_value(&encoding.encode(current))?,
+                        None => println!("Key not found"),
                     }
+                    process::exit(1);
                 }
+                Ok(_) => {}
             }
         }
         None => {
@@ -124,3 +247,155 @@ fn main() -> io::Result<()> {
     }
     Ok(())
 }
+
+/// Print keys and their values a tab apart, fetching every page of the scan
+/// unless a limit was given, in which case the cursor for the rest is printed
+fn scan(
+    addr: &str,
+    prefix: Vec<u8>,
+    mut cursor: Option<Vec<u8>>,
+    limit: Option<u32>,
+) -> io::Result<()> {
+    let mut client = KvsClient::new(addr.to_owned()).unwrap_or_else(|err| fail(err));
+    let mut remaining = limit;
+    loop {
+        let command = Command::Scan {
+            prefix: prefix.clone(),
+            cursor: cursor.take(),
+            limit: remaining.unwrap_or(u32::MAX),
+        };
+        let (pairs, next_cursor) = match client.send(command) {
+            Ok(Response::Pairs { pairs, cursor }) => (pairs, cursor),
+            Ok(response) => {
+                eprintln!("Error: Unexpected response {:?}", response);
+                process::exit(1);
+            }
+            Err(err) => fail(err),
+        };
+
+        {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for (key, value) in &pairs {
+                stdout.write_all(key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(value)?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        if let Some(remaining) = remaining.as_mut() {
+            *remaining -= (pairs.len() as u32).min(*remaining);
+        }
+
+        cursor = next_cursor;
+        match (&cursor, remaining) {
+            (None, _) => return Ok(()),
+            (Some(cursor), Some(0)) => {
+                eprintln!("Next cursor: {}", String::from_utf8_lossy(cursor));
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Print an error and exit with the status for its kind, so scripts can
+/// tell a missing key from a server which is down
+fn fail(err: KvStoreError) -> ! {
+    eprintln!("Error: {}", err);
+    let status = match err {
+        KvStoreError::NonExistentKeyError(_) => 2,
+        KvStoreError::InvalidRequest(_) | KvStoreError::ProtocolError(_) => 3,
+        KvStoreError::TransactionConflict => 4,
+        KvStoreError::ServerBusy(_) => 5,
+        KvStoreError::Unauthenticated(_) => 6,
+        KvStoreError::RemoteIo(_) => 7,
+        KvStoreError::RemoteCorruption(_) => 8,
+        // Couldn't reach the server or lost the connection
+        KvStoreError::Io(_) => 9,
+        _ => 1,
+    };
+    process::exit(status);
+}
+
+/// How values are written on the command line
+#[derive(Clone, Copy, Debug)]
+enum Encoding {
+    Raw,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    fn of(matches: &ArgMatches) -> Self {
+        if matches.is_present("hex") {
+            Encoding::Hex
+        } else if matches.is_present("base64") {
+            Encoding::Base64
+        } else {
+            Encoding::Raw
+        }
+    }
+
+    /// Decode a value given on the command line, exiting if it's malformed
+    fn decode(self, value: &str) -> Vec<u8> {
+        let decoded = match self {
+            Encoding::Raw => Some(value.as_bytes().to_vec()),
+            Encoding::Hex => decode_hex(value),
+            Encoding::Base64 => base64::decode(value).ok(),
+        };
+        decoded.unwrap_or_else(|| {
+            eprintln!("Invalid value");
+            process::exit(1);
+        })
+    }
+
+    /// Encode a value to be printed
+    fn encode(self, value: Vec<u8>) -> Vec<u8> {
+        match self {
+            Encoding::Raw => value,
+            Encoding::Hex => value
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+                .into_bytes(),
+            Encoding::Base64 => base64::encode(&value).into_bytes(),
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn key_arg(matches: &ArgMatches) -> Vec<u8> {
+    matches.value_of("key").unwrap().into()
+}
+
+/// The value to write, read from `--file` or decoded from the command line
+fn value_arg(matches: &ArgMatches) -> Option<Vec<u8>> {
+    if let Some(path) = matches.value_of("file") {
+        return Some(fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Error reading {}: {}", path, err);
+            process::exit(1);
+        }));
+    }
+    let encoding = Encoding::of(matches);
+    matches
+        .value_of("value")
+        .map(|value| encoding.decode(value))
+}
+
+/// Print a value exactly as it's stored, followed by a newline
+fn print_value(value: &[u8]) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(value)?;
+    stdout.write_all(b"\n")
+}
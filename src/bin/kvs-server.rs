@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate clap;
 
 #[macro_use]
@@ -11,13 +12,17 @@ use std::fs;
 use std::io;
 use std::path::Path;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use num_cpus;
+use slog::Logger;
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
 
-use kvs::{KvStore, KvsServer, RayonThreadPool, SharedQueueThreadPool, SledKvsEngine, ThreadPool};
+use kvs::{
+    CompactionPolicy, KvStore, KvStoreOptions, KvsEngine, KvsServer, RayonThreadPool,
+    SharedQueueThreadPool, SledKvsEngine, SyncPolicy, ThreadPool,
+};
 
 fn get_engine(engine_path: &Path) -> io::Result<Option<String>> {
     match fs::read_to_string(engine_path) {
@@ -27,7 +32,107 @@ fn get_engine(engine_path: &Path) -> io::Result<Option<String>> {
     }
 }
 
+/// The sync policy given on the command line, if any
+fn sync_policy(matches: &ArgMatches<'_>) -> Option<SyncPolicy> {
+    if matches.is_present("sync") {
+        Some(value_t_or_exit!(matches, "sync", SyncPolicy))
+    } else {
+        None
+    }
+}
+
+/// Build the kvs engine's options from the command line, leaving anything
+/// which wasn't given at its default
+fn kvs_options(matches: &ArgMatches<'_>) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new().read_only(matches.is_present("read-only"));
+
+    if let Some(sync_policy) = sync_policy(matches) {
+        options = options.sync_policy(sync_policy);
+    }
+
+    if matches.is_present("max-segment-size") {
+        options = options.max_segment_size(value_t_or_exit!(matches, "max-segment-size", u64));
+    }
+    if matches.is_present("read-buffer-size") {
+        options = options.read_buffer_size(value_t_or_exit!(matches, "read-buffer-size", usize));
+    }
+
+    let mut compaction_policy = CompactionPolicy::default();
+    if matches.is_present("compaction-min-dead-bytes") {
+        compaction_policy.min_dead_bytes =
+            value_t_or_exit!(matches, "compaction-min-dead-bytes", u64);
+    }
+    if matches.is_present("compaction-dead-ratio") {
+        compaction_policy.dead_bytes_ratio =
+            value_t_or_exit!(matches, "compaction-dead-ratio", f64);
+    }
+    options.compaction_policy(compaction_policy)
+}
+
+/// The signals which stop the server gracefully
+#[cfg(unix)]
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        signals
+    }
+}
+
+/// Block the shutdown signals in this thread and every thread it goes on to
+/// spawn, so they stay pending until `wait_for_shutdown_signal` takes them
+#[cfg(unix)]
+fn block_shutdown_signals() -> io::Result<()> {
+    let signals = shutdown_signals();
+    let errno = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    if errno != 0 {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    Ok(())
+}
+
+/// Wait for a shutdown signal, returning its number
+#[cfg(unix)]
+fn wait_for_shutdown_signal() -> io::Result<i32> {
+    let signals = shutdown_signals();
+    let mut signal = 0;
+    let errno = unsafe { libc::sigwait(&signals, &mut signal) };
+    if errno != 0 {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    Ok(signal)
+}
+
+/// Serve until a client sends EXIT or, on unix, a shutdown signal arrives
+fn serve<E: KvsEngine>(
+    mut server: KvsServer<E>,
+    thread_pool: SharedQueueThreadPool,
+    logger: Logger,
+) -> io::Result<()> {
+    let handle = server.start(thread_pool)?;
+
+    #[cfg(unix)]
+    std::thread::spawn(move || match wait_for_shutdown_signal() {
+        Ok(signal) => {
+            info!(logger, "received shutdown signal"; "signal" => signal);
+            server.stop();
+        }
+        Err(e) => error!(logger, "error waiting for shutdown signal"; "error" => %&e),
+    });
+    #[cfg(not(unix))]
+    drop((server, logger));
+
+    handle.join().expect("server thread panicked");
+    Ok(())
+}
+
 fn main() -> io::Result<()> {
+    // Before the logger or anything else has spawned a thread
+    #[cfg(unix)]
+    block_shutdown_signals()?;
+
     let mut builder = TerminalLoggerBuilder::new();
     builder.level(Severity::Debug);
     builder.destination(Destination::Stderr);
@@ -60,6 +165,49 @@ fn main() -> io::Result<()> {
                 .help("the directory to store data in")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("max-segment-size")
+                .long("max-segment-size")
+                .value_name("BYTES")
+                .help("start a new log file once the active one is this big (kvs engine)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compaction-min-dead-bytes")
+                .long("compaction-min-dead-bytes")
+                .value_name("BYTES")
+                .help("only compact once at least this many bytes are dead (kvs engine)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compaction-dead-ratio")
+                .long("compaction-dead-ratio")
+                .value_name("RATIO")
+                .help("compact once this fraction of the logs is dead (kvs engine)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help(
+                    "when writes are synced to disk before being acknowledged: \
+                     always, never, or at most every <N>ms",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-buffer-size")
+                .long("read-buffer-size")
+                .value_name("BYTES")
+                .help("buffer size used when replaying log files (kvs engine)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("serve reads without ever writing to the data directory (kvs engine)"),
+        )
         .get_matches();
 
     let data_path = Path::new(matches.value_of("data-path").unwrap_or("./"));
@@ -84,25 +232,27 @@ fn main() -> io::Result<()> {
         ));
     }
 
-    fs::write(&engine_path, engine_opt.as_bytes())?;
+    if !matches.is_present("read-only") {
+        fs::write(&engine_path, engine_opt.as_bytes())?;
+    }
 
     // let thread_pool = RayonThreadPool::new(num_cpus::get().try_into().unwrap()).unwrap();
     let thread_pool = SharedQueueThreadPool::new(num_cpus::get().try_into().unwrap()).unwrap();
 
     // TODO: better else condition?
-    let handle = if engine_opt == "kvs" {
-        let store = KvStore::open(data_path).expect("can't open KvStore");
-        let mut server = KvsServer::new(addr, store, logger);
-        server.start(thread_pool)?
+    if engine_opt == "kvs" {
+        let options = kvs_options(&matches);
+        info!(logger, "kvs options"; "options" => format!("{:?}", &options));
+        let store = KvStore::open_with(data_path, options).expect("can't open KvStore");
+        let server = KvsServer::new(addr, store, logger.clone());
+        serve(server, thread_pool, logger)
     } else if engine_opt == "sled" {
-        let store = SledKvsEngine::open(data_path).expect("can't open sled db");
-        let mut server = KvsServer::new(addr, store, logger);
-        server.start(thread_pool)?
+        let sync_policy = sync_policy(&matches).unwrap_or_default();
+        info!(logger, "sled options"; "sync_policy" => %sync_policy);
+        let store = SledKvsEngine::open_with(data_path, sync_policy).expect("can't open sled db");
+        let server = KvsServer::new(addr, store, logger.clone());
+        serve(server, thread_pool, logger)
     } else {
         panic!("server_opt not properly specified");
-    };
-
-    handle.join().unwrap();
-
-    Ok(())
+    }
 }
@@ -1,74 +1,76 @@
 use crate::errors::{KvStoreError, Result};
+use crate::protocol::{read_frame, write_frame, Command, Response, PROTOCOL_MAGIC};
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
 use std::net::TcpStream;
 
-/// A KvsServer command
-#[derive(Debug)]
-pub enum Command {
-    /// KvsServer GET command
-    Get(String),
-    /// KvsServer SET command
-    Set(String, String),
-    /// KvsServer REMOVE command
-    Remove(String),
-    /// KvsServer EXIT command for prompting server to exit
-    Exit,
-}
+/// The most requests `KvsClient::pipeline` will have in flight at once.
+/// Bounding this keeps both sides' socket buffers from filling up and
+/// deadlocking a very large pipeline
+const PIPELINE_WINDOW: usize = 128;
 
-/// A Client for sending commands to a KvsServer
+/// A Client for sending commands to a KvsServer over a single
+/// persistent connection
 #[derive(Debug)]
 pub struct KvsClient {
+    reader: BufReader<TcpStream>,
     stream: TcpStream,
 }
 
 impl KvsClient {
     /// Create a new KvsClient
     pub fn new(addr: String) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(Self { stream })
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&[PROTOCOL_MAGIC])?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self { reader, stream })
     }
 
-    fn serialize(&self, command: Command) -> String {
-        match command {
-            Command::Get(key) => format!("GET:{}", key),
-            Command::Set(key, value) => format!("SET:{}:{}", key, value,),
-            Command::Remove(key) => format!("REMOVE:{}", key),
-            Command::Exit => format!("EXIT"),
-        }
+    /// Send a command to the KvsServer and wait for its response.
+    /// Error responses from the server are returned as the `KvStoreError`
+    /// matching their `ErrorCode`
+    pub fn send(&mut self, command: Command) -> Result<Response> {
+        write_frame(&mut self.stream, &command.encode())?;
+        self.stream.flush()?;
+        self.receive()
     }
 
-    /// Send a command to the KvsServer where the result string is the success response
-    /// from the server
-    pub fn send(&mut self, command: Command) -> Result<String> {
-        let serialized = self.serialize(command);
-        self.stream.write_all(serialized.as_bytes())?;
-        self.stream.write_all(&b"\n".to_owned())?;
-        self.stream.flush()?;
+    /// Send many commands without waiting for each response in between,
+    /// returning the responses in the same order as the commands
+    pub fn pipeline(&mut self, commands: Vec<Command>) -> Result<Vec<Result<Response>>> {
+        let mut responses = Vec::with_capacity(commands.len());
 
-        let mut incoming_string = String::new();
-        self.stream.read_to_string(&mut incoming_string)?;
+        for window in commands.chunks(PIPELINE_WINDOW) {
+            {
+                let mut writer = BufWriter::new(&mut self.stream);
+                for command in window {
+                    write_frame(&mut writer, &command.encode())?;
+                }
+                writer.flush()?;
+            }
 
-        self.handle_responses(incoming_string)
+            for _ in window {
+                let response = self.receive();
+                // A broken connection fails every remaining request, so bail out
+                if let Err(KvStoreError::Io(e)) = response {
+                    return Err(KvStoreError::Io(e));
+                }
+                responses.push(response);
+            }
+        }
+
+        Ok(responses)
     }
 
-    fn handle_responses(&self, incoming: String) -> Result<String> {
-        let mut sections = incoming.trim_end().split(':');
-        let success_string = sections.next();
+    /// Read the next response off the connection
+    fn receive(&mut self) -> Result<Response> {
+        let payload = read_frame(&mut self.reader)?.ok_or_else(|| {
+            KvStoreError::ClientError("Error: Didn't receive any response from server".to_owned())
+        })?;
 
-        if let Some(success_string) = success_string {
-            let response = sections
-                .next()
-                .map(|v| String::from_utf8(base64::decode(v).unwrap()).unwrap())
-                .unwrap_or_else(|| "Undefined response from server".to_owned());
-            if success_string == "OK" {
-                Ok(response)
-            } else {
-                Err(KvStoreError::ClientError(response))
-            }
-        } else {
-            Err(KvStoreError::ClientError(
-                "Error: Didn't receive any response from server".to_owned(),
-            ))
+        match Response::decode(&payload)? {
+            Response::Err(code, message) => Err(KvStoreError::from_response(code, message)),
+            response => Ok(response),
         }
     }
 }
@@ -1,36 +1,93 @@
+use crate::protocol::ErrorCode;
 use sled;
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::result;
+use std::string::FromUtf8Error;
 
+/// Errors returned by the key value stores, server and client
 #[derive(Debug)]
 pub enum KvStoreError {
+    /// An underlying IO error
     Io(io::Error),
-    EncoderError(bson::EncoderError),
-    DecoderError(bson::DecoderError),
+    /// Error from the sled engine
     SledError(sled::Error),
+    /// Tried to remove a key which isn't in the store
     NonExistentKeyError(String),
+    /// A record couldn't be serialized
     SerializationError(String),
+    /// A lock or channel was poisoned or closed
     LockError(String),
+    /// The server answered a client request with an error which has no
+    /// more specific variant
     ClientError(String),
+    /// The server's store hit an I/O error answering a client request
+    RemoteIo(String),
+    /// The server's store found corrupt data answering a client request
+    RemoteCorruption(String),
+    /// The server rejected a client request as malformed or out of place
+    InvalidRequest(String),
+    /// The server was too busy to answer a client request
+    ServerBusy(String),
+    /// The server needs the client to authenticate first
+    Unauthenticated(String),
+    /// A malformed wire protocol message
+    ProtocolError(String),
+    /// A log file record failed its checksum
+    Corruption {
+        /// The damaged log file
+        path: PathBuf,
+        /// Offset of the damaged record within the file
+        offset: u64,
+    },
+    /// A log file doesn't start with the header this version of kvs writes,
+    /// as with logs from before records were checksummed
+    UnknownLogFormat(PathBuf),
+    /// Tried to write to a store opened read-only
+    ReadOnly,
+    /// A key or value read through the `String` methods isn't valid UTF-8
+    InvalidUtf8(FromUtf8Error),
+    /// A transaction wasn't committed because a key it wrote was written
+    /// by another commit since it began
+    TransactionConflict,
 }
 
 impl From<KvStoreError> for io::Error {
     fn from(err: KvStoreError) -> Self {
         match err {
             KvStoreError::Io(err) => err,
-            KvStoreError::EncoderError(err) => {
-                io::Error::new(io::ErrorKind::Other, err.description())
+            KvStoreError::SledError(sled::Error::Io(err)) => err,
+            KvStoreError::SledError(err) => io::Error::other(err),
+            KvStoreError::NonExistentKeyError(err) => io::Error::other(err),
+            KvStoreError::SerializationError(err) => io::Error::other(err),
+            KvStoreError::LockError(err) => io::Error::other(err),
+            KvStoreError::ClientError(err) => io::Error::other(err),
+            KvStoreError::RemoteIo(err) => io::Error::other(err),
+            KvStoreError::RemoteCorruption(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            KvStoreError::InvalidRequest(err) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            KvStoreError::ServerBusy(err) => io::Error::new(io::ErrorKind::WouldBlock, err),
+            KvStoreError::Unauthenticated(err) => {
+                io::Error::new(io::ErrorKind::PermissionDenied, err)
             }
-            KvStoreError::DecoderError(err) => {
-                io::Error::new(io::ErrorKind::Other, err.description())
+            KvStoreError::ProtocolError(err) => io::Error::other(err),
+            KvStoreError::Corruption { path, offset } => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Corrupt record in {:?} at offset {}", path, offset),
+            ),
+            KvStoreError::UnknownLogFormat(path) => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} isn't a log this version of kvs can read", path),
+            ),
+            KvStoreError::ReadOnly => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Store was opened read-only",
+            ),
+            KvStoreError::InvalidUtf8(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+            KvStoreError::TransactionConflict => {
+                io::Error::other("Transaction conflicts with a later commit")
             }
-            KvStoreError::SledError(err) => io::Error::new(io::ErrorKind::Other, err.description()),
-            KvStoreError::NonExistentKeyError(err) => io::Error::new(io::ErrorKind::Other, err),
-            KvStoreError::SerializationError(err) => io::Error::new(io::ErrorKind::Other, err),
-            KvStoreError::LockError(err) => io::Error::new(io::ErrorKind::Other, err),
-            KvStoreError::ClientError(err) => io::Error::new(io::ErrorKind::Other, err),
         }
     }
 }
@@ -41,15 +98,9 @@ impl From<io::Error> for KvStoreError {
     }
 }
 
-impl From<bson::EncoderError> for KvStoreError {
-    fn from(err: bson::EncoderError) -> KvStoreError {
-        KvStoreError::EncoderError(err)
-    }
-}
-
-impl From<bson::DecoderError> for KvStoreError {
-    fn from(err: bson::DecoderError) -> KvStoreError {
-        KvStoreError::DecoderError(err)
+impl From<FromUtf8Error> for KvStoreError {
+    fn from(err: FromUtf8Error) -> KvStoreError {
+        KvStoreError::InvalidUtf8(err)
     }
 }
 
@@ -61,7 +112,17 @@ impl From<sled::Error> for KvStoreError {
 
 impl fmt::Display for KvStoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.description())
+        match self {
+            KvStoreError::Io(err) => write!(f, "{}", err),
+            KvStoreError::SledError(err) => write!(f, "{}", err),
+            KvStoreError::Corruption { path, offset } => {
+                write!(f, "Corrupt record in {:?} at offset {}", path, offset)
+            }
+            KvStoreError::UnknownLogFormat(path) => {
+                write!(f, "{:?} isn't a log this version of kvs can read", path)
+            }
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
@@ -69,26 +130,93 @@ impl Error for KvStoreError {
     fn description(&self) -> &str {
         match self {
             KvStoreError::Io(err) => err.description(),
-            KvStoreError::EncoderError(err) => err.description(),
-            KvStoreError::DecoderError(err) => err.description(),
             KvStoreError::SledError(err) => err.description(),
             KvStoreError::NonExistentKeyError(string) => string,
             KvStoreError::SerializationError(string) => string,
             KvStoreError::LockError(string) => string,
             KvStoreError::ClientError(string) => string,
+            KvStoreError::RemoteIo(string) => string,
+            KvStoreError::RemoteCorruption(string) => string,
+            KvStoreError::InvalidRequest(string) => string,
+            KvStoreError::ServerBusy(string) => string,
+            KvStoreError::Unauthenticated(string) => string,
+            KvStoreError::ProtocolError(string) => string,
+            KvStoreError::Corruption { .. } => "Corrupt record in log file",
+            KvStoreError::UnknownLogFormat(_) => "Log file is in an unknown format",
+            KvStoreError::ReadOnly => "Store was opened read-only",
+            KvStoreError::InvalidUtf8(_) => "Key or value isn't valid UTF-8",
+            KvStoreError::TransactionConflict => "Transaction conflicts with a later commit",
         }
     }
 
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             KvStoreError::Io(err) => Some(err),
-            KvStoreError::EncoderError(err) => Some(err),
-            KvStoreError::DecoderError(err) => Some(err),
             KvStoreError::SledError(err) => Some(err),
             KvStoreError::NonExistentKeyError(_) => None,
             KvStoreError::SerializationError(_) => None,
             KvStoreError::LockError(_) => None,
             KvStoreError::ClientError(_) => None,
+            KvStoreError::RemoteIo(_) => None,
+            KvStoreError::RemoteCorruption(_) => None,
+            KvStoreError::InvalidRequest(_) => None,
+            KvStoreError::ServerBusy(_) => None,
+            KvStoreError::Unauthenticated(_) => None,
+            KvStoreError::ProtocolError(_) => None,
+            KvStoreError::Corruption { .. } => None,
+            KvStoreError::UnknownLogFormat(_) => None,
+            KvStoreError::ReadOnly => None,
+            KvStoreError::InvalidUtf8(err) => Some(err),
+            KvStoreError::TransactionConflict => None,
+        }
+    }
+}
+
+impl KvStoreError {
+    /// The error for a key which isn't in the store. The key is only
+    /// shown in the message, so it's fine for it to be lossy
+    pub(crate) fn non_existent_key(key: &[u8]) -> Self {
+        KvStoreError::NonExistentKeyError(String::from_utf8_lossy(key).into_owned())
+    }
+
+    /// The code a server reports this error to its clients with
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            KvStoreError::Io(_) | KvStoreError::RemoteIo(_) => ErrorCode::Io,
+            KvStoreError::SledError(sled::Error::Io(_)) => ErrorCode::Io,
+            KvStoreError::SledError(sled::Error::Corruption { .. }) => ErrorCode::Corruption,
+            KvStoreError::SledError(_) => ErrorCode::Internal,
+            KvStoreError::NonExistentKeyError(_) => ErrorCode::NotFound,
+            KvStoreError::Corruption { .. } | KvStoreError::RemoteCorruption(_) => {
+                ErrorCode::Corruption
+            }
+            // Writing to a read-only server is the client's mistake, and
+            // retrying won't help the way it might for an I/O error
+            KvStoreError::ProtocolError(_)
+            | KvStoreError::InvalidUtf8(_)
+            | KvStoreError::InvalidRequest(_)
+            | KvStoreError::ReadOnly => ErrorCode::InvalidRequest,
+            KvStoreError::ServerBusy(_) => ErrorCode::Busy,
+            KvStoreError::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            KvStoreError::TransactionConflict => ErrorCode::Conflict,
+            KvStoreError::SerializationError(_)
+            | KvStoreError::LockError(_)
+            | KvStoreError::ClientError(_)
+            | KvStoreError::UnknownLogFormat(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// The error a client returns for an error response from the server
+    pub(crate) fn from_response(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::Internal => KvStoreError::ClientError(message),
+            ErrorCode::NotFound => KvStoreError::NonExistentKeyError(message),
+            ErrorCode::Io => KvStoreError::RemoteIo(message),
+            ErrorCode::Corruption => KvStoreError::RemoteCorruption(message),
+            ErrorCode::InvalidRequest => KvStoreError::InvalidRequest(message),
+            ErrorCode::Busy => KvStoreError::ServerBusy(message),
+            ErrorCode::Unauthenticated => KvStoreError::Unauthenticated(message),
+            ErrorCode::Conflict => KvStoreError::TransactionConflict,
         }
     }
 }
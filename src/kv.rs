@@ -1,14 +1,227 @@
+use crate::batch::WriteBatch;
 use crate::errors::Result;
+use crate::scan::owned_bounds;
+use crate::ttl::Ttl;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// Key/value pairs in key order, as returned by `KvsEngine::scan`
+pub type KvPairs = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Raw key/value pairs in key order, as returned by `KvsEngine::scan_bytes`
+pub type KvBytePairs = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
 
 /// A trait which defines the required methods to implement a pluggable
-/// storage backend for our key value server
+/// storage backend for our key value server.
+///
+/// Keys and values are arbitrary bytes. The `String` methods are a thin layer
+/// on top which fail with `KvStoreError::InvalidUtf8` when reading anything
+/// that isn't UTF-8. Keys sort by their bytes, which for UTF-8 strings is
+/// the same as sorting the strings
 pub trait KvsEngine: Clone + Send + 'static {
+    /// A read-only view of the engine as it was at some point in time
+    type Snapshot: KvsSnapshot;
+
+    /// Take a snapshot of the store as it is now. Writes made afterwards are
+    /// never seen through it. Dropping the snapshot releases it
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// An optimistic transaction over many keys
+    type Transaction: KvsTransaction;
+
+    /// Begin a transaction which reads the store as it is now
+    fn begin(&self) -> Result<Self::Transaction>;
+
     /// Set a key to a value
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Set a key to a value which expires once `ttl` has passed. Expired keys
+    /// read as missing. Setting the key again without a TTL keeps it for good
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// How long a key has left before it expires, `None` if it doesn't exist
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Ttl>>;
 
     /// Get a key's value
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
     /// Remove a key's value from the store
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Set a key to `new`, or remove it if `new` is `None`, but only if its
+    /// current value is `expected`, with `None` meaning the key doesn't exist.
+    /// On a mismatch nothing is written and the current value is returned
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>>;
+
+    /// Apply every write in a batch atomically
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Force every write made so far out to disk, whatever the sync policy
+    fn flush(&self) -> Result<()>;
+
+    /// Iterate over every key within a range and its value, in key order
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs>;
+
+    /// Iterate over every key starting with a prefix and its value, in key order
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvBytePairs> {
+        let scan = self.scan_bytes(prefix.clone()..)?;
+        Ok(Box::new(scan.take_while(move |pair| match pair {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
+    /// Set a key to a value unless it already exists. Returns whether it was set
+    fn set_bytes_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.compare_and_swap_bytes(key, None, Some(value))?.is_ok())
+    }
+
+    /// Set a `String` key to a `String` value
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set a `String` key to a `String` value which expires once `ttl` has passed
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// How long a `String` key has left before it expires
+    fn ttl(&self, key: String) -> Result<Option<Ttl>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+
+    /// Get a `String` key's value as a `String`
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.as_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Remove a `String` key
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Compare and swap the `String` value of a `String` key
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let swapped = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match swapped {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+        }
+    }
+
+    /// Set a `String` key to a `String` value unless it already exists
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_bytes_if_absent(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Iterate over every `String` key within a range and its value, in key order
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvPairs> {
+        let scan = self.scan_bytes(owned_bounds(&range))?;
+        Ok(Box::new(scan.map(|pair| into_strings(pair?))))
+    }
+
+    /// Iterate over every `String` key starting with a prefix and its value, in key order
+    fn scan_prefix(&self, prefix: String) -> Result<KvPairs> {
+        let scan = self.scan_prefix_bytes(prefix.into_bytes())?;
+        Ok(Box::new(scan.map(|pair| into_strings(pair?))))
+    }
+}
+
+/// A read-only view of an engine as it was when the snapshot was taken,
+/// as returned by `KvsEngine::snapshot`. Keys which had expired by then read
+/// as missing, and keys which expire afterwards are still seen
+pub trait KvsSnapshot: Send + 'static {
+    /// Get a key's value
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Iterate over every key within a range and its value, in key order
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs>;
+
+    /// Iterate over every key starting with a prefix and its value, in key order
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvBytePairs> {
+        let scan = self.scan_bytes(prefix.clone()..)?;
+        Ok(Box::new(scan.take_while(move |pair| match pair {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
+    /// Get a `String` key's value as a `String`
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.as_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Iterate over every `String` key within a range and its value, in key order
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvPairs> {
+        let scan = self.scan_bytes(owned_bounds(&range))?;
+        Ok(Box::new(scan.map(|pair| into_strings(pair?))))
+    }
+
+    /// Iterate over every `String` key starting with a prefix and its value, in key order
+    fn scan_prefix(&self, prefix: String) -> Result<KvPairs> {
+        let scan = self.scan_prefix_bytes(prefix.into_bytes())?;
+        Ok(Box::new(scan.map(|pair| into_strings(pair?))))
+    }
+}
+
+/// An optimistic transaction with snapshot isolation, as returned by
+/// `KvsEngine::begin`. Reads see the store as it was when the transaction
+/// began along with the transaction's own writes, which are held back until
+/// `commit`. Dropping a transaction without committing it aborts it
+pub trait KvsTransaction: Sized + Send + 'static {
+    /// Get a key's value
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Set a key to a value once the transaction commits
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Remove a key once the transaction commits. Unlike `KvsEngine::remove`
+    /// it's fine for the key not to exist
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// Apply every write atomically. Fails with `KvStoreError::TransactionConflict`
+    /// without writing anything if another commit has written one of the same
+    /// keys since the transaction began
+    fn commit(self) -> Result<()>;
+
+    /// Throw away every write
+    fn abort(self) {}
+
+    /// Get a `String` key's value as a `String`
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.as_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Set a `String` key to a `String` value once the transaction commits
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a `String` key once the transaction commits
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+}
+
+/// A raw pair as returned by the `String` scans
+fn into_strings((key, value): (Vec<u8>, Vec<u8>)) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
 }
@@ -3,21 +3,39 @@
 
 //! A Key Value Store!
 
-pub use crate::sled::SledKvsEngine;
-pub use client::{Command, KvsClient};
-pub use errors::Result;
-pub use kv::KvsEngine;
+pub use crate::sled::{SledKvsEngine, SledSnapshot, SledStats, SledTransaction};
+pub use batch::{BatchOp, WriteBatch};
+pub use client::KvsClient;
+pub use errors::{KvStoreError, Result};
+pub use kv::{KvBytePairs, KvPairs, KvsEngine, KvsSnapshot, KvsTransaction};
+pub use protocol::{Command, ErrorCode, Response};
 pub use server::KvsServer;
-pub use store::KvStore;
+pub use store::{
+    CompactionPolicy, FileHandle, FileSystem, KvStore, KvStoreOptions, KvStoreSnapshot,
+    KvStoreStats, KvStoreTransaction, OsFileSystem,
+};
+pub use sync::SyncPolicy;
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use ttl::Ttl;
 
 /// A Thread Pool module which contains both a pluggable ThreadPool trait
 /// as well as implementations of it
 pub mod thread_pool;
 
+/// A conformance suite any `KvsEngine` implementation can be checked with,
+/// and a simulated filesystem for crash testing `KvStore`
+#[cfg(feature = "testing")]
+pub mod testing;
+
+mod batch;
 mod client;
 mod errors;
 mod kv;
+mod protocol;
+mod scan;
 mod server;
 mod sled;
 mod store;
+mod sweeper;
+mod sync;
+mod ttl;
@@ -0,0 +1,559 @@
+use crate::batch::{BatchOp, WriteBatch};
+use crate::errors::{KvStoreError, Result};
+use crate::ttl::Ttl;
+use std::io;
+use std::io::prelude::*;
+use std::str;
+use std::time::Duration;
+
+/// Negotiation byte a client sends right after connecting to select the
+/// framed binary protocol. Anything else is treated as the legacy text protocol.
+pub(crate) const PROTOCOL_MAGIC: u8 = 0xB5;
+
+/// Version written into every frame header
+pub(crate) const PROTOCOL_VERSION: u8 = 1;
+
+/// Upper bound on a single frame's payload so a bogus length can't make us
+/// allocate unbounded memory
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+const COMMAND_GET: u8 = 1;
+const COMMAND_SET: u8 = 2;
+const COMMAND_REMOVE: u8 = 3;
+const COMMAND_EXIT: u8 = 4;
+const COMMAND_SCAN: u8 = 5;
+const COMMAND_BATCH: u8 = 6;
+const COMMAND_CAS: u8 = 7;
+const COMMAND_SET_WITH_TTL: u8 = 8;
+const COMMAND_TTL: u8 = 9;
+const COMMAND_BEGIN: u8 = 10;
+const COMMAND_COMMIT: u8 = 11;
+const COMMAND_ABORT: u8 = 12;
+
+const BATCH_OP_SET: u8 = 1;
+const BATCH_OP_REMOVE: u8 = 2;
+
+const RESPONSE_OK: u8 = 1;
+const RESPONSE_VALUE: u8 = 2;
+const RESPONSE_NONE: u8 = 3;
+const RESPONSE_ERR: u8 = 4;
+const RESPONSE_PAIRS: u8 = 5;
+const RESPONSE_MISMATCH: u8 = 6;
+const RESPONSE_TTL: u8 = 7;
+const RESPONSE_ERR_CODE: u8 = 8;
+
+const ERROR_INTERNAL: u8 = 0;
+const ERROR_NOT_FOUND: u8 = 1;
+const ERROR_IO: u8 = 2;
+const ERROR_CORRUPTION: u8 = 3;
+const ERROR_INVALID_REQUEST: u8 = 4;
+const ERROR_BUSY: u8 = 5;
+const ERROR_UNAUTHENTICATED: u8 = 6;
+const ERROR_CONFLICT: u8 = 7;
+
+const TTL_MISSING: u8 = 0;
+const TTL_FOREVER: u8 = 1;
+const TTL_REMAINING: u8 = 2;
+
+/// A KvsServer command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// KvsServer GET command
+    Get(Vec<u8>),
+    /// KvsServer SET command
+    Set(Vec<u8>, Vec<u8>),
+    /// KvsServer REMOVE command
+    Remove(Vec<u8>),
+    /// KvsServer EXIT command for prompting server to exit
+    Exit,
+    /// KvsServer SCAN command for a page of keys starting with `prefix`, in key order
+    Scan {
+        /// Only keys starting with this are returned
+        prefix: Vec<u8>,
+        /// Continue after this key, as returned with the previous page
+        cursor: Option<Vec<u8>>,
+        /// The most pairs to return. The server may return fewer
+        limit: u32,
+    },
+    /// KvsServer BATCH command for applying many writes atomically
+    Batch(WriteBatch),
+    /// KvsServer CAS command for setting, or removing, a key only if it has the expected value
+    Cas {
+        /// The key to swap
+        key: Vec<u8>,
+        /// The value the key must have, `None` if it mustn't exist
+        expected: Option<Vec<u8>>,
+        /// The value to set, `None` to remove the key
+        new: Option<Vec<u8>>,
+    },
+    /// KvsServer SET command for a key which expires after a TTL
+    SetWithTtl(Vec<u8>, Vec<u8>, Duration),
+    /// KvsServer TTL command for how long a key has left
+    Ttl(Vec<u8>),
+    /// KvsServer BEGIN command for starting a transaction on this connection.
+    /// GET, SET and REMOVE go through it until COMMIT or ABORT
+    Begin,
+    /// KvsServer COMMIT command for committing the connection's transaction
+    Commit,
+    /// KvsServer ABORT command for throwing away the connection's transaction
+    Abort,
+}
+
+/// What kind of failure an error response reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Any failure without a more specific code
+    Internal,
+    /// The key doesn't exist
+    NotFound,
+    /// The server's store hit an I/O error
+    Io,
+    /// The server's store found corrupt data
+    Corruption,
+    /// The request was malformed or not allowed in this state
+    InvalidRequest,
+    /// The server can't take the request right now and it may be retried
+    Busy,
+    /// The client has to authenticate first
+    Unauthenticated,
+    /// A transaction conflicts with a later commit
+    Conflict,
+}
+
+impl ErrorCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::Internal => ERROR_INTERNAL,
+            ErrorCode::NotFound => ERROR_NOT_FOUND,
+            ErrorCode::Io => ERROR_IO,
+            ErrorCode::Corruption => ERROR_CORRUPTION,
+            ErrorCode::InvalidRequest => ERROR_INVALID_REQUEST,
+            ErrorCode::Busy => ERROR_BUSY,
+            ErrorCode::Unauthenticated => ERROR_UNAUTHENTICATED,
+            ErrorCode::Conflict => ERROR_CONFLICT,
+        }
+    }
+
+    /// Codes from a newer server which we don't know about are `Internal`
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            ERROR_NOT_FOUND => ErrorCode::NotFound,
+            ERROR_IO => ErrorCode::Io,
+            ERROR_CORRUPTION => ErrorCode::Corruption,
+            ERROR_INVALID_REQUEST => ErrorCode::InvalidRequest,
+            ERROR_BUSY => ErrorCode::Busy,
+            ERROR_UNAUTHENTICATED => ErrorCode::Unauthenticated,
+            ERROR_CONFLICT => ErrorCode::Conflict,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// A KvsServer response
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The command succeeded and has nothing to return
+    Ok,
+    /// The result of a GET command, `None` if the key doesn't exist
+    Value(Option<Vec<u8>>),
+    /// The command failed, with what kind of failure it was and a message
+    Err(ErrorCode, String),
+    /// A page of results for a SCAN command
+    Pairs {
+        /// Keys and their values, in key order
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        /// Where to continue from, `None` once the scan is finished
+        cursor: Option<Vec<u8>>,
+    },
+    /// A CAS command found a different value, `None` if the key doesn't exist
+    Mismatch(Option<Vec<u8>>),
+    /// The result of a TTL command, `None` if the key doesn't exist
+    Ttl(Option<Ttl>),
+}
+
+impl Command {
+    /// Encode the command into a frame payload
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Command::Get(key) => {
+                buf.push(COMMAND_GET);
+                put_bytes(&mut buf, key);
+            }
+            Command::Set(key, value) => {
+                buf.push(COMMAND_SET);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, value);
+            }
+            Command::Remove(key) => {
+                buf.push(COMMAND_REMOVE);
+                put_bytes(&mut buf, key);
+            }
+            Command::Exit => buf.push(COMMAND_EXIT),
+            Command::Scan {
+                prefix,
+                cursor,
+                limit,
+            } => {
+                buf.push(COMMAND_SCAN);
+                put_bytes(&mut buf, prefix);
+                put_option(&mut buf, cursor);
+                buf.extend_from_slice(&limit.to_be_bytes());
+            }
+            Command::Batch(batch) => {
+                buf.push(COMMAND_BATCH);
+                buf.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Set(key, value) => {
+                            buf.push(BATCH_OP_SET);
+                            put_bytes(&mut buf, key);
+                            put_bytes(&mut buf, value);
+                        }
+                        BatchOp::Remove(key) => {
+                            buf.push(BATCH_OP_REMOVE);
+                            put_bytes(&mut buf, key);
+                        }
+                    }
+                }
+            }
+            Command::Cas { key, expected, new } => {
+                buf.push(COMMAND_CAS);
+                put_bytes(&mut buf, key);
+                put_option(&mut buf, expected);
+                put_option(&mut buf, new);
+            }
+            Command::SetWithTtl(key, value, ttl) => {
+                buf.push(COMMAND_SET_WITH_TTL);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, value);
+                buf.extend_from_slice(&(ttl.as_millis() as u64).to_be_bytes());
+            }
+            Command::Ttl(key) => {
+                buf.push(COMMAND_TTL);
+                put_bytes(&mut buf, key);
+            }
+            Command::Begin => buf.push(COMMAND_BEGIN),
+            Command::Commit => buf.push(COMMAND_COMMIT),
+            Command::Abort => buf.push(COMMAND_ABORT),
+        }
+        buf
+    }
+
+    /// Decode a command from a frame payload
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(payload);
+        let command = match decoder.u8()? {
+            COMMAND_GET => Command::Get(decoder.byte_vec()?),
+            COMMAND_SET => Command::Set(decoder.byte_vec()?, decoder.byte_vec()?),
+            COMMAND_REMOVE => Command::Remove(decoder.byte_vec()?),
+            COMMAND_EXIT => Command::Exit,
+            COMMAND_SCAN => Command::Scan {
+                prefix: decoder.byte_vec()?,
+                cursor: decoder.option()?,
+                limit: decoder.u32()?,
+            },
+            COMMAND_BATCH => {
+                let count = decoder.u32()?;
+                let mut ops = Vec::new();
+                for _ in 0..count {
+                    ops.push(match decoder.u8()? {
+                        BATCH_OP_SET => BatchOp::Set(decoder.byte_vec()?, decoder.byte_vec()?),
+                        BATCH_OP_REMOVE => BatchOp::Remove(decoder.byte_vec()?),
+                        tag => {
+                            return Err(KvStoreError::ProtocolError(format!(
+                                "Unknown batch operation tag {}",
+                                tag
+                            )))
+                        }
+                    });
+                }
+                Command::Batch(ops.into())
+            }
+            COMMAND_CAS => Command::Cas {
+                key: decoder.byte_vec()?,
+                expected: decoder.option()?,
+                new: decoder.option()?,
+            },
+            COMMAND_SET_WITH_TTL => Command::SetWithTtl(
+                decoder.byte_vec()?,
+                decoder.byte_vec()?,
+                Duration::from_millis(decoder.u64()?),
+            ),
+            COMMAND_TTL => Command::Ttl(decoder.byte_vec()?),
+            COMMAND_BEGIN => Command::Begin,
+            COMMAND_COMMIT => Command::Commit,
+            COMMAND_ABORT => Command::Abort,
+            tag => {
+                return Err(KvStoreError::ProtocolError(format!(
+                    "Unknown command tag {}",
+                    tag
+                )))
+            }
+        };
+        decoder.finish()?;
+        Ok(command)
+    }
+
+    /// Parse a line sent with the legacy `COMMAND:key:value` text protocol.
+    /// Anything after the fields a command needs is ignored
+    pub fn parse_text(line: &[u8]) -> Result<Self> {
+        let line = str::from_utf8(line)
+            .map_err(|_e| KvStoreError::ProtocolError("Invalid UTF-8 in request".to_owned()))?;
+        let mut sections = line.trim_end().split(':');
+        let name = sections.next().unwrap_or_default();
+        let mut field = |field: &str| {
+            sections
+                .next()
+                .map(Vec::from)
+                .ok_or_else(|| KvStoreError::ProtocolError(format!("Missing {} in request", field)))
+        };
+        match name {
+            "GET" => Ok(Command::Get(field("key")?)),
+            "SET" => {
+                let key = field("key")?;
+                let value = field("value")?;
+                Ok(Command::Set(key, value))
+            }
+            "REMOVE" => Ok(Command::Remove(field("key")?)),
+            "EXIT" => Ok(Command::Exit),
+            _ => Err(KvStoreError::ProtocolError(
+                "Command not recognized".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Response {
+    /// Encode the response into a frame payload
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Response::Ok => buf.push(RESPONSE_OK),
+            Response::Value(Some(value)) => {
+                buf.push(RESPONSE_VALUE);
+                put_bytes(&mut buf, value);
+            }
+            Response::Value(None) => buf.push(RESPONSE_NONE),
+            Response::Err(code, message) => {
+                buf.push(RESPONSE_ERR_CODE);
+                buf.push(code.to_byte());
+                put_bytes(&mut buf, message.as_bytes());
+            }
+            Response::Pairs { pairs, cursor } => {
+                buf.push(RESPONSE_PAIRS);
+                buf.extend_from_slice(&(pairs.len() as u32).to_be_bytes());
+                for (key, value) in pairs {
+                    put_bytes(&mut buf, key);
+                    put_bytes(&mut buf, value);
+                }
+                put_option(&mut buf, cursor);
+            }
+            Response::Mismatch(current) => {
+                buf.push(RESPONSE_MISMATCH);
+                put_option(&mut buf, current);
+            }
+            Response::Ttl(ttl) => {
+                buf.push(RESPONSE_TTL);
+                match ttl {
+                    None => buf.push(TTL_MISSING),
+                    Some(Ttl::Forever) => buf.push(TTL_FOREVER),
+                    Some(Ttl::Remaining(remaining)) => {
+                        buf.push(TTL_REMAINING);
+                        buf.extend_from_slice(&(remaining.as_millis() as u64).to_be_bytes());
+                    }
+                }
+            }
+        }
+        buf
+    }
+
+    /// Decode a response from a frame payload
+    pub(crate) fn decode(payload: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(payload);
+        let response = match decoder.u8()? {
+            RESPONSE_OK => Response::Ok,
+            RESPONSE_VALUE => Response::Value(Some(decoder.byte_vec()?)),
+            RESPONSE_NONE => Response::Value(None),
+            // Sent by servers from before error codes
+            RESPONSE_ERR => Response::Err(ErrorCode::Internal, decoder.string()?),
+            RESPONSE_ERR_CODE => {
+                let code = ErrorCode::from_byte(decoder.u8()?);
+                Response::Err(code, decoder.string()?)
+            }
+            RESPONSE_PAIRS => {
+                let count = decoder.u32()?;
+                let mut pairs = Vec::new();
+                for _ in 0..count {
+                    pairs.push((decoder.byte_vec()?, decoder.byte_vec()?));
+                }
+                Response::Pairs {
+                    pairs,
+                    cursor: decoder.option()?,
+                }
+            }
+            RESPONSE_MISMATCH => Response::Mismatch(decoder.option()?),
+            RESPONSE_TTL => Response::Ttl(match decoder.u8()? {
+                TTL_MISSING => None,
+                TTL_FOREVER => Some(Ttl::Forever),
+                TTL_REMAINING => Some(Ttl::Remaining(Duration::from_millis(decoder.u64()?))),
+                flag => {
+                    return Err(KvStoreError::ProtocolError(format!(
+                        "Invalid TTL flag {}",
+                        flag
+                    )))
+                }
+            }),
+            tag => {
+                return Err(KvStoreError::ProtocolError(format!(
+                    "Unknown response tag {}",
+                    tag
+                )))
+            }
+        };
+        decoder.finish()?;
+        Ok(response)
+    }
+}
+
+/// Write a single frame: a version byte, a big endian u32 payload length,
+/// then the payload itself
+pub(crate) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        return Err(KvStoreError::ProtocolError(format!(
+            "Frame of {} bytes exceeds the maximum frame size",
+            payload.len()
+        )));
+    }
+    writer.write_all(&[PROTOCOL_VERSION])?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Read a single frame's payload. Returns `None` if the stream was closed
+/// cleanly before a new frame started.
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut version = [0u8; 1];
+    loop {
+        match reader.read(&mut version) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if version[0] != PROTOCOL_VERSION {
+        return Err(KvStoreError::ProtocolError(format!(
+            "Unsupported protocol version {}",
+            version[0]
+        )));
+    }
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_SIZE {
+        return Err(KvStoreError::ProtocolError(format!(
+            "Frame of {} bytes exceeds the maximum frame size",
+            len
+        )));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Append a u32 length prefixed byte string
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Append a byte string preceded by a presence byte
+fn put_option(buf: &mut Vec<u8>, value: &Option<Vec<u8>>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            put_bytes(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+/// A cursor over a frame payload which never reads past the end
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(KvStoreError::ProtocolError(
+                "Unexpected end of frame".to_owned(),
+            ));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn byte_vec(&mut self) -> Result<Vec<u8>> {
+        Ok(self.bytes()?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_e| KvStoreError::ProtocolError("Invalid UTF-8 in frame".to_owned()))
+    }
+
+    fn option(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.byte_vec()?)),
+            flag => Err(KvStoreError::ProtocolError(format!(
+                "Invalid presence flag {}",
+                flag
+            ))),
+        }
+    }
+
+    /// Make sure the whole payload was consumed
+    fn finish(self) -> Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(KvStoreError::ProtocolError(
+                "Trailing bytes in frame".to_owned(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::errors::Result;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+
+/// How many pairs a scan fetches from its engine at a time
+const SCAN_PAGE_SIZE: usize = 256;
+
+/// A page of key/value pairs in key order
+pub(crate) type KvPage = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Owned copies of a range's bounds as bytes. Keys sort by their bytes,
+/// so a range of strings covers the same keys as a range of their bytes
+pub(crate) fn owned_bounds<K, R>(range: &R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let owned = |bound: Bound<&K>| match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (owned(range.start_bound()), owned(range.end_bound()))
+}
+
+/// Whether no key can fall between two bounds. Ordered maps panic when
+/// asked for a range like this, so scans have to check first
+pub(crate) fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// An iterator over a key range which fetches a page at a time from its engine,
+/// so it never holds the engine's locks between calls to `next`.
+/// Writes made while scanning may or may not be seen
+pub(crate) struct PagedScan<F> {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Fetches at most `limit` pairs within a non-empty range
+    fetch_page: F,
+    done: bool,
+}
+
+impl<F> PagedScan<F>
+where
+    F: FnMut(&Bound<Vec<u8>>, &Bound<Vec<u8>>, usize) -> Result<KvPage>,
+{
+    pub(crate) fn new(range: (Bound<Vec<u8>>, Bound<Vec<u8>>), fetch_page: F) -> Self {
+        let (start, end) = range;
+        Self {
+            start,
+            end,
+            page: VecDeque::new(),
+            fetch_page,
+            done: false,
+        }
+    }
+}
+
+impl<F> Iterator for PagedScan<F>
+where
+    F: FnMut(&Bound<Vec<u8>>, &Bound<Vec<u8>>, usize) -> Result<KvPage>,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if is_empty_range(&self.start, &self.end) {
+                self.done = true;
+                return None;
+            }
+
+            let page = match (self.fetch_page)(&self.start, &self.end, SCAN_PAGE_SIZE) {
+                Ok(page) => page,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            self.done = page.len() < SCAN_PAGE_SIZE;
+            if let Some((key, _)) = page.last() {
+                self.start = Bound::Excluded(key.clone());
+            }
+            self.page.extend(page);
+        }
+
+        self.page.pop_front().map(Ok)
+    }
+}
@@ -1,13 +1,22 @@
-use crate::kv::KvsEngine;
+use crate::errors::{KvStoreError, Result};
+use crate::kv::{KvsEngine, KvsTransaction};
+use crate::protocol::{read_frame, write_frame, Command, ErrorCode, Response, PROTOCOL_MAGIC};
+use crate::scan::KvPage;
 use crate::thread_pool::ThreadPool;
 use base64;
-use crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam::crossbeam_channel::{bounded, select, unbounded, Receiver, Sender};
 use slog::{error, info, Logger};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::marker::Send;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
 /// A struct implementing a key value server with
 /// a pluggable db backend
@@ -22,94 +31,631 @@ pub struct KvsServer<E: KvsEngine> {
     sender: Sender<Message>,
     /// A crossbeam channel receiver for knowing when to exit
     receiver: Receiver<Message>,
+    /// Address the listener is bound to once started
+    local_addr: Option<SocketAddr>,
 }
 
 enum Message {
     Terminate,
 }
 
-enum ServerResult {
-    Ok(String),
-    Err(String),
+/// How long the accept loop first waits after failing to accept a connection
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+
+/// The longest the accept loop waits after repeatedly failing to accept
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long stopping waits for in-flight requests to be answered before
+/// closing their connections
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection keeps its pool thread waiting for the next request
+/// before it's parked, so idle clients can't starve new ones of threads
+const PARK_AFTER: Duration = Duration::from_millis(20);
+
+/// The longest a newly parked connection waits to be watched for requests
+const PARK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Tell the accept loop to stop, then connect to the listener so it notices
+/// without waiting for the next client
+fn request_shutdown(sender: &Sender<Message>, wake_addr: SocketAddr) {
+    // Neither fails unless the accept loop is already gone
+    let _ = sender.send(Message::Terminate);
+    let _ = TcpStream::connect(wake_addr);
+}
+
+/// An address a listener bound to `addr` can be reached on from this host
+fn local_wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
+
+/// Connections currently being served, so stopping can wait for them
+#[derive(Default)]
+struct Connections {
+    open: Mutex<HashMap<u64, TcpStream>>,
+    /// Notified whenever a connection is finished with
+    finished: Condvar,
+    next_id: AtomicU64,
+}
+
+impl Connections {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, TcpStream>> {
+        // The map is left consistent whoever panicked holding it
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Track a connection until `finish` is called with the returned id
+    fn register(&self, stream: &TcpStream) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.lock().insert(id, stream.try_clone()?);
+        Ok(id)
+    }
+
+    fn finish(&self, id: u64) {
+        self.lock().remove(&id);
+        self.finished.notify_all();
+    }
+
+    /// Stop reading from every connection. Requests which have already been
+    /// read are still answered, then the connection sees end of stream
+    fn stop_reading(&self) {
+        for stream in self.lock().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Wait for every connection to be finished with, closing any still open
+    /// once `timeout` has passed. Returns how many had to be closed
+    fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut open = self.lock();
+        while !open.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                for stream in open.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return open.len();
+            }
+            open = self
+                .finished
+                .wait_timeout(open, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        0
+    }
+}
+
+/// A client connection along with what it was in the middle of, so it can be
+/// parked between requests and picked up again by any pool thread
+struct Connection<T> {
+    /// The connection's id in `Connections`
+    id: u64,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Set once the client has picked the binary protocol
+    binary: bool,
+    /// Aborted if the client disconnects before finishing it
+    transaction: Option<T>,
+}
+
+impl<T> Connection<T> {
+    fn new(id: u64, stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            id,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            binary: false,
+            transaction: None,
+        })
+    }
+}
+
+/// Wait up to `PARK_AFTER` for the client to send something, or to
+/// disconnect. Returns false if it's still idle
+fn wait_for_request(reader: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+    reader.get_ref().set_read_timeout(Some(PARK_AFTER))?;
+    let waited = reader.fill_buf().map(|_| ());
+    reader.get_ref().set_read_timeout(None)?;
+    match waited {
+        Ok(()) => Ok(true),
+        Err(ref e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// How a pool thread finished with a connection
+enum Served {
+    Closed,
+    /// The client asked the server to exit
     Exit,
+    /// The client went quiet and the connection should be parked
+    Idle,
 }
 
-fn handle_incoming<E: KvsEngine>(
+/// Everything a pool thread needs to serve a connection
+struct Serving<E: KvsEngine> {
     store: E,
-    stream: TcpStream,
     logger: Logger,
-) -> io::Result<(ServerResult, TcpStream)> {
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut incoming_string = String::new();
-
-    reader.read_line(&mut incoming_string)?;
-
-    info!(logger, "incoming"; "data" => &incoming_string);
-
-    let mut sections = incoming_string.trim_end().split(':');
-
-    let command = sections.next();
-    let store_response = if let Some(command) = command {
-        info!(logger, "command"; "command" => &command);
-        if command == "GET" {
-            let key = sections.next().unwrap();
-            info!(logger, "get input"; "key" => &key);
-            let result = store.get(key.to_owned());
-            result.map_or_else(
-                |_err| ServerResult::Err("Error getting value".to_owned()),
-                |option| {
-                    option.map_or_else(
-                        || ServerResult::Ok("NONE".to_owned()),
-                        |value| {
-                            info!(logger, "get result"; "value" => &value);
-                            ServerResult::Ok(value)
-                        },
-                    )
+    /// Tells the accept loop to stop
+    sender: Sender<Message>,
+    wake_addr: SocketAddr,
+    connections: Arc<Connections>,
+    /// Hands idle connections to the parker
+    parked: Sender<Connection<E::Transaction>>,
+}
+
+// Derived `Clone` would need the engine's transactions to be `Clone` too
+impl<E: KvsEngine> Clone for Serving<E> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            logger: self.logger.clone(),
+            sender: self.sender.clone(),
+            wake_addr: self.wake_addr,
+            connections: self.connections.clone(),
+            parked: self.parked.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine> Serving<E> {
+    /// Serve a connection until it closes or goes idle, parking it if it does
+    fn serve(&self, mut connection: Connection<E::Transaction>) {
+        let id = connection.id;
+        match serve_connection(&self.store, &mut connection, &self.logger) {
+            Err(e) => {
+                error!(self.logger, "error handling incoming"; "error" => %&e);
+            }
+            Ok(Served::Idle) => {
+                // This only fails once the parker's stopped, by which time
+                // every connection is being closed anyway
+                if self.parked.send(connection).is_ok() {
+                    return;
+                }
+            }
+            Ok(Served::Exit) => request_shutdown(&self.sender, self.wake_addr),
+            Ok(Served::Closed) => {}
+        }
+        self.connections.finish(id);
+    }
+}
+
+/// Watch parked connections without holding pool threads, handing each back
+/// to the pool once its client sends something or disconnects. Returns once
+/// `stopped` is disconnected and nothing is left parked
+fn run_parker<E, P>(
+    serving: Serving<E>,
+    parked: Receiver<Connection<E::Transaction>>,
+    stopped: Receiver<()>,
+    thread_pool: Arc<P>,
+) where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    let mut idle = Vec::new();
+    loop {
+        if idle.is_empty() {
+            select! {
+                recv(parked) -> connection => match connection {
+                    Ok(connection) => idle.push(connection),
+                    // Never happens, as `serving` holds a sender
+                    Err(_) => return,
                 },
+                // Nothing is ever sent, the sender's just dropped
+                recv(stopped) -> _ => return,
+            }
+        }
+        idle.extend(parked.try_iter());
+
+        let ready = {
+            let streams: Vec<&TcpStream> = idle
+                .iter()
+                .map(|connection: &Connection<_>| connection.reader.get_ref())
+                .collect();
+            // Let the pool find out what's wrong rather than watch forever
+            readable(&streams, PARK_POLL_INTERVAL).unwrap_or_else(|_| vec![true; streams.len()])
+        };
+        for (i, ready) in ready.into_iter().enumerate().rev() {
+            if ready {
+                let connection = idle.swap_remove(i);
+                let serving = serving.clone();
+                thread_pool.spawn(move || serving.serve(connection));
+            }
+        }
+    }
+}
+
+/// Wait up to `timeout` for any of `streams` to have something to read or
+/// to be closed, returning which do
+#[cfg(unix)]
+fn readable(streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fds: Vec<libc::pollfd> = streams
+        .iter()
+        .map(|stream| libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let ret = unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+        return Err(e);
+    }
+    Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+}
+
+/// Wait up to `timeout` for any of `streams` to have something to read or
+/// to be closed, returning which do. Without `poll` this just sleeps, then
+/// peeks at each one
+#[cfg(not(unix))]
+fn readable(streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+    thread::sleep(timeout);
+    streams
+        .iter()
+        .map(|stream| {
+            stream.set_nonblocking(true)?;
+            let peeked = stream.peek(&mut [0u8]);
+            stream.set_nonblocking(false)?;
+            match peeked {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                _ => Ok(true),
+            }
+        })
+        .collect()
+}
+
+/// The most pairs a single SCAN response carries
+const MAX_SCAN_LIMIT: u32 = 1000;
+
+/// Run a command against the store and build the response to send back.
+/// While the connection has a transaction open the command goes to it instead
+fn process_command<E: KvsEngine>(
+    store: &E,
+    transaction: &mut Option<E::Transaction>,
+    command: Command,
+    logger: &Logger,
+) -> Response {
+    info!(logger, "command"; "command" => format!("{:?}", &command));
+    if let Some(open) = transaction.take() {
+        let (response, open) = process_in_transaction(open, command, logger);
+        *transaction = open;
+        return response;
+    }
+    match command {
+        Command::Get(key) => {
+            info!(logger, "get input"; "key" => %lossy(&key));
+            match store.get_bytes(&key) {
+                Err(err) => error_response(err, "Error getting value"),
+                Ok(value) => {
+                    if let Some(value) = &value {
+                        info!(logger, "get result"; "value" => %lossy(value));
+                    }
+                    Response::Value(value)
+                }
+            }
+        }
+        Command::Set(key, value) => {
+            info!(logger, "set input"; "key" => %lossy(&key), "value" => %lossy(&value));
+            store.set_bytes(key, value).map_or_else(
+                |err| error_response(err, "Error setting key"),
+                |_| Response::Ok,
             )
-        } else if command == "SET" {
-            let key = sections.next().unwrap();
-            let value = sections.next().unwrap();
-            info!(logger, "set input"; "key" => &key, "value" => &value);
-            let result = store.set(key.to_owned(), value.to_owned());
-            result.map_or_else(
-                |_err| ServerResult::Err("Error setting key".to_owned()),
-                |_| ServerResult::Ok("".to_owned()),
+        }
+        Command::Remove(key) => {
+            info!(logger, "remove input"; "key" => %lossy(&key));
+            store.remove_bytes(key).map_or_else(
+                |err| error_response(err, "Error removing key"),
+                |_| Response::Ok,
+            )
+        }
+        Command::Exit => Response::Ok,
+        Command::Batch(batch) => {
+            info!(logger, "batch input"; "writes" => batch.len());
+            store.write_batch(batch).map_or_else(
+                |err| error_response(err, "Error writing batch"),
+                |_| Response::Ok,
+            )
+        }
+        Command::SetWithTtl(key, value, ttl) => {
+            info!(logger, "set input"; "key" => %lossy(&key), "value" => %lossy(&value), "ttl" => ?ttl);
+            store.set_bytes_with_ttl(key, value, ttl).map_or_else(
+                |err| error_response(err, "Error setting key"),
+                |_| Response::Ok,
+            )
+        }
+        Command::Ttl(key) => {
+            info!(logger, "ttl input"; "key" => %lossy(&key));
+            store.ttl_bytes(&key).map_or_else(
+                |err| error_response(err, "Error getting TTL"),
+                Response::Ttl,
+            )
+        }
+        Command::Cas { key, expected, new } => {
+            info!(logger, "cas input"; "key" => %lossy(&key), "expected" => ?&expected, "new" => ?&new);
+            match store.compare_and_swap_bytes(key, expected, new) {
+                Err(err) => error_response(err, "Error swapping value"),
+                Ok(Ok(())) => Response::Ok,
+                Ok(Err(current)) => Response::Mismatch(current),
+            }
+        }
+        Command::Scan {
+            prefix,
+            cursor,
+            limit,
+        } => {
+            info!(logger, "scan input"; "prefix" => %lossy(&prefix), "cursor" => ?&cursor, "limit" => limit);
+            match scan_page(store, prefix, cursor, limit) {
+                Err(err) => error_response(err, "Error scanning keys"),
+                Ok((pairs, cursor)) => Response::Pairs { pairs, cursor },
+            }
+        }
+        Command::Begin => match store.begin() {
+            Err(err) => error_response(err, "Error beginning transaction"),
+            Ok(open) => {
+                *transaction = Some(open);
+                Response::Ok
+            }
+        },
+        Command::Commit | Command::Abort => invalid_request("No transaction is open"),
+    }
+}
+
+/// Run a command inside a connection's open transaction. Returns the
+/// transaction along with the response unless the command finished it
+fn process_in_transaction<T: KvsTransaction>(
+    mut transaction: T,
+    command: Command,
+    logger: &Logger,
+) -> (Response, Option<T>) {
+    let response = match command {
+        Command::Get(key) => {
+            info!(logger, "transaction get input"; "key" => %lossy(&key));
+            transaction.get_bytes(&key).map_or_else(
+                |err| error_response(err, "Error getting value"),
+                Response::Value,
+            )
+        }
+        Command::Set(key, value) => {
+            info!(logger, "transaction set input"; "key" => %lossy(&key), "value" => %lossy(&value));
+            transaction.set_bytes(key, value).map_or_else(
+                |err| error_response(err, "Error setting key"),
+                |_| Response::Ok,
             )
-        } else if command == "REMOVE" {
-            let key = sections.next().unwrap();
-            info!(logger, "remove input"; "key" => &key);
-            let result = store.remove(key.to_owned());
-            result.map_or_else(
-                |_err| ServerResult::Err("Key not found".to_owned()),
-                |_| ServerResult::Ok("".to_owned()),
+        }
+        Command::Remove(key) => {
+            info!(logger, "transaction remove input"; "key" => %lossy(&key));
+            transaction.remove_bytes(key).map_or_else(
+                |err| error_response(err, "Error removing key"),
+                |_| Response::Ok,
             )
-        } else if command == "EXIT" {
-            ServerResult::Exit
-        } else {
-            ServerResult::Err("Command not recognized".to_owned())
         }
+        Command::Commit => {
+            let response = transaction.commit().map_or_else(
+                |err| error_response(err, "Error committing transaction"),
+                |_| Response::Ok,
+            );
+            return (response, None);
+        }
+        Command::Abort | Command::Exit => {
+            transaction.abort();
+            return (Response::Ok, None);
+        }
+        Command::Begin => invalid_request("A transaction is already open"),
+        _ => invalid_request("Only GET, SET and REMOVE can be used in a transaction"),
+    };
+    (response, Some(transaction))
+}
+
+/// Read one page of keys starting with `prefix` which sort after `cursor`,
+/// along with the cursor to continue from if there are more
+fn scan_page<E: KvsEngine>(
+    store: &E,
+    prefix: Vec<u8>,
+    cursor: Option<Vec<u8>>,
+    limit: u32,
+) -> Result<(KvPage, Option<Vec<u8>>)> {
+    let limit = limit.clamp(1, MAX_SCAN_LIMIT) as usize;
+    let start = match cursor {
+        Some(cursor) if cursor >= prefix => Bound::Excluded(cursor),
+        _ => Bound::Included(prefix.clone()),
+    };
+
+    let mut pairs = store
+        .scan_bytes((start, Bound::Unbounded))?
+        .take_while(|pair| match pair {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })
+        .take(limit + 1)
+        .collect::<Result<Vec<_>>>()?;
+
+    // The extra pair only tells us whether there's another page
+    let cursor = if pairs.len() > limit {
+        pairs.truncate(limit);
+        pairs.last().map(|(key, _)| key.clone())
     } else {
-        ServerResult::Err("No command sent".to_owned())
+        None
     };
+    Ok((pairs, cursor))
+}
+
+/// The response for a command which failed. A missing key gets the plain
+/// message clients have always shown for one
+fn error_response(err: KvStoreError, context: &str) -> Response {
+    match err {
+        KvStoreError::NonExistentKeyError(_) => {
+            Response::Err(ErrorCode::NotFound, "Key not found".to_owned())
+        }
+        err => Response::Err(err.code(), format!("{}: {}", context, err)),
+    }
+}
+
+/// The response for a request which can't be carried out as sent
+fn invalid_request(message: &str) -> Response {
+    Response::Err(ErrorCode::InvalidRequest, message.to_owned())
+}
+
+/// Keys and values as they're logged, which needn't be exact
+fn lossy(bytes: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
+
+/// Serve a connection until it closes or goes idle, picking the wire protocol
+/// from the first byte the client sends
+fn serve_connection<E: KvsEngine>(
+    store: &E,
+    connection: &mut Connection<E::Transaction>,
+    logger: &Logger,
+) -> io::Result<Served> {
+    if !connection.binary {
+        if !wait_for_request(&mut connection.reader)? {
+            return Ok(Served::Idle);
+        }
+        match connection.reader.buffer().first().cloned() {
+            Some(PROTOCOL_MAGIC) => {
+                connection.reader.consume(1);
+                connection.binary = true;
+            }
+            Some(_) => {
+                let exit = handle_text(store, connection, logger)?;
+                return Ok(if exit { Served::Exit } else { Served::Closed });
+            }
+            None => return Ok(Served::Closed),
+        }
+    }
+    handle_binary(store, connection, logger)
+}
 
-    Ok((store_response, stream))
+/// Serve commands sent with the framed binary protocol until the client
+/// disconnects or goes idle. Responses are only flushed once every pipelined
+/// request that has already arrived has been answered
+fn handle_binary<E: KvsEngine>(
+    store: &E,
+    connection: &mut Connection<E::Transaction>,
+    logger: &Logger,
+) -> io::Result<Served> {
+    let Connection {
+        reader,
+        writer,
+        transaction,
+        ..
+    } = connection;
+    loop {
+        if !wait_for_request(reader)? {
+            return Ok(Served::Idle);
+        }
+        let payload = match read_frame(reader) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Ok(Served::Closed),
+            Err(e) => {
+                // We can't find the next frame boundary, so answer and hang up
+                error!(logger, "error reading frame"; "error" => %&e);
+                let response = invalid_request(&format!("Invalid request: {}", e));
+                write_frame(writer, &response.encode())?;
+                writer.flush()?;
+                return Ok(Served::Closed);
+            }
+        };
+
+        let (response, exit) = match Command::decode(&payload) {
+            Ok(command) => {
+                let exit = command == Command::Exit;
+                (process_command(store, transaction, command, logger), exit)
+            }
+            Err(e) => {
+                error!(logger, "error decoding command"; "error" => %&e);
+                (invalid_request(&format!("Invalid request: {}", e)), false)
+            }
+        };
+
+        write_frame(writer, &response.encode())?;
+        if exit {
+            writer.flush()?;
+            return Ok(Served::Exit);
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
 }
 
-fn handle_response(result: ServerResult, mut stream: TcpStream) -> io::Result<()> {
-    match result {
-        ServerResult::Ok(response) => {
-            stream.write_all(b"OK:")?;
-            stream.write_all(base64::encode(response.as_bytes()).as_bytes())?;
+/// Serve a single command sent with the legacy `COMMAND:key:value\n` text protocol.
+/// Text clients read until the connection closes so we never keep these open
+fn handle_text<E: KvsEngine>(
+    store: &E,
+    connection: &mut Connection<E::Transaction>,
+    logger: &Logger,
+) -> io::Result<bool> {
+    let Connection { reader, writer, .. } = connection;
+    let mut line = Vec::new();
+
+    reader.read_until(b'\n', &mut line)?;
+
+    info!(logger, "incoming"; "data" => %lossy(&line));
+
+    let (response, exit) = match Command::parse_text(&line) {
+        Ok(command) => {
+            let exit = command == Command::Exit;
+            (process_command(store, &mut None, command, logger), exit)
+        }
+        Err(e) => (invalid_request(&e.to_string()), false),
+    };
+
+    match response {
+        Response::Ok => {
+            writer.write_all(b"OK:")?;
+        }
+        Response::Value(value) => {
+            let value = value.unwrap_or_else(|| b"NONE".to_vec());
+            writer.write_all(b"OK:")?;
+            writer.write_all(base64::encode(&value).as_bytes())?;
+        }
+        Response::Err(_, message) => {
+            writer.write_all(b"ERR:")?;
+            writer.write_all(base64::encode(message.as_bytes()).as_bytes())?;
+        }
+        Response::Pairs { .. } => {
+            writer.write_all(b"ERR:")?;
+            writer.write_all(base64::encode("Scans need the binary protocol").as_bytes())?;
         }
-        ServerResult::Err(response) => {
-            stream.write_all(b"ERR:")?;
-            stream.write_all(base64::encode(response.as_bytes()).as_bytes())?;
+        Response::Mismatch(_) => {
+            writer.write_all(b"ERR:")?;
+            writer.write_all(base64::encode("CAS needs the binary protocol").as_bytes())?;
+        }
+        Response::Ttl(_) => {
+            writer.write_all(b"ERR:")?;
+            writer.write_all(base64::encode("TTLs need the binary protocol").as_bytes())?;
         }
-        _ => {}
     };
-    stream.flush()?;
-    Ok(())
+    writer.flush()?;
+    Ok(exit)
 }
 
 impl<E: KvsEngine> KvsServer<E> {
@@ -123,64 +669,110 @@ impl<E: KvsEngine> KvsServer<E> {
             logger,
             sender,
             receiver,
+            local_addr: None,
         }
     }
 
-    /// Stop the key value server listening
+    /// The address the server is listening on once started. Useful when it
+    /// was asked to listen on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Stop the key value server. It stops accepting connections right away,
+    /// answers the requests it has already read, waits up to
+    /// `SHUTDOWN_DRAIN_TIMEOUT` for their connections to finish, and flushes
+    /// the store. Join the handle returned by `start` to wait for all of that
     pub fn stop(&mut self) {
-        self.sender
-            .send(Message::Terminate)
-            .expect("failed sending message");
+        if let Some(local_addr) = self.local_addr {
+            request_shutdown(&self.sender, local_wake_addr(local_addr));
+        }
     }
 
-    /// Start the key value server listening for connections
-    pub fn start<P: ThreadPool + Send + 'static>(
+    /// Start the key value server listening for connections, returning an
+    /// error if it can't bind its address.
+    /// Binary protocol connections are persistent, but only hold a thread
+    /// pool job while they're busy. One left idle for `PARK_AFTER` is parked
+    /// until its client sends more, then handed back to the pool
+    pub fn start<P: ThreadPool + Send + Sync + 'static>(
         &mut self,
         thread_pool: P,
     ) -> io::Result<thread::JoinHandle<()>> {
-        let store = self.store.clone();
-        let logger = self.logger.clone();
+        let listener = TcpListener::bind(&self.addr)?;
+        let local_addr = listener.local_addr()?;
+        let wake_addr = local_wake_addr(local_addr);
+        self.local_addr = Some(local_addr);
 
-        let addr = self.addr.clone();
-        let sender = self.sender.clone();
+        let logger = self.logger.clone();
         let receiver = self.receiver.clone();
+        let (parked, parked_receiver) = unbounded();
+        let serving = Serving {
+            store: self.store.clone(),
+            logger: logger.clone(),
+            sender: self.sender.clone(),
+            wake_addr,
+            connections: Arc::new(Connections::default()),
+            parked,
+        };
+        let thread_pool = Arc::new(thread_pool);
+        let (stop_parker, parker_stopped) = bounded::<()>(0);
+        let parker = {
+            let serving = serving.clone();
+            let thread_pool = thread_pool.clone();
+            thread::Builder::new()
+                .name("kvs-parker".to_owned())
+                .spawn(move || run_parker(serving, parked_receiver, parker_stopped, thread_pool))?
+        };
         let handle = thread::spawn(move || {
-            // TODO: error handling for all of these unwraps
-            let listener = TcpListener::bind(&addr).unwrap();
-
+            let connections = serving.connections.clone();
+            let mut backoff = ACCEPT_BACKOFF_MIN;
             for stream in listener.incoming() {
-                let stream = stream.unwrap();
-                let store = store.clone();
-                let logger = logger.clone();
-                let sender = sender.clone();
-                let receiver = receiver.clone();
-
-                thread_pool.spawn(move || {
-                    // TODO: handle error
-                    match handle_incoming(store, stream, logger.clone()) {
+                if let Ok(Message::Terminate) = receiver.try_recv() {
+                    break;
+                }
+                let accepted = stream.and_then(|stream| {
+                    let id = connections.register(&stream)?;
+                    match Connection::new(id, stream) {
+                        Ok(connection) => Ok(connection),
                         Err(e) => {
-                            error!(logger, "error handling incoming"; "error" => %&e);
-                        }
-                        Ok((store_response, stream)) => {
-                            if let ServerResult::Exit = store_response {
-                                sender
-                                    .send(Message::Terminate)
-                                    .expect("failed sending message");
-                            } else {
-                                let result = handle_response(store_response, stream);
-                                if let Err(e) = result {
-                                    error!(logger, "error responding"; "error" => %&e);
-                                }
-                            }
+                            connections.finish(id);
+                            Err(e)
                         }
                     }
                 });
-                if let Ok(message) = receiver.try_recv() {
-                    if let Message::Terminate = message {
-                        break;
+                let connection = match accepted {
+                    Ok(connection) => {
+                        backoff = ACCEPT_BACKOFF_MIN;
+                        connection
                     }
-                }
+                    Err(e) => {
+                        // Errors like running out of file descriptors tend to
+                        // persist, so wait longer each time rather than spin
+                        error!(logger, "error accepting connection"; "error" => %&e, "backoff" => ?backoff);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                        continue;
+                    }
+                };
+                let serving = serving.clone();
+                thread_pool.spawn(move || serving.serve(connection));
+            }
+
+            info!(logger, "shutting down");
+            connections.stop_reading();
+            let closed = connections.drain(SHUTDOWN_DRAIN_TIMEOUT);
+            if closed > 0 {
+                error!(logger, "closed connections which didn't finish in time"; "connections" => closed);
+            }
+            // Parked connections have all been handed back by now
+            drop(stop_parker);
+            let _ = parker.join();
+            // Workers exit once the pool is dropped
+            drop(thread_pool);
+            if let Err(e) = serving.store.flush() {
+                error!(logger, "error flushing store"; "error" => %&e);
             }
+            info!(logger, "server stopped");
         });
         Ok(handle)
     }
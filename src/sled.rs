@@ -1,55 +1,865 @@
+use crate::batch::{BatchOp, WriteBatch};
 use crate::errors::{KvStoreError, Result};
-use crate::kv::KvsEngine;
-use sled::Db;
+use crate::kv::{KvBytePairs, KvsEngine, KvsSnapshot, KvsTransaction};
+use crate::scan::{is_empty_range, owned_bounds, KvPage, PagedScan};
+use crate::sweeper::{SweepTarget, Sweeper};
+use crate::sync::{SyncPolicy, SyncTarget, Syncer};
+use crate::ttl::{expiry_after, has_expired, has_expired_at, now_millis, remaining, Ttl};
+use sled::{
+    Batch, ConflictableTransactionResult, Db, IVec, TransactionError, Transactional,
+    TransactionalTree, Tree,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
-/// A wrapper for the sled db which implements the KvsEngine trait
+/// Name of the tree holding the expiry of every key set with a TTL
+const EXPIRIES_TREE: &[u8] = b"kvs_expiries";
+/// Name of the tree listing the snapshots which haven't been released
+const SNAPSHOTS_TREE: &[u8] = b"kvs_snapshots";
+/// Name of the tree holding what each key written since a snapshot was
+/// taken held at the time, keyed by the snapshot's id and then the key
+const SNAPSHOT_UNDO_TREE: &[u8] = b"kvs_snapshot_undo";
+/// Key in the snapshots tree of the big endian ids of every live snapshot
+const LIVE_SNAPSHOTS: &[u8] = b"live";
+
+/// How often expired keys are removed, as `KvStore` does by default
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tags for what a key held when a snapshot kept it
+const KEPT_MISSING: u8 = 0;
+const KEPT_VALUE: u8 = 1;
+const KEPT_EXPIRING_VALUE: u8 = 2;
+
+/// A wrapper for the sled db which implements the KvsEngine trait.
+/// Values are stored untouched in the default tree, so other sled users
+/// see exactly what was written
 #[derive(Clone, Debug)]
 pub struct SledKvsEngine {
     db: Db,
+    /// When keys set with a TTL expire, as big endian milliseconds since the
+    /// Unix epoch. It's only ever written along with the default tree
+    expiries: Tree,
+    /// The ids of the live snapshots, which every write checks
+    snapshots: Tree,
+    /// What keys held before they were first written after a snapshot was taken
+    snapshot_undo: Tree,
+    /// Held exclusively by every transaction and shared by plain tree writes,
+    /// and by reads which need a key and its expiry to agree. Sled doesn't
+    /// keep its transactions apart from plain reads and writes, and with
+    /// every expiry and snapshot added in a transaction, a plain write which
+    /// found no bookkeeping to do stays right until it's done
+    write_gate: Arc<RwLock<()>>,
+    /// Number of the latest write handed to sled
+    write_seq: Arc<AtomicU64>,
+    syncer: Arc<Syncer>,
+    /// Stops and joins the sweeper thread once the last clone is dropped.
+    /// `None` for the sweeper's own handle
+    _sweeper: Option<Arc<Sweeper>>,
+}
+
+/// A point-in-time summary of a `SledKvsEngine`'s snapshots
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SledStats {
+    /// Number of snapshots which haven't been released yet
+    pub snapshots: usize,
+    /// Bytes of old keys and values kept in sled because a snapshot still
+    /// reads them
+    pub pinned_bytes: u64,
+}
+
+/// A read-only view of a `SledKvsEngine` as it was when the snapshot was taken.
+///
+/// Sled can't read as of a point in time, so while a snapshot is live every
+/// write first keeps what the key held for it in sled. Reads look there
+/// before the live trees. Clones share the same snapshot, which is released
+/// when the last one is dropped
+#[derive(Clone, Debug)]
+pub struct SledSnapshot {
+    state: Arc<SnapshotState>,
+}
+
+#[derive(Debug)]
+struct SnapshotState {
+    engine: SledKvsEngine,
+    /// The snapshot's big endian id, which prefixes everything kept for it
+    id: [u8; 8],
+    /// When the snapshot was taken, which decides whether keys had expired
+    taken_at: u64,
+}
+
+impl Drop for SnapshotState {
+    fn drop(&mut self) {
+        // Anything left behind is cleared when the db is next opened
+        let _ = self.engine.release_snapshot(self.id);
+    }
+}
+
+impl SledSnapshot {
+    /// Up to `limit` pairs as the snapshot saw them. Each batch of live pairs
+    /// is read before what was kept for the snapshot over the same keys, and
+    /// a key is always kept before it changes, so one changed after its live
+    /// pair was read is found kept rather than missed
+    fn read_page(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<KvPage> {
+        let state = &self.state;
+        let engine = &state.engine;
+        let mut start = start.clone();
+        let mut page = Vec::with_capacity(limit);
+        loop {
+            let mut seen = BTreeMap::new();
+            for pair in engine.db.range((start.clone(), end.clone())) {
+                let (key, value) = pair?;
+                let expires_at = engine.expiries.get(&key)?;
+                let live = (value.to_vec(), expires_at.as_ref().map(decode_expiry));
+                seen.insert(key.to_vec(), Some(live));
+                if seen.len() == limit {
+                    break;
+                }
+            }
+            // Stop short of any live keys left unread, they're read next time
+            let last_read = match seen.keys().next_back() {
+                Some(last) if seen.len() == limit => Some(last.clone()),
+                _ => None,
+            };
+            let kept_end = match &last_read {
+                Some(last) => Bound::Included(undo_key(&state.id, last)),
+                None => kept_bound(&state.id, end),
+            };
+            let kept_start = match &start {
+                Bound::Unbounded => Bound::Included(state.id.to_vec()),
+                start => kept_bound(&state.id, start),
+            };
+            for pair in engine.snapshot_undo.range((kept_start, kept_end)) {
+                let (key, kept) = pair?;
+                if !key.starts_with(&state.id) {
+                    break;
+                }
+                seen.insert(key[state.id.len()..].to_vec(), decode_kept(&kept));
+            }
+
+            page.extend(seen.into_iter().filter_map(|(key, seen)| {
+                visible_at(seen, state.taken_at).map(|value| (key, value))
+            }));
+            match last_read {
+                Some(last) if page.len() < limit => {
+                    start = Bound::Excluded(last);
+                    if is_empty_range(&start, end) {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        // Any kept keys past the limit are read again with the next page
+        page.truncate(limit);
+        Ok(page)
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    /// Read the key's live value before looking for one kept for the
+    /// snapshot, as a write keeps the old value before changing it
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let state = &self.state;
+        let engine = &state.engine;
+        let value = engine.db.get(key)?;
+        let expires_at = engine.expiries.get(key)?;
+        let seen = match engine.snapshot_undo.get(undo_key(&state.id, key))? {
+            Some(kept) => decode_kept(&kept),
+            None => value.map(|value| (value.to_vec(), expires_at.as_ref().map(decode_expiry))),
+        };
+        Ok(visible_at(seen, state.taken_at))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs> {
+        let snapshot = self.clone();
+        Ok(Box::new(PagedScan::new(
+            owned_bounds(&range),
+            move |start, end, limit| snapshot.read_page(start, end, limit),
+        )))
+    }
+}
+
+/// Flushes sled for the syncer
+struct SledSyncTarget {
+    db: Db,
+    write_seq: Arc<AtomicU64>,
+}
+
+impl SyncTarget for SledSyncTarget {
+    fn sync(&self) -> Result<u64> {
+        let write_seq = self.write_seq.load(Ordering::SeqCst);
+        self.db.flush()?;
+        Ok(write_seq)
+    }
+}
+
+/// Sweeps sled for the sweeper
+struct SledSweepTarget {
+    engine: SledKvsEngine,
+}
+
+impl SweepTarget for SledSweepTarget {
+    /// Remove every key whose expiry has passed, each in its own transaction
+    fn sweep(&self) -> Result<()> {
+        let now = now_millis();
+        for pair in self.engine.expiries.iter() {
+            let (key, expires_at) = pair?;
+            if has_expired_at(decode_expiry(&expires_at), now) {
+                self.engine.transaction(|trees| trees.purge_expired(&key))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An optimistic transaction over a `SledKvsEngine`, committed in one of
+/// sled's own transactions.
+///
+/// Each key keeps the value it had when the transaction first read or wrote
+/// it. Commit checks every one of them still has that value, so it rejects
+/// more than write-write conflicts: any key the transaction read which has
+/// changed since fails it too
+#[derive(Debug)]
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    /// The value of every key read or written, as first seen
+    seen: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Every key written so far and its new value, `None` to remove it
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl SledTransaction {
+    /// Remember a key's current value the first time the transaction touches it
+    fn observe(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.seen.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.engine.get_bytes(key)?;
+        self.seen.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+}
+
+impl KvsTransaction for SledTransaction {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.observe(key),
+        }
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.observe(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.observe(&key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let seen = &self.seen;
+        let writes = &self.writes;
+        let committed = self.engine.transaction(|trees| {
+            for (key, value) in seen {
+                if trees.live_value(key)? != *value {
+                    return Ok(false);
+                }
+            }
+            for (key, value) in writes {
+                match value {
+                    Some(value) => trees.set(key, value, None)?,
+                    None => trees.remove(key)?,
+                }
+            }
+            Ok(true)
+        })?;
+        if !committed {
+            return Err(KvStoreError::TransactionConflict);
+        }
+        self.engine.synced_write()
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
-    /// Get a key
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.db
-            .get(key.as_bytes())
-            .map(|o| o.map(|v| String::from_utf8_lossy(&v).into_owned()))
-            .map_err(|_| KvStoreError::NonExistentKeyError(key))
-    }
-
-    /// Set a key's value
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let result = self
-            .db
-            .insert(key.as_bytes(), value.as_bytes())
-            .map(|_| ())
-            .map_err(|_| KvStoreError::NonExistentKeyError(key));
-        // TODO: can we get away w/out flushing here? it's *terrible* for performance
-        // self.db.flush()?;
-        result
+    type Snapshot = SledSnapshot;
+
+    /// Add a snapshot to the live ones. It's added exclusively of every
+    /// write, so each write either lands before the snapshot or keeps what
+    /// it overwrites for it
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let id = self.db.generate_id()?.to_be_bytes();
+        let _gate = self.write_gate()?;
+        let snapshots = &self.snapshots;
+        snapshots
+            .transaction(|snapshots| {
+                let mut live = snapshots
+                    .get(LIVE_SNAPSHOTS)?
+                    .map_or_else(Vec::new, |live| live.to_vec());
+                live.extend_from_slice(&id);
+                snapshots.insert(LIVE_SNAPSHOTS, live)?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        Ok(SledSnapshot {
+            state: Arc::new(SnapshotState {
+                engine: self.clone(),
+                id,
+                taken_at: now_millis(),
+            }),
+        })
+    }
+
+    type Transaction = SledTransaction;
+
+    /// Begin a transaction whose reads go straight to sled
+    fn begin(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction {
+            engine: self.clone(),
+            seen: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    /// Get a key. A key without an expiry is a plain lookup, and one given
+    /// an expiry since was live when it was set. Otherwise the key and its
+    /// expiry are read together, and an expired key is hidden and left for
+    /// the sweeper to remove, so reads never write
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.expiries.contains_key(key)? {
+            return Ok(self.db.get(key)?.map(|value| value.to_vec()));
+        }
+        let _gate = self.read_gate()?;
+        let value = match self.db.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match self.expiries.get(key)? {
+            Some(expires_at) if has_expired(decode_expiry(&expires_at)) => Ok(None),
+            _ => Ok(Some(value.to_vec())),
+        }
+    }
+
+    /// Set a key's value, dropping any expiry it had
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if self
+            .plain_write(&[&key], |db| db.insert(&key, &value[..]))?
+            .is_none()
+        {
+            self.transaction(|trees| trees.set(&key, &value, None))?;
+        }
+        self.synced_write()
+    }
+
+    /// Set a key along with its expiry. Once it expires the key is hidden,
+    /// then removed by the background sweep
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_after(ttl);
+        self.transaction(|trees| trees.set(&key, &value, Some(expires_at)))?;
+        self.synced_write()
+    }
+
+    /// How long a key has left before it expires
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Ttl>> {
+        let _gate = self.read_gate()?;
+        if !self.db.contains_key(key)? {
+            return Ok(None);
+        }
+        Ok(remaining(
+            self.expiries.get(key)?.as_ref().map(decode_expiry),
+        ))
     }
 
     /// Remove a key from the database
-    fn remove(&self, key: String) -> Result<()> {
-        let result = self.db.remove(key.as_bytes());
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let removed = match self.plain_write(&[&key], |db| db.remove(&key))? {
+            Some(old) => old.is_some(),
+            None => self.transaction(|trees| {
+                if trees.live_value(&key)?.is_none() {
+                    return Ok(false);
+                }
+                trees.remove(&key)?;
+                Ok(true)
+            })?,
+        };
+        if !removed {
+            return Err(KvStoreError::non_existent_key(&key));
+        }
+        self.synced_write()
+    }
+
+    /// Compare and swap a key's value, which also drops any expiry the key
+    /// had. Sled's own `compare_and_swap` does when the key has no expiry
+    /// and no snapshot needs its old value, otherwise it's done in a transaction
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let plain = self.plain_write(&[&key], |db| {
+            db.compare_and_swap(&key, expected.as_deref(), new.as_deref())
+        })?;
+        let swapped = match plain {
+            Some(swapped) => swapped.map_err(|e| e.current.map(|value| value.to_vec())),
+            None => self.transaction(|trees| {
+                let current = trees.live_value(&key)?;
+                if current != expected {
+                    return Ok(Err(current));
+                }
+                match &new {
+                    Some(value) => trees.set(&key, value, None)?,
+                    None => trees.remove(&key)?,
+                }
+                Ok(Ok(()))
+            })?,
+        };
+        if swapped.is_ok() {
+            self.synced_write()?;
+        }
+        Ok(swapped)
+    }
 
-        // TODO: can we get away w/out flushing here? it's *terrible* for performance
+    /// Apply a batch atomically, as one of sled's own batches when none of
+    /// its keys needs bookkeeping, otherwise in a single transaction
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys: Vec<&[u8]> = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set(key, _) | BatchOp::Remove(key) => &key[..],
+            })
+            .collect();
+        let applied = self.plain_write(&keys, |db| {
+            let mut plain_batch = Batch::default();
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Set(key, value) => plain_batch.insert(&key[..], &value[..]),
+                    BatchOp::Remove(key) => plain_batch.remove(&key[..]),
+                }
+            }
+            db.apply_batch(plain_batch)
+        })?;
+        if applied.is_some() {
+            return self.synced_write();
+        }
+        self.transaction(|trees| {
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Set(key, value) => trees.set(key, value, None)?,
+                    BatchOp::Remove(key) => trees.remove(key)?,
+                }
+            }
+            Ok(())
+        })?;
+        self.synced_write()
+    }
+
+    /// Flush sled's in-memory writes to disk
+    fn flush(&self) -> Result<()> {
         self.db.flush()?;
-        match result {
-            Ok(o) => match o {
-                None => Err(KvStoreError::NonExistentKeyError(key)),
-                _v => Ok(()),
+        Ok(())
+    }
+
+    /// Iterate over a range of keys with sled's ordered `range`
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs> {
+        let db = self.db.clone();
+        let expiries = self.expiries.clone();
+        Ok(Box::new(PagedScan::new(
+            owned_bounds(&range),
+            move |start, end, limit| {
+                read_page(db.range((start.clone(), end.clone())), &expiries, limit)
             },
-            Err(e) => Err(KvStoreError::SledError(e)),
-        }
+        )))
+    }
+
+    /// Iterate over keys with a prefix using sled's native `scan_prefix`
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvBytePairs> {
+        let db = self.db.clone();
+        let expiries = self.expiries.clone();
+        Ok(Box::new(PagedScan::new(
+            (Bound::Included(prefix.clone()), Bound::Unbounded),
+            move |start, _end, limit| match start {
+                // Resuming after the last page, so carry on from there
+                Bound::Excluded(last) => {
+                    let pairs =
+                        db.range::<&[u8], _>((Bound::Excluded(&last[..]), Bound::Unbounded));
+                    read_page(
+                        pairs.take_while(|pair| match pair {
+                            Ok((key, _)) => key.starts_with(&prefix),
+                            Err(_) => true,
+                        }),
+                        &expiries,
+                        limit,
+                    )
+                }
+                _ => read_page(db.scan_prefix(&prefix), &expiries, limit),
+            },
+        )))
     }
 }
 
 impl SledKvsEngine {
-    /// Open the sled db for reading and writing
+    /// Open the sled db for reading and writing with the default sync policy,
+    /// as `KvStore::open` does. Sled holds writes in memory until it flushes
+    /// them, so unlike `KvStore` this can lose writes if the process is killed.
+    /// Open it with `SyncPolicy::Always` to flush every write before it's acknowledged
     pub fn open(dirpath: &Path) -> Result<Self> {
-        let db = Db::open(dirpath)?;
-        Ok(Self { db })
+        Self::open_with(dirpath, SyncPolicy::default())
+    }
+
+    /// Open the sled db, flushing writes to disk according to `sync_policy`.
+    /// With `SyncPolicy::Never` writes are left to sled's own periodic flush.
+    /// Dbs written by sled before 0.29 can't be opened and fail with a `SledError`
+    pub fn open_with(dirpath: &Path, sync_policy: SyncPolicy) -> Result<Self> {
+        let db = sled::open(dirpath)?;
+        let expiries = db.open_tree(EXPIRIES_TREE)?;
+        // Snapshots don't outlive the process which took them
+        let snapshots = db.open_tree(SNAPSHOTS_TREE)?;
+        snapshots.clear()?;
+        let snapshot_undo = db.open_tree(SNAPSHOT_UNDO_TREE)?;
+        snapshot_undo.clear()?;
+        let write_seq = Arc::new(AtomicU64::new(0));
+        let syncer = Syncer::start(
+            sync_policy,
+            Arc::new(SledSyncTarget {
+                db: db.clone(),
+                write_seq: write_seq.clone(),
+            }),
+        )?;
+        let mut engine = Self {
+            db,
+            expiries,
+            snapshots,
+            snapshot_undo,
+            write_gate: Arc::new(RwLock::new(())),
+            write_seq,
+            syncer: Arc::new(syncer),
+            _sweeper: None,
+        };
+        let target = SledSweepTarget {
+            engine: engine.clone(),
+        };
+        engine._sweeper = Some(Arc::new(Sweeper::start(Arc::new(target), SWEEP_INTERVAL)?));
+        Ok(engine)
+    }
+
+    /// Summarize the engine's snapshots
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvsEngine, SledKvsEngine};
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let engine = SledKvsEngine::open(temp_dir.path())?;
+    /// engine.set("key".to_owned(), "value".to_owned())?;
+    /// let snapshot = engine.snapshot()?;
+    /// engine.set("key".to_owned(), "changed".to_owned())?;
+    /// assert!(engine.stats()?.pinned_bytes > 0);
+    /// drop(snapshot);
+    /// assert_eq!(engine.stats()?.pinned_bytes, 0);
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stats(&self) -> Result<SledStats> {
+        let mut stats = SledStats::default();
+        if let Some(live) = self.snapshots.get(LIVE_SNAPSHOTS)? {
+            stats.snapshots = live.len() / 8;
+        }
+        for pair in self.snapshot_undo.iter() {
+            let (key, kept) = pair?;
+            stats.pinned_bytes += (key.len() + kept.len()) as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Run a transaction over every tree a write touches, so a key's value,
+    /// its expiry and what's kept of it for snapshots change together
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&Trees) -> ConflictableTransactionResult<T>,
+    {
+        let _gate = self.write_gate()?;
+        // Sled applies each tree in turn, undo first, so whatever a snapshot
+        // reads from the live trees has been kept for it by the time it looks
+        let trees: (&Tree, &Tree, &Tree, &Tree) = (
+            &self.snapshot_undo,
+            &self.snapshots,
+            &self.db,
+            &self.expiries,
+        );
+        trees
+            .transaction(|(snapshot_undo, snapshots, db, expiries)| {
+                f(&Trees {
+                    db,
+                    expiries,
+                    snapshots,
+                    snapshot_undo,
+                })
+            })
+            .map_err(transaction_error)
+    }
+
+    /// Run a write straight against the default tree when none of `keys`
+    /// has an expiry to drop and no snapshot needs what they hold kept.
+    /// Returns `None` without running it otherwise
+    fn plain_write<T, F>(&self, keys: &[&[u8]], write: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Tree) -> sled::Result<T>,
+    {
+        let _gate = self.read_gate()?;
+        let has_snapshots = self
+            .snapshots
+            .get(LIVE_SNAPSHOTS)?
+            .is_some_and(|live| !live.is_empty());
+        if has_snapshots {
+            return Ok(None);
+        }
+        for key in keys {
+            if self.expiries.contains_key(key)? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(write(&self.db)?))
+    }
+
+    fn read_gate(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        self.write_gate
+            .read()
+            .map_err(|_e| KvStoreError::LockError("Error getting sled write gate".to_owned()))
+    }
+
+    fn write_gate(&self) -> Result<RwLockWriteGuard<'_, ()>> {
+        self.write_gate
+            .write()
+            .map_err(|_e| KvStoreError::LockError("Error getting sled write gate".to_owned()))
+    }
+
+    /// Take a snapshot off the live ones, then drop everything kept for it
+    fn release_snapshot(&self, id: [u8; 8]) -> Result<()> {
+        self.snapshots
+            .transaction(|snapshots| {
+                if let Some(live) = snapshots.get(LIVE_SNAPSHOTS)? {
+                    let live: Vec<u8> = live
+                        .chunks(id.len())
+                        .filter(|live_id| *live_id != id)
+                        .flatten()
+                        .copied()
+                        .collect();
+                    snapshots.insert(LIVE_SNAPSHOTS, live)?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        for pair in self.snapshot_undo.scan_prefix(id) {
+            let (key, _) = pair?;
+            self.snapshot_undo.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Number a write which sled has applied and wait until it's as
+    /// durable as the sync policy asks for
+    fn synced_write(&self) -> Result<()> {
+        let write_seq = self.write_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        self.syncer.wait_for(write_seq)
+    }
+}
+
+/// Read up to `limit` pairs from a sled iterator, skipping expired keys
+fn read_page<I: Iterator<Item = sled::Result<(IVec, IVec)>>>(
+    pairs: I,
+    expiries: &Tree,
+    limit: usize,
+) -> Result<KvPage> {
+    let mut page = Vec::with_capacity(limit);
+    for pair in pairs {
+        if page.len() == limit {
+            break;
+        }
+        let (key, value) = pair?;
+        if let Some(expires_at) = expiries.get(&key)? {
+            if has_expired(decode_expiry(&expires_at)) {
+                continue;
+            }
+        }
+        page.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(page)
+}
+
+/// The trees of a transaction run by `SledKvsEngine::transaction`
+struct Trees<'a> {
+    db: &'a TransactionalTree,
+    expiries: &'a TransactionalTree,
+    snapshots: &'a TransactionalTree,
+    snapshot_undo: &'a TransactionalTree,
+}
+
+impl Trees<'_> {
+    /// A key's value, unless it has expired
+    fn live_value(&self, key: &[u8]) -> ConflictableTransactionResult<Option<Vec<u8>>> {
+        let value = match self.db.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match self.expiries.get(key)? {
+            Some(expires_at) if has_expired(decode_expiry(&expires_at)) => Ok(None),
+            _ => Ok(Some(value.to_vec())),
+        }
+    }
+
+    /// Set a key's value along with when it expires, if it ever does
+    fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+    ) -> ConflictableTransactionResult<()> {
+        self.keep_for_snapshots(key)?;
+        self.db.insert(key, value)?;
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key, &expires_at.to_be_bytes()[..])?,
+            None => self.expiries.remove(key)?,
+        };
+        Ok(())
+    }
+
+    /// Remove a key if it has expired, returning whether it had
+    fn purge_expired(&self, key: &[u8]) -> ConflictableTransactionResult<bool> {
+        match self.expiries.get(key)? {
+            Some(expires_at) if has_expired(decode_expiry(&expires_at)) => {
+                self.remove(key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Remove a key and its expiry
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<()> {
+        self.keep_for_snapshots(key)?;
+        self.db.remove(key)?;
+        self.expiries.remove(key)?;
+        Ok(())
+    }
+
+    /// Keep what a key holds for every live snapshot which hasn't already
+    /// kept it, before it's changed
+    fn keep_for_snapshots(&self, key: &[u8]) -> ConflictableTransactionResult<()> {
+        let live = match self.snapshots.get(LIVE_SNAPSHOTS)? {
+            Some(live) => live,
+            None => return Ok(()),
+        };
+        let mut kept = None;
+        for id in live.chunks_exact(8) {
+            let undo_key = undo_key(id, key);
+            if self.snapshot_undo.get(&undo_key)?.is_some() {
+                continue;
+            }
+            if kept.is_none() {
+                kept = Some(encode_kept(self.db.get(key)?, self.expiries.get(key)?));
+            }
+            self.snapshot_undo.insert(undo_key, kept.clone().unwrap())?;
+        }
+        Ok(())
+    }
+}
+
+/// Key in the undo tree of what a key held for a snapshot
+fn undo_key(id: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut undo_key = Vec::with_capacity(id.len() + key.len());
+    undo_key.extend_from_slice(id);
+    undo_key.extend_from_slice(key);
+    undo_key
+}
+
+/// A bound on keys as a bound on what one snapshot kept of them
+fn kept_bound(id: &[u8], bound: &Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(undo_key(id, key)),
+        Bound::Excluded(key) => Bound::Excluded(undo_key(id, key)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Encode what a key held as a tag, the expiry if it has one, then the value
+fn encode_kept(value: Option<IVec>, expires_at: Option<IVec>) -> Vec<u8> {
+    let value = match value {
+        Some(value) => value,
+        None => return vec![KEPT_MISSING],
+    };
+    let mut kept = Vec::with_capacity(9 + value.len());
+    match expires_at {
+        Some(expires_at) => {
+            kept.push(KEPT_EXPIRING_VALUE);
+            kept.extend_from_slice(&decode_expiry(&expires_at).to_be_bytes());
+        }
+        None => kept.push(KEPT_VALUE),
+    }
+    kept.extend_from_slice(&value);
+    kept
+}
+
+/// Decode what a key held into its value and expiry, `None` if it didn't exist
+fn decode_kept(kept: &[u8]) -> Option<(Vec<u8>, Option<u64>)> {
+    match kept.split_first() {
+        Some((&KEPT_VALUE, value)) => Some((value.to_vec(), None)),
+        Some((&KEPT_EXPIRING_VALUE, rest)) if rest.len() >= 8 => {
+            let (expires_at, value) = rest.split_at(8);
+            let mut expiry = [0u8; 8];
+            expiry.copy_from_slice(expires_at);
+            Some((value.to_vec(), Some(u64::from_be_bytes(expiry))))
+        }
+        _ => None,
+    }
+}
+
+/// The value a snapshot taken at `taken_at` sees, unless it had expired by then
+fn visible_at(seen: Option<(Vec<u8>, Option<u64>)>, taken_at: u64) -> Option<Vec<u8>> {
+    match seen {
+        Some((_, Some(expires_at))) if has_expired_at(expires_at, taken_at) => None,
+        Some((value, _)) => Some(value),
+        None => None,
+    }
+}
+
+/// Map a failed transaction to the store's error
+fn transaction_error(e: TransactionError<()>) -> KvStoreError {
+    match e {
+        TransactionError::Storage(e) => KvStoreError::SledError(e),
+        // None of our transactions abort
+        TransactionError::Abort(()) => {
+            KvStoreError::LockError("Sled transaction aborted".to_owned())
+        }
+    }
+}
+
+/// Parse an expiry as stored in the expiries tree. Anything malformed is
+/// treated as already expired
+fn decode_expiry(stored: &IVec) -> u64 {
+    let mut expires_at = [0u8; 8];
+    if stored.len() != expires_at.len() {
+        return 0;
     }
+    expires_at.copy_from_slice(stored);
+    u64::from_be_bytes(expires_at)
 }
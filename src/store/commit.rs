@@ -0,0 +1,517 @@
+use super::record::{encode_batch, stamp_seq, Record, RECORD_HEADER_SIZE};
+use super::{
+    log_file_path, read_record_at, read_versioned_at, LogFileIndexMap, RecordLocation,
+    SharedKvStore,
+};
+use crate::errors::{KvStoreError, Result};
+use crate::sync::Syncer;
+use crossbeam::crossbeam_channel::{bounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, MutexGuard};
+
+/// One record inside a pending write
+#[derive(Debug)]
+struct PendingRecord {
+    key: Vec<u8>,
+    deleted: bool,
+    expires_at: Option<u64>,
+    /// Where the record's frame starts within the write
+    offset: u64,
+    size: u64,
+}
+
+impl PendingRecord {
+    fn new(record: Record, offset: u64, size: u64) -> Self {
+        let expires_at = record.expires_at();
+        let (key, deleted) = record.into_key();
+        Self {
+            key,
+            deleted,
+            expires_at,
+            offset,
+            size,
+        }
+    }
+}
+
+/// What has to hold for a single record write to go ahead. It's checked by
+/// the commit leader with the writer lock held, so nothing can change in between
+#[derive(Debug)]
+pub(super) enum WriteCondition {
+    Always,
+    /// The key has to exist, as for a lone remove
+    KeyExists,
+    /// The key's current value has to be this, `None` meaning it doesn't exist
+    ValueIs(Option<Vec<u8>>),
+    /// The key has to have expired, as when the sweeper removes it
+    Expired,
+    /// None of the write's keys may have been written by a commit after `since`,
+    /// as for a transaction which began then. `existed` says for each record
+    /// whether its key was in the index at the time, so a removal since then
+    /// counts as a write too
+    Unchanged {
+        since: u64,
+        existed: Vec<bool>,
+    },
+}
+
+/// What became of a write once the commit leader got to it
+#[derive(Debug)]
+pub(super) enum Committed {
+    /// Written along with where each of its records ended up
+    Written(Vec<RecordLocation>),
+    /// Not written because the key's value wasn't the expected one
+    Mismatch(Option<Vec<u8>>),
+    /// Not written because one of its keys was written since `WriteCondition::Unchanged`
+    Conflict,
+}
+
+/// Framed records waiting for the commit leader to append them
+#[derive(Debug)]
+pub(super) struct PendingWrite {
+    records: Vec<PendingRecord>,
+    frame: Vec<u8>,
+    condition: WriteCondition,
+    /// Sent once the write is as durable as the sync policy asks for
+    done: Sender<Result<Committed>>,
+}
+
+impl PendingWrite {
+    /// A write along with where to wait for its outcome
+    fn new(
+        records: Vec<PendingRecord>,
+        frame: Vec<u8>,
+        condition: WriteCondition,
+    ) -> (Self, Receiver<Result<Committed>>) {
+        let (done, committed) = bounded(1);
+        let write = Self {
+            records,
+            frame,
+            condition,
+            done,
+        };
+        (write, committed)
+    }
+}
+
+/// Writes queued up behind the current commit leader
+#[derive(Debug, Default)]
+pub(super) struct CommitQueue {
+    pending: Vec<PendingWrite>,
+    /// Set while a writer is appending groups of writes on everyone's behalf
+    leading: bool,
+}
+
+/// Gives up leading the commit queue should `lead_commits` bail out early.
+/// Writes still queued are dropped, which fails them rather than leave
+/// their writers waiting on a leader who's gone
+struct Leading<'a> {
+    queue: &'a Mutex<CommitQueue>,
+    /// Set once the leader has stepped down cleanly
+    done: bool,
+}
+
+impl Drop for Leading<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // The queue is left consistent whoever panicked holding it
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.leading = false;
+        queue.pending.clear();
+    }
+}
+
+impl SharedKvStore {
+    fn lock_commit_queue(&self) -> Result<MutexGuard<'_, CommitQueue>> {
+        self.commit_queue
+            .lock()
+            .map_err(|_e| KvStoreError::LockError("Error getting commit queue lock".to_owned()))
+    }
+
+    /// Commit a single set or remove once `condition` holds. A failed
+    /// `WriteCondition::KeyExists` is a `KvStoreError::NonExistentKeyError`
+    pub(super) fn commit_record(
+        &self,
+        syncer: &Syncer,
+        record: Record,
+        condition: WriteCondition,
+    ) -> Result<Committed> {
+        let frame = record.encode()?;
+        let records = vec![PendingRecord::new(record, 0, frame.len() as u64)];
+        self.commit(syncer, records, frame, condition)
+    }
+
+    /// Commit records as a single batch frame once `condition` holds, so
+    /// they're either all replayed after a crash or none of them are
+    pub(super) fn commit_batch(
+        &self,
+        syncer: &Syncer,
+        batch: Vec<Record>,
+        condition: WriteCondition,
+    ) -> Result<Committed> {
+        let mut frames = Vec::new();
+        let mut records = Vec::with_capacity(batch.len());
+        for record in batch {
+            let frame = record.encode()?;
+            let offset = RECORD_HEADER_SIZE + frames.len() as u64;
+            records.push(PendingRecord::new(record, offset, frame.len() as u64));
+            frames.extend_from_slice(&frame);
+        }
+        let frame = encode_batch(&frames)?;
+        self.commit(syncer, records, frame, condition)
+    }
+
+    /// Remove keys which have expired, each only if it still has by the time
+    /// the commit leader gets to it. They're queued together so they can
+    /// share a single append and sync
+    pub(super) fn commit_expired(&self, syncer: &Syncer, keys: Vec<Vec<u8>>) -> Result<()> {
+        let mut writes = Vec::with_capacity(keys.len());
+        let mut waiting = Vec::with_capacity(keys.len());
+        for key in keys {
+            let record = Record::Delete(key);
+            let frame = record.encode()?;
+            let records = vec![PendingRecord::new(record, 0, frame.len() as u64)];
+            let (write, committed) = PendingWrite::new(records, frame, WriteCondition::Expired);
+            writes.push(write);
+            waiting.push(committed);
+        }
+        self.enqueue(syncer, writes)?;
+        for committed in waiting {
+            // A key which was written again since it expired is simply left alone
+            wait_for_commit(committed)?;
+        }
+        Ok(())
+    }
+
+    /// Queue a write and wait for it to be committed
+    fn commit(
+        &self,
+        syncer: &Syncer,
+        records: Vec<PendingRecord>,
+        frame: Vec<u8>,
+        condition: WriteCondition,
+    ) -> Result<Committed> {
+        let (write, committed) = PendingWrite::new(records, frame, condition);
+        self.enqueue(syncer, vec![write])?;
+        wait_for_commit(committed)
+    }
+
+    /// Add writes to the commit queue. The first writer to find no leader
+    /// becomes one and keeps appending whole groups of writes, each with a
+    /// single flush and sync, until the queue is empty
+    fn enqueue(&self, syncer: &Syncer, writes: Vec<PendingWrite>) -> Result<()> {
+        let lead = {
+            let mut queue = self.lock_commit_queue()?;
+            queue.pending.extend(writes);
+            !mem::replace(&mut queue.leading, true)
+        };
+
+        if lead {
+            self.lead_commits(syncer)?;
+        }
+        Ok(())
+    }
+
+    fn lead_commits(&self, syncer: &Syncer) -> Result<()> {
+        let mut leading = Leading {
+            queue: &self.commit_queue,
+            done: false,
+        };
+        loop {
+            let group = {
+                let mut queue = self.lock_commit_queue()?;
+                if queue.pending.is_empty() {
+                    // Under the same lock as the check, so a writer queueing
+                    // after it always finds no leader and takes over
+                    queue.leading = false;
+                    leading.done = true;
+                    return Ok(());
+                }
+                mem::take(&mut queue.pending)
+            };
+            self.commit_group(syncer, group);
+        }
+    }
+
+    /// Append a group of writes, sync it and hand every writer in it their outcome
+    fn commit_group(&self, syncer: &Syncer, group: Vec<PendingWrite>) {
+        let (results, write_seq) = match self.append_group(&group) {
+            Ok(appended) => appended,
+            Err(e) => {
+                for write in group {
+                    let _ = write.done.send(Err(copy_error(&e)));
+                }
+                return;
+            }
+        };
+
+        let synced = syncer.wait_for(write_seq);
+        for (write, result) in group.into_iter().zip(results) {
+            let result = match &synced {
+                Err(e) if result.is_ok() => Err(copy_error(e)),
+                _ => result,
+            };
+            let _ = write.done.send(result);
+        }
+    }
+
+    /// Write every write of a group whose condition holds to the active log at
+    /// once and index their records. Each write gets the next commit sequence
+    /// number stamped into its records. Returns what became of each write along
+    /// with the number of the last write made
+    fn append_group(&self, group: &[PendingWrite]) -> Result<(Vec<Result<Committed>>, u64)> {
+        let mut writer = self.lock_writer()?;
+        if writer.poisoned {
+            return Err(KvStoreError::Io(io::Error::other(
+                "An earlier failed write couldn't be undone, so the store has to be reopened",
+            )));
+        }
+        self.setup_active_log_file(&mut writer)?;
+
+        let active_log = writer.active_log_mut()?;
+        let generation = active_log.generation;
+        let group_start = active_log.file.len()?;
+
+        let mut results = Vec::with_capacity(group.len());
+        let mut frames = Vec::new();
+        let group_seq = writer.commit_seq;
+        {
+            let log_index = self.read_index()?;
+            // The latest frame setting each key touched earlier in the group,
+            // or `None` if it was removed
+            let mut group_keys: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+            for write in group {
+                if let Some(outcome) = self.check_condition(&log_index, &group_keys, write) {
+                    results.push(outcome);
+                    continue;
+                }
+
+                let write_start = group_start + frames.len() as u64;
+                let locations = write
+                    .records
+                    .iter()
+                    .map(|record| (generation, write_start + record.offset, record.size))
+                    .collect();
+                for record in &write.records {
+                    let frame = if record.deleted {
+                        None
+                    } else {
+                        let start = record.offset as usize;
+                        Some(&write.frame[start..start + record.size as usize])
+                    };
+                    group_keys.insert(&record.key, frame);
+                }
+                frames.extend_from_slice(&write.frame);
+                // Only a damaged log or hint file could have got anywhere near this
+                writer.commit_seq = writer.commit_seq.checked_add(1).ok_or_else(|| {
+                    KvStoreError::SerializationError(
+                        "Commit sequence numbers have run out".to_owned(),
+                    )
+                })?;
+                stamp_seq(
+                    &mut frames[(write_start - group_start) as usize..],
+                    writer.commit_seq,
+                );
+                results.push(Ok(Committed::Written(locations)));
+            }
+        }
+
+        let active_log = writer.active_log_mut()?;
+        if let Err(e) = active_log.file.append(&frames) {
+            // Part of the group may have made it into the log, and a torn frame
+            // followed by later writes would hide them all from replay
+            let path = log_file_path(&self.dirpath, generation);
+            if self.file_system.truncate(&path, group_start).is_err() {
+                writer.poisoned = true;
+            }
+            return Err(e.into());
+        }
+
+        {
+            let mut log_index = self.write_index()?;
+            // Snapshots are taken with the index read lock held, so none can
+            // start while the group lands
+            let keep_superseded = self.lock_pins()?.snapshots() > 0;
+            let mut superseded = self.write_superseded()?;
+            if !keep_superseded {
+                superseded.clear();
+            }
+            let mut seq = group_seq;
+            for (write, result) in group.iter().zip(&results) {
+                let locations = match result {
+                    Ok(Committed::Written(locations)) => locations,
+                    _ => continue,
+                };
+                writer.write_seq += 1;
+                seq += 1;
+
+                // A batch's header belongs to none of its records
+                let frame_size = write.frame.len() as u64;
+                let records_size: u64 = write.records.iter().map(|record| record.size).sum();
+                if let Some(stats) = writer.log_file_stats.get_mut(&generation) {
+                    stats.len += frame_size;
+                    stats.dead += frame_size - records_size;
+                }
+
+                for (record, location) in write.records.iter().zip(locations) {
+                    let prev = if record.deleted {
+                        // Nothing ever points at a tombstone, so it's dead as soon as it's written
+                        writer.mark_dead(location);
+                        log_index.remove(&record.key)
+                    } else {
+                        log_index.insert(record.key.clone(), *location)
+                    };
+                    if keep_superseded {
+                        superseded.keep(&record.key, seq, prev, None);
+                    }
+                    if let Some(prev) = prev {
+                        writer.mark_dead(&prev);
+                    }
+                }
+            }
+            self.committed_seq
+                .store(writer.commit_seq, Ordering::SeqCst);
+        }
+
+        // Only queued for the sweeper once they're in the index
+        let mut expiring = self.lock_expiring()?;
+        for (write, result) in group.iter().zip(&results) {
+            if let Ok(Committed::Written(_)) = result {
+                for record in &write.records {
+                    if let Some(expires_at) = record.expires_at {
+                        expiring.insert((expires_at, record.key.clone()));
+                    }
+                }
+            }
+        }
+        drop(expiring);
+
+        self.maybe_request_compaction(&writer);
+        Ok((results, writer.write_seq))
+    }
+
+    /// Check a write's condition against the index and the writes ahead of it
+    /// in its group. Returns the write's outcome if it mustn't go ahead
+    fn check_condition(
+        &self,
+        log_index: &LogFileIndexMap,
+        group_keys: &HashMap<&[u8], Option<&[u8]>>,
+        write: &PendingWrite,
+    ) -> Option<Result<Committed>> {
+        match &write.condition {
+            WriteCondition::Always => return None,
+            WriteCondition::Unchanged { since, existed } => {
+                return self.check_unchanged(log_index, group_keys, write, *since, existed);
+            }
+            _ => {}
+        }
+        let key = &write.records[0].key;
+        let current = match group_keys.get(key.as_slice()) {
+            Some(frame) => frame.map(decode_pending).transpose(),
+            None => self.indexed_record(log_index, key),
+        };
+        let current = match current {
+            Ok(current) => current,
+            Err(e) => return Some(Err(e)),
+        };
+
+        match &write.condition {
+            WriteCondition::Always | WriteCondition::Unchanged { .. } => None,
+            WriteCondition::KeyExists => match current.and_then(Record::into_live_value) {
+                Some(_) => None,
+                None => Some(Err(KvStoreError::non_existent_key(key))),
+            },
+            WriteCondition::ValueIs(expected) => {
+                let current = current.and_then(Record::into_live_value);
+                if current == *expected {
+                    None
+                } else {
+                    Some(Ok(Committed::Mismatch(current)))
+                }
+            }
+            WriteCondition::Expired => {
+                if current.is_some_and(|record| record.has_expired()) {
+                    None
+                } else {
+                    Some(Ok(Committed::Mismatch(None)))
+                }
+            }
+        }
+    }
+
+    /// Check that no commit after `since` has written any of a write's keys,
+    /// including the writes ahead of it in its group
+    fn check_unchanged(
+        &self,
+        log_index: &LogFileIndexMap,
+        group_keys: &HashMap<&[u8], Option<&[u8]>>,
+        write: &PendingWrite,
+        since: u64,
+        existed: &[bool],
+    ) -> Option<Result<Committed>> {
+        for (record, existed) in write.records.iter().zip(existed) {
+            if group_keys.contains_key(record.key.as_slice()) {
+                return Some(Ok(Committed::Conflict));
+            }
+            let written = match log_index.get(&record.key) {
+                Some(location) => match self
+                    .reader(location.0)
+                    .and_then(|log_file| read_versioned_at(&*log_file, &self.dirpath, location))
+                {
+                    Ok((_, seq)) => seq > since,
+                    Err(e) => return Some(Err(e)),
+                },
+                // Removed since, unless it was missing all along
+                None => *existed,
+            };
+            if written {
+                return Some(Ok(Committed::Conflict));
+            }
+        }
+        None
+    }
+
+    /// Read the record the index has for a key
+    fn indexed_record(&self, log_index: &LogFileIndexMap, key: &[u8]) -> Result<Option<Record>> {
+        let location = match log_index.get(key) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let log_file = self.reader(location.0)?;
+        read_record_at(&*log_file, &self.dirpath, location).map(Some)
+    }
+}
+
+/// Decode the frame of a record which hasn't been written yet
+fn decode_pending(frame: &[u8]) -> Result<Record> {
+    Record::decode(frame)
+        .ok_or_else(|| KvStoreError::SerializationError("Error decoding pending record".to_owned()))
+}
+
+/// Wait for the commit leader to hand back a write's outcome
+fn wait_for_commit(committed: Receiver<Result<Committed>>) -> Result<Committed> {
+    committed.recv().map_err(|_e| {
+        KvStoreError::LockError("Commit leader stopped before committing write".to_owned())
+    })?
+}
+
+/// Every writer in a failed group gets its own copy of the error
+fn copy_error(err: &KvStoreError) -> KvStoreError {
+    match err {
+        KvStoreError::Io(err) => KvStoreError::Io(io::Error::new(err.kind(), err.to_string())),
+        KvStoreError::NonExistentKeyError(key) => KvStoreError::NonExistentKeyError(key.clone()),
+        KvStoreError::LockError(message) => KvStoreError::LockError(message.clone()),
+        KvStoreError::Corruption { path, offset } => KvStoreError::Corruption {
+            path: path.clone(),
+            offset: *offset,
+        },
+        KvStoreError::ReadOnly => KvStoreError::ReadOnly,
+        KvStoreError::TransactionConflict => KvStoreError::TransactionConflict,
+        err => KvStoreError::Io(io::Error::other(err.to_string())),
+    }
+}
@@ -0,0 +1,327 @@
+use super::hint::{build_hint_file, write_hint_file, HintEntry};
+use super::manifest::write_manifest;
+use super::record::{Record, LOG_HEADER_SIZE, LOG_MAGIC};
+use super::vfs::Appender;
+use super::{log_file_path, KvStoreWriter, LogFileStats, RecordLocation, SharedKvStore};
+use crate::errors::{KvStoreError, Result};
+use crossbeam::crossbeam_channel::{Receiver, Sender};
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+/// Knobs deciding when the background worker compacts on its own.
+/// A compaction is triggered once either threshold is crossed
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionPolicy {
+    /// Fraction of all log bytes which have to be dead (overwritten or
+    /// removed) before compacting
+    pub dead_bytes_ratio: f64,
+    /// Never compact for the dead bytes ratio alone until at least
+    /// this many bytes are dead
+    pub min_dead_bytes: u64,
+    /// Compact once there are more than this many log files
+    pub max_log_files: usize,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            dead_bytes_ratio: 0.5,
+            min_dead_bytes: 2048,
+            max_log_files: 64,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub(super) fn should_compact(&self, writer: &KvStoreWriter) -> bool {
+        if writer.log_generations.len() > self.max_log_files {
+            return true;
+        }
+
+        let (len, dead) = writer
+            .log_file_stats
+            .values()
+            .fold((0, 0), |(len, dead), stats| {
+                (len + stats.len, dead + stats.dead)
+            });
+
+        dead > self.min_dead_bytes && dead as f64 >= len as f64 * self.dead_bytes_ratio
+    }
+}
+
+/// Work for the background compaction thread
+#[derive(Debug)]
+pub(super) enum CompactionMessage {
+    /// Compact if the policy says so, or unconditionally when `force` is set.
+    /// The outcome is sent on `done` once finished
+    Compact {
+        force: bool,
+        done: Option<Sender<Result<()>>>,
+    },
+    /// Write the hint file for a sealed log generation
+    WriteHint {
+        generation: u64,
+    },
+    Shutdown,
+}
+
+/// Owns the background compaction thread, stopping and joining it on drop.
+/// It also writes hint files, which keeps them from racing with compaction
+#[derive(Debug)]
+pub(super) struct CompactionWorker {
+    sender: Sender<CompactionMessage>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl CompactionWorker {
+    pub(super) fn start(
+        shared: Arc<SharedKvStore>,
+        receiver: Receiver<CompactionMessage>,
+    ) -> Result<Self> {
+        let sender = shared.compaction_sender.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                while let Ok(message) = receiver.recv() {
+                    match message {
+                        CompactionMessage::Shutdown => break,
+                        CompactionMessage::Compact { force, done } => {
+                            shared.compaction_pending.store(false, Ordering::SeqCst);
+                            let result = if force {
+                                shared.run_compaction()
+                            } else {
+                                shared.compaction_due().and_then(|due| {
+                                    if due {
+                                        shared.run_compaction()
+                                    } else {
+                                        Ok(())
+                                    }
+                                })
+                            };
+                            if let Some(done) = done {
+                                let _ = done.send(result);
+                            }
+                        }
+                        CompactionMessage::WriteHint { generation } => {
+                            // A missing hint file only makes the next open slower
+                            let _ = shared.write_hint(generation);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sender,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        let _ = self.sender.send(CompactionMessage::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl SharedKvStore {
+    fn compaction_due(&self) -> Result<bool> {
+        let writer = self.lock_writer()?;
+        Ok(writer.options.compaction_policy.should_compact(&writer))
+    }
+
+    fn write_hint(&self, generation: u64) -> Result<()> {
+        let read_buffer_size = {
+            let writer = self.lock_writer()?;
+            let active_generation = writer.active_log.as_ref().map(|log| log.generation);
+            // Already compacted away, or still being appended to
+            if active_generation == Some(generation)
+                || !writer.log_generations.contains(&generation)
+            {
+                return Ok(());
+            }
+            writer.options.read_buffer_size
+        };
+        build_hint_file(
+            &*self.file_system,
+            &self.dirpath,
+            generation,
+            read_buffer_size,
+        )
+    }
+
+    /// Rewrite every live record of the sealed log files into a new generation,
+    /// then point the index at it and delete the old files, or retire them
+    /// if a snapshot still reads from them.
+    ///
+    /// The writer lock is only held while the active log is sealed and while the
+    /// manifest and index are updated at the end, so writes carry on while records
+    /// are being copied.
+    pub(super) fn run_compaction(&self) -> Result<()> {
+        // The compacted file gets a generation newer than everything it replaces
+        // but older than the fresh active log, keeping replay order correct
+        let (compaction_generation, sealed_generations) = {
+            let mut writer = self.lock_writer()?;
+            writer.log_file_counter += 1;
+            let compaction_generation = writer.log_file_counter;
+            self.open_new_log_file(&mut writer)?;
+
+            let sealed_count = writer.log_generations.len() - 1;
+            let sealed_generations: HashSet<u64> = writer.log_generations[..sealed_count]
+                .iter()
+                .cloned()
+                .collect();
+            (compaction_generation, sealed_generations)
+        };
+
+        let live: Vec<(Vec<u8>, RecordLocation)> = self
+            .read_index()?
+            .iter()
+            .filter(|(_, (generation, _, _))| sealed_generations.contains(generation))
+            .map(|(key, location)| (key.clone(), *location))
+            .collect();
+
+        let mut moved = Vec::with_capacity(live.len());
+        let mut expired = Vec::new();
+        if !live.is_empty() {
+            let compaction_path = log_file_path(&self.dirpath, compaction_generation);
+            let temp_path = compaction_path.with_extension("compacting");
+            let file = self.file_system.create(&temp_path)?;
+            let mut compacted = BufWriter::new(Appender(file));
+            compacted.write_all(&LOG_MAGIC)?;
+
+            let mut offset = LOG_HEADER_SIZE;
+            let mut max_seq = 0;
+            for (key, location) in live {
+                let (generation, record_location, record_size) = location;
+                let mut buf = vec![0u8; record_size as usize];
+                self.reader(generation)?
+                    .read_exact_at(&mut buf, record_location)?;
+                // Don't carry a damaged record over into a file that looks freshly written
+                let expires_at = match Record::decode_versioned(&buf) {
+                    Some((ref record, _)) if record.has_expired() => {
+                        expired.push((key, location, record.expires_at()));
+                        continue;
+                    }
+                    Some((record, seq)) => {
+                        max_seq = max_seq.max(seq);
+                        record.expires_at()
+                    }
+                    None => {
+                        return Err(KvStoreError::Corruption {
+                            path: log_file_path(&self.dirpath, generation),
+                            offset: record_location,
+                        });
+                    }
+                };
+                compacted.write_all(&buf)?;
+
+                let new_location = (compaction_generation, offset, record_size);
+                offset += record_size;
+                moved.push((key, location, new_location, expires_at));
+            }
+
+            if moved.is_empty() {
+                // Every record left had expired, so there's nothing to keep
+                drop(compacted);
+                self.file_system.remove_file(&temp_path)?;
+            } else {
+                compacted.flush()?;
+                compacted.get_ref().0.sync_all()?;
+                drop(compacted);
+                self.file_system.rename(&temp_path, &compaction_path)?;
+
+                let hints: Vec<HintEntry> = moved
+                    .iter()
+                    .map(|(key, _, (_, offset, size), expires_at)| HintEntry {
+                        key: key.clone(),
+                        deleted: false,
+                        expires_at: *expires_at,
+                        offset: *offset,
+                        size: *size,
+                    })
+                    .collect();
+                // A missing hint file only makes the next open slower
+                let _ = write_hint_file(
+                    &*self.file_system,
+                    &self.dirpath,
+                    compaction_generation,
+                    offset,
+                    max_seq,
+                    &hints,
+                );
+
+                let file = self.file_system.open(&compaction_path)?;
+                self.write_readers()?.insert(compaction_generation, file);
+            }
+        }
+
+        {
+            let mut writer = self.lock_writer()?;
+
+            // Once the manifest stops listing the sealed files a crash can't bring them back,
+            // so the compacted file has to be durable and listed in the same step
+            let mut log_generations: Vec<u64> = writer
+                .log_generations
+                .iter()
+                .cloned()
+                .filter(|generation| !sealed_generations.contains(generation))
+                .collect();
+            if !moved.is_empty() {
+                log_generations.push(compaction_generation);
+                log_generations.sort();
+            }
+            write_manifest(&*self.file_system, &self.dirpath, &log_generations)?;
+            writer.log_generations = log_generations;
+
+            // Only swap entries nobody has overwritten since we copied them
+            let mut compacted_len = 0;
+            let mut stale_bytes = 0;
+            {
+                let mut log_index = self.write_index()?;
+                for (key, old_location, new_location, _) in moved {
+                    compacted_len += new_location.2;
+                    match log_index.get_mut(&key) {
+                        Some(current) if *current == old_location => *current = new_location,
+                        _ => stale_bytes += new_location.2,
+                    }
+                }
+                // Expired keys are dropped rather than rewritten. Every older record
+                // of theirs is in the files being replaced, so nothing can resurface.
+                // Snapshots from before they expired still see them
+                let keep_superseded = self.lock_pins()?.snapshots() > 0;
+                let mut superseded = self.write_superseded()?;
+                let until = self.committed_seq.load(Ordering::SeqCst) + 1;
+                for (key, old_location, expires_at) in expired {
+                    if log_index.get(&key) == Some(&old_location) {
+                        log_index.remove(&key);
+                        if keep_superseded {
+                            superseded.keep(&key, until, Some(old_location), expires_at);
+                        }
+                    }
+                }
+            }
+
+            for generation in &sealed_generations {
+                writer.log_file_stats.remove(generation);
+            }
+            if compacted_len > 0 {
+                writer.log_file_stats.insert(
+                    compaction_generation,
+                    LogFileStats {
+                        len: LOG_HEADER_SIZE + compacted_len,
+                        dead: stale_bytes,
+                    },
+                );
+            }
+        }
+
+        self.retire_generations(&sealed_generations)
+    }
+}
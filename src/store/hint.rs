@@ -0,0 +1,193 @@
+use super::record::{
+    take, take_bytes, take_u64, NextRecord, RecordReader, LOG_HEADER_SIZE, RECORD_HEADER_SIZE,
+};
+use super::vfs::{FileSystem, SequentialReader};
+use super::{check_log_header, log_file_path};
+use crate::errors::{KvStoreError, Result};
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+const HINT_KIND_SET: u8 = 1;
+const HINT_KIND_DELETE: u8 = 2;
+const HINT_KIND_EXPIRING_SET: u8 = 3;
+
+/// Where one record of a sealed log lives, without its value
+#[derive(Debug)]
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    pub(super) deleted: bool,
+    pub(super) expires_at: Option<u64>,
+    pub(super) offset: u64,
+    pub(super) size: u64,
+}
+
+/// Path of the hint file for a generation
+pub(super) fn hint_file_path(dirpath: &Path, generation: u64) -> PathBuf {
+    dirpath.join(format!("{}.hint", generation))
+}
+
+/// Read a generation's hint file, returning `None` if it's missing or doesn't
+/// describe the log as it is now, in which case the log has to be replayed.
+///
+/// Hint files are laid out as the little endian u64 length of the log they
+/// describe and the highest commit sequence number in it, then one entry per record of
+/// `[kind u8][offset u64][size u64][key length u32][key]`, with the
+/// entries of sets which expire followed by their `[expires at u64]`,
+/// then a CRC32 of everything before it
+pub(super) fn read_hint_file(
+    file_system: &dyn FileSystem,
+    dirpath: &Path,
+    generation: u64,
+    log_len: u64,
+) -> Result<Option<(Vec<HintEntry>, u64)>> {
+    let contents = match file_system.read(&hint_file_path(dirpath, generation)) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(decode_hints(&contents, log_len))
+}
+
+fn decode_hints(contents: &[u8], log_len: u64) -> Option<(Vec<HintEntry>, u64)> {
+    if contents.len() < 20 {
+        return None;
+    }
+    let (body, checksum) = contents.split_at(contents.len() - 4);
+    if crc32fast::hash(body)
+        != u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]])
+    {
+        return None;
+    }
+
+    let mut body = body;
+    if take_u64(&mut body)? != log_len {
+        return None;
+    }
+    let max_seq = take_u64(&mut body)?;
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let kind = take(&mut body, 1)?[0];
+        let deleted = match kind {
+            HINT_KIND_SET | HINT_KIND_EXPIRING_SET => false,
+            HINT_KIND_DELETE => true,
+            _ => return None,
+        };
+        let offset = take_u64(&mut body)?;
+        let size = take_u64(&mut body)?;
+        // Anything pointing outside the log would send reads off the end of it
+        match offset.checked_add(size) {
+            Some(end)
+                if offset >= LOG_HEADER_SIZE && size >= RECORD_HEADER_SIZE && end <= log_len => {}
+            _ => return None,
+        }
+        let key = take_bytes(&mut body)?.to_vec();
+        let expires_at = if kind == HINT_KIND_EXPIRING_SET {
+            Some(take_u64(&mut body)?)
+        } else {
+            None
+        };
+        entries.push(HintEntry {
+            key,
+            deleted,
+            expires_at,
+            offset,
+            size,
+        });
+    }
+    Some((entries, max_seq))
+}
+
+/// Write the hint file for a generation whose log is `log_len` bytes long
+/// and holds commits up to `max_seq`.
+/// It's written to a temporary file first so a crash never leaves a half
+/// written hint behind
+pub(super) fn write_hint_file(
+    file_system: &dyn FileSystem,
+    dirpath: &Path,
+    generation: u64,
+    log_len: u64,
+    max_seq: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&log_len.to_le_bytes());
+    contents.extend_from_slice(&max_seq.to_le_bytes());
+    for entry in entries {
+        let kind = match (entry.deleted, entry.expires_at) {
+            (true, _) => HINT_KIND_DELETE,
+            (false, None) => HINT_KIND_SET,
+            (false, Some(_)) => HINT_KIND_EXPIRING_SET,
+        };
+        contents.push(kind);
+        contents.extend_from_slice(&entry.offset.to_le_bytes());
+        contents.extend_from_slice(&entry.size.to_le_bytes());
+        contents.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        contents.extend_from_slice(&entry.key);
+        if let (false, Some(expires_at)) = (entry.deleted, entry.expires_at) {
+            contents.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+    let checksum = crc32fast::hash(&contents);
+    contents.extend_from_slice(&checksum.to_le_bytes());
+
+    let path = hint_file_path(dirpath, generation);
+    let temp_path = path.with_extension("hinting");
+    let file = file_system.create(&temp_path)?;
+    file.append(&contents)?;
+    file.sync_all()?;
+    drop(file);
+    file_system.rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Scan a sealed log and write its hint file
+pub(super) fn build_hint_file(
+    file_system: &dyn FileSystem,
+    dirpath: &Path,
+    generation: u64,
+    read_buffer_size: usize,
+) -> Result<()> {
+    let path = log_file_path(dirpath, generation);
+    let file = file_system.open(&path)?;
+    let log_len = file.len()?;
+    check_log_header(&*file, &path, log_len)?;
+    let mut records = RecordReader::new(
+        BufReader::with_capacity(
+            read_buffer_size,
+            SequentialReader::new(file, LOG_HEADER_SIZE),
+        ),
+        log_len,
+    );
+
+    let mut entries = Vec::new();
+    loop {
+        let (record, offset, size) = match records.next()? {
+            NextRecord::Record(record, offset, size) => (record, offset, size),
+            NextRecord::End => break,
+            NextRecord::Torn | NextRecord::Corrupt => {
+                let offset = records.offset();
+                return Err(KvStoreError::Corruption { path, offset });
+            }
+        };
+        let expires_at = record.expires_at();
+        let (key, deleted) = record.into_key();
+        entries.push(HintEntry {
+            key,
+            deleted,
+            expires_at,
+            offset,
+            size,
+        });
+    }
+
+    write_hint_file(
+        file_system,
+        dirpath,
+        generation,
+        log_len,
+        records.max_seq(),
+        &entries,
+    )
+}
@@ -0,0 +1,84 @@
+use super::vfs::FileSystem;
+use crate::errors::{KvStoreError, Result};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file listing a store's live log generations
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// First line of every manifest, bumped if the format ever changes
+const MANIFEST_HEADER: &str = "kvs-manifest 1";
+
+fn manifest_path(dirpath: &Path) -> PathBuf {
+    dirpath.join(MANIFEST_FILE)
+}
+
+/// Read the live log generations recorded in a store's manifest, oldest first.
+/// Returns `None` for directories written before manifests existed
+pub(super) fn read_manifest(
+    file_system: &dyn FileSystem,
+    dirpath: &Path,
+) -> Result<Option<Vec<u64>>> {
+    let path = manifest_path(dirpath);
+    let contents = match file_system.read(&path) {
+        Ok(contents) => String::from_utf8(contents).map_err(|e| KvStoreError::Corruption {
+            path: path.clone(),
+            offset: e.utf8_error().valid_up_to() as u64,
+        })?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut offset = 0;
+    let mut generations = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let valid = if line_number == 0 {
+            line == MANIFEST_HEADER
+        } else {
+            match line.parse() {
+                Ok(generation) => {
+                    generations.push(generation);
+                    true
+                }
+                Err(_) => false,
+            }
+        };
+        if !valid {
+            return Err(KvStoreError::Corruption { path, offset });
+        }
+        offset += line.len() as u64 + 1;
+    }
+
+    // The manifest is replaced with a rename so it's never half written,
+    // an empty one means something other than us has been at it
+    if offset == 0 {
+        return Err(KvStoreError::Corruption { path, offset });
+    }
+    generations.sort();
+    Ok(Some(generations))
+}
+
+/// Atomically replace the manifest with a new list of live generations
+pub(super) fn write_manifest(
+    file_system: &dyn FileSystem,
+    dirpath: &Path,
+    generations: &[u64],
+) -> Result<()> {
+    let path = manifest_path(dirpath);
+    let temp_path = path.with_extension("tmp");
+
+    let mut contents = String::from(MANIFEST_HEADER);
+    contents.push('\n');
+    for generation in generations {
+        contents.push_str(&format!("{}\n", generation));
+    }
+
+    let file = file_system.create(&temp_path)?;
+    file.append(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    file_system.rename(&temp_path, &path)?;
+    file_system.sync_dir(dirpath)?;
+    Ok(())
+}
@@ -0,0 +1,977 @@
+use crate::batch::{BatchOp, WriteBatch};
+use crate::errors::{KvStoreError, Result};
+use crate::kv::{KvBytePairs, KvsEngine};
+use crate::scan::{is_empty_range, owned_bounds, KvPage, PagedScan};
+use crate::sweeper::Sweeper;
+use crate::sync::{SyncTarget, Syncer};
+use crate::ttl::{expiry_after, remaining, Ttl};
+use crossbeam::crossbeam_channel::{unbounded, Sender};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::io::BufReader;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use std::{ffi, fmt};
+
+pub use self::compaction::CompactionPolicy;
+use self::commit::{CommitQueue, Committed, WriteCondition};
+use self::compaction::{CompactionMessage, CompactionWorker};
+use self::hint::read_hint_file;
+use self::manifest::{read_manifest, write_manifest};
+pub use self::options::KvStoreOptions;
+use self::record::{NextRecord, Record, RecordReader, LOG_HEADER_SIZE, LOG_MAGIC};
+use self::snapshot::{PinnedGenerations, SupersededVersions};
+pub use self::snapshot::KvStoreSnapshot;
+pub use self::stats::KvStoreStats;
+use self::sweeper::StoreSweepTarget;
+pub use self::transaction::KvStoreTransaction;
+pub use self::vfs::{FileHandle, FileSystem, OsFileSystem};
+use self::vfs::SequentialReader;
+
+mod commit;
+mod compaction;
+mod hint;
+mod manifest;
+mod options;
+mod record;
+mod snapshot;
+mod stats;
+mod sweeper;
+mod transaction;
+mod vfs;
+
+/// A type for writing to, and tracking the active log file
+#[derive(Debug)]
+struct LogFileWriter {
+    generation: u64,
+    file: Arc<dyn FileHandle>,
+}
+
+type RecordLocation = (u64, u64, u64);
+
+/// A mapping between a key and a (log generation, file location, record size) tuple,
+/// kept in key order for scans
+type LogFileIndexMap = BTreeMap<Vec<u8>, RecordLocation>;
+
+/// Keys set with a TTL, ordered by when they expire, for the sweeper to remove.
+/// Keys written again since stay in until they're due and are skipped then
+type ExpirySchedule = BTreeSet<(u64, Vec<u8>)>;
+
+/// Read handles for every log generation. Records are read with positional reads
+/// so a single handle can be shared by any number of concurrent readers
+type LogFileReaderMap = HashMap<u64, Arc<dyn FileHandle>>;
+
+/// Size accounting for a single log file
+#[derive(Debug, Clone, Copy)]
+struct LogFileStats {
+    /// Total bytes written to the file
+    len: u64,
+    /// Bytes belonging to overwritten or removed records
+    dead: u64,
+}
+
+impl LogFileStats {
+    /// A log holding nothing but its header, which is never dead
+    fn empty() -> Self {
+        Self {
+            len: LOG_HEADER_SIZE,
+            dead: 0,
+        }
+    }
+}
+
+/// KvsStore data which has to be shared across threads
+#[derive(Debug)]
+pub struct SharedKvStore {
+    log_index: RwLock<LogFileIndexMap>,
+    log_file_readers: RwLock<LogFileReaderMap>,
+    writer: Mutex<KvStoreWriter>,
+    /// Writes waiting for the commit leader to append them
+    commit_queue: Mutex<CommitQueue>,
+    expiring: Mutex<ExpirySchedule>,
+    /// Live snapshots, and the log generations compaction has kept for them
+    pins: Mutex<PinnedGenerations>,
+    /// What commits have replaced since the oldest live snapshot was taken.
+    /// It's locked after the index and before the pins
+    superseded: RwLock<SupersededVersions>,
+    /// Sequence number of the latest commit in the index. It's only changed
+    /// with the index write lock held, so it always matches the index
+    committed_seq: AtomicU64,
+    dirpath: PathBuf,
+    /// Where every file is read from and written to
+    file_system: Arc<dyn FileSystem>,
+    /// Channel for handing work to the background compaction worker
+    compaction_sender: Sender<CompactionMessage>,
+    /// Set while an automatic compaction request is queued so writers
+    /// don't flood the worker
+    compaction_pending: AtomicBool,
+}
+
+/// State which only writers touch. Holding its lock serializes writes
+/// without blocking readers
+#[derive(Debug)]
+struct KvStoreWriter {
+    /// `None` when the store was opened read-only
+    active_log: Option<LogFileWriter>,
+    /// Every live log generation, oldest first with the active log last.
+    /// The MANIFEST is rewritten whenever this changes
+    log_generations: Vec<u64>,
+    log_file_counter: u64,
+    log_file_stats: HashMap<u64, LogFileStats>,
+    /// Number of the latest write handed to the OS, which the syncer
+    /// uses to tell writers when their record is durable
+    write_seq: u64,
+    /// Sequence number of the latest commit, which is stamped into every record
+    /// it writes. Unlike `write_seq` it carries on across reopening the store
+    commit_seq: u64,
+    /// Set when part of a failed append couldn't be cut back off the active
+    /// log, after which nothing more is appended until the store's reopened
+    poisoned: bool,
+    options: KvStoreOptions,
+}
+
+/// KvsStore backing which each thread can hold a copy of
+#[derive(Clone, Debug)]
+pub struct KvStore {
+    shared: Arc<SharedKvStore>,
+    /// Stops and joins the compaction thread once the last clone is dropped
+    _compaction_worker: Arc<CompactionWorker>,
+    syncer: Arc<Syncer>,
+    /// Stops and joins the sweeper thread once the last clone is dropped.
+    /// `None` when the store was opened read-only
+    _sweeper: Option<Arc<Sweeper>>,
+}
+
+impl fmt::Display for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:?})", self.shared.dirpath)
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
+    /// Take a snapshot which reads from a copy of the index. Compaction keeps
+    /// the log files it points at around until it's dropped
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        KvStoreSnapshot::new(self.shared.clone())
+    }
+
+    type Transaction = KvStoreTransaction;
+
+    /// Begin a transaction which reads through a snapshot and only commits
+    /// if none of the keys it writes have been written since
+    fn begin(&self) -> Result<KvStoreTransaction> {
+        KvStoreTransaction::new(self.clone())
+    }
+
+    /// Get a key's value
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::path::Path;
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set_bytes(b"key".to_vec(), vec![0xde, 0xad])?;
+    /// let val = store.get_bytes(b"key")?;
+    /// assert_eq!(val, Some(vec![0xde, 0xad]));
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.shared.read_key(key)?.and_then(Record::into_live_value))
+    }
+
+    /// Set a key to a value
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::path::Path;
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
+    /// store.set_bytes(b"key".to_vec(), b"value".to_vec())?;
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.shared
+            .commit_record(&self.syncer, Record::Set(key, value, None), WriteCondition::Always)?;
+        Ok(())
+    }
+
+    /// Set a key which expires once `ttl` has passed. The expiry is kept in
+    /// the key's record, so it holds across reopening the store
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let record = Record::Set(key, value, Some(expiry_after(ttl)));
+        self.shared
+            .commit_record(&self.syncer, record, WriteCondition::Always)?;
+        Ok(())
+    }
+
+    /// How long a key has left before it expires
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Ttl>> {
+        match self.shared.read_key(key)? {
+            Some(record @ Record::Set(..)) => Ok(remaining(record.expires_at())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Remove a key
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::path::Path;
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
+    /// store.set_bytes(b"key".to_vec(), b"value".to_vec())?;
+    /// store.remove_bytes(b"key".to_vec())?;
+    /// let val = store.get_bytes(b"key")?;
+    /// assert_eq!(val, None);
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.shared
+            .commit_record(&self.syncer, Record::Delete(key), WriteCondition::KeyExists)?;
+        Ok(())
+    }
+
+    /// Compare and swap a key's value. The commit leader checks the current
+    /// value with the writer lock held, so no other write can slip in between
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// assert!(store.set_if_absent("key".to_owned(), "1".to_owned())?);
+    ///
+    /// let one = Some("1".to_owned());
+    /// let two = Some("2".to_owned());
+    /// let swapped = store.compare_and_swap("key".to_owned(), one.clone(), two.clone())?;
+    /// assert_eq!(swapped, Ok(()));
+    /// let swapped = store.compare_and_swap("key".to_owned(), one, None)?;
+    /// assert_eq!(swapped, Err(two));
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let record = match new {
+            Some(value) => Record::Set(key, value, None),
+            None => Record::Delete(key),
+        };
+        let committed =
+            self.shared
+                .commit_record(&self.syncer, record, WriteCondition::ValueIs(expected))?;
+        match committed {
+            Committed::Written(_) => Ok(Ok(())),
+            Committed::Mismatch(current) => Ok(Err(current)),
+            Committed::Conflict => Err(KvStoreError::TransactionConflict),
+        }
+    }
+
+    /// Write a batch as a single record in the log
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let records = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => Record::Set(key, value, None),
+                BatchOp::Remove(key) => Record::Delete(key),
+            })
+            .collect();
+        self.shared
+            .commit_batch(&self.syncer, records, WriteCondition::Always)?;
+        Ok(())
+    }
+
+    /// Sync every log file, so writes made under `SyncPolicy::Never` are durable too
+    fn flush(&self) -> Result<()> {
+        self.shared.sync_all()
+    }
+
+    /// Iterate over a range of keys and their values, in key order
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("a".to_owned(), "1".to_owned())?;
+    /// store.set("b".to_owned(), "2".to_owned())?;
+    /// store.set("c".to_owned(), "3".to_owned())?;
+    /// let pairs = store
+    ///     .scan("b".to_owned()..)?
+    ///     .collect::<kvs::Result<Vec<_>>>()?;
+    /// assert_eq!(pairs, vec![
+    ///     ("b".to_owned(), "2".to_owned()),
+    ///     ("c".to_owned(), "3".to_owned()),
+    /// ]);
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs> {
+        let shared = self.shared.clone();
+        Ok(Box::new(PagedScan::new(
+            owned_bounds(&range),
+            move |start, end, limit| shared.read_page(start, end, limit),
+        )))
+    }
+}
+
+impl KvStore {
+    /// Open a directory for use as KvStore backing
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::KvStore;
+    /// use std::path::Path;
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let mut store = KvStore::open(temp_dir.path())?;
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open(dirpath: &Path) -> Result<Self> {
+        Self::open_with(dirpath, KvStoreOptions::default())
+    }
+
+    /// Open a directory for use as KvStore backing with tuned options
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvStoreOptions};
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let options = KvStoreOptions::new().max_segment_size(4 * 1024 * 1024);
+    /// let store = KvStore::open_with(temp_dir.path(), options)?;
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_with(dirpath: &Path, options: KvStoreOptions) -> Result<Self> {
+        let read_only = options.read_only;
+        let sync_policy = options.sync_policy;
+        let sweep_interval = options.sweep_interval;
+        let file_system = options.file_system.clone();
+        let mut log_index: LogFileIndexMap = BTreeMap::new();
+        let mut log_file_readers: LogFileReaderMap = HashMap::new();
+        let mut log_file_stats: HashMap<u64, LogFileStats> = HashMap::new();
+        let mut expiring = ExpirySchedule::new();
+        let mut commit_seq = 0;
+
+        let mut generations_on_disk: Vec<u64> = Vec::new();
+        let mut hint_files: Vec<(u64, PathBuf)> = Vec::new();
+        for path in file_system.read_dir(dirpath)? {
+            let extension = path.extension().unwrap_or_else(|| ffi::OsStr::new(""));
+            if extension == "compacting" || extension == "hinting" {
+                // Output of a compaction or hint write that never finished,
+                // everything it would have held is still in the log files
+                if !read_only {
+                    file_system.remove_file(&path)?;
+                }
+            } else if extension == "log" {
+                if let Some(generation) = log_file_generation(&path) {
+                    generations_on_disk.push(generation);
+                }
+            } else if extension == "hint" {
+                if let Some(generation) = log_file_generation(&path) {
+                    hint_files.push((generation, path));
+                }
+            }
+        }
+
+        let manifest = read_manifest(&*file_system, dirpath)?;
+        let mut log_generations = match &manifest {
+            Some(generations) => {
+                // Left behind by a compaction or log rotation which crashed before
+                // its manifest update, everything in them is in the listed files
+                for generation in &generations_on_disk {
+                    if !read_only && !generations.contains(generation) {
+                        file_system.remove_file(&log_file_path(dirpath, *generation))?;
+                    }
+                }
+                generations.clone()
+            }
+            None => {
+                // A store from before the manifest existed. Compaction output is written
+                // to a newer generation than the files it replaces, so replaying in
+                // generation order always ends on the latest value
+                generations_on_disk.sort();
+                generations_on_disk
+            }
+        };
+
+        for (generation, path) in &hint_files {
+            if !read_only && !log_generations.contains(generation) {
+                file_system.remove_file(path)?;
+            }
+        }
+
+        // Sealed generations which had to be replayed get a hint file written for next time
+        let mut unhinted_generations = Vec::new();
+
+        for (index, generation) in log_generations.iter().enumerate() {
+            let is_active = index + 1 == log_generations.len();
+            let path = log_file_path(dirpath, *generation);
+            let file = file_system.open(&path)?;
+            let file_len = file.len()?;
+            check_log_header(&*file, &path, file_len)?;
+            log_file_stats.insert(*generation, LogFileStats::empty());
+
+            if !is_active {
+                if let Some((entries, max_seq)) =
+                    read_hint_file(&*file_system, dirpath, *generation, file_len)?
+                {
+                    commit_seq = commit_seq.max(max_seq);
+                    for entry in entries {
+                        let location = (*generation, entry.offset, entry.size);
+                        if let Some(expires_at) = entry.expires_at {
+                            expiring.insert((expires_at, entry.key.clone()));
+                        }
+                        index_record(
+                            &mut log_index,
+                            &mut log_file_stats,
+                            entry.key,
+                            entry.deleted,
+                            location,
+                        );
+                    }
+                    count_unowned_bytes(&mut log_file_stats, *generation, file_len);
+                    log_file_readers.insert(*generation, file);
+                    continue;
+                }
+                unhinted_generations.push(*generation);
+            }
+
+            let mut records = RecordReader::new(
+                BufReader::with_capacity(
+                    options.read_buffer_size,
+                    SequentialReader::new(file.clone(), LOG_HEADER_SIZE),
+                ),
+                file_len,
+            );
+            loop {
+                let (record, offset, record_size) = match records.next()? {
+                    NextRecord::Record(record, offset, record_size) => (record, offset, record_size),
+                    NextRecord::End => break,
+                    NextRecord::Torn if is_active && read_only => break,
+                    NextRecord::Torn if is_active => {
+                        // Left by a crash part way through an append. That write was never
+                        // acknowledged, so drop it and carry on appending from the last good record
+                        file_system.truncate(&path, records.offset())?;
+                        break;
+                    }
+                    NextRecord::Torn | NextRecord::Corrupt => {
+                        return Err(KvStoreError::Corruption {
+                            path,
+                            offset: records.offset(),
+                        });
+                    }
+                };
+
+                let expires_at = record.expires_at();
+                let (key, deleted) = record.into_key();
+                if let Some(expires_at) = expires_at {
+                    expiring.insert((expires_at, key.clone()));
+                }
+                let location = (*generation, offset, record_size);
+                index_record(
+                    &mut log_index,
+                    &mut log_file_stats,
+                    key,
+                    deleted,
+                    location,
+                );
+            }
+            count_unowned_bytes(&mut log_file_stats, *generation, records.offset());
+            commit_seq = commit_seq.max(records.max_seq());
+
+            log_file_readers.insert(*generation, file);
+        }
+
+        let log_file_counter = log_generations.last().cloned().unwrap_or(0);
+        let active_log = if read_only {
+            None
+        } else {
+            let active_log_generation = match log_generations.last() {
+                Some(generation) => *generation,
+                None => {
+                    log_generations.push(log_file_counter);
+                    log_file_stats.insert(log_file_counter, LogFileStats::empty());
+                    create_log_file(&*file_system, &log_file_path(dirpath, log_file_counter))?;
+                    log_file_counter
+                }
+            };
+
+            let active_log_file =
+                file_system.open_append(&log_file_path(dirpath, active_log_generation))?;
+
+            if manifest.as_ref() != Some(&log_generations) {
+                write_manifest(&*file_system, dirpath, &log_generations)?;
+            }
+
+            log_file_readers.insert(active_log_generation, active_log_file.clone());
+
+            Some(LogFileWriter {
+                file: active_log_file,
+                generation: active_log_generation,
+            })
+        };
+        if read_only {
+            // Nothing can be written, so there's no point in queueing hint files
+            unhinted_generations.clear();
+        }
+
+        let (compaction_sender, compaction_receiver) = unbounded();
+
+        let shared = Arc::new(SharedKvStore {
+            log_index: RwLock::new(log_index),
+            log_file_readers: RwLock::new(log_file_readers),
+            writer: Mutex::new(KvStoreWriter {
+                active_log,
+                log_generations,
+                log_file_counter,
+                log_file_stats,
+                write_seq: 0,
+                commit_seq,
+                poisoned: false,
+                options,
+            }),
+            commit_queue: Mutex::new(CommitQueue::default()),
+            expiring: Mutex::new(expiring),
+            pins: Mutex::new(PinnedGenerations::default()),
+            superseded: RwLock::new(SupersededVersions::default()),
+            committed_seq: AtomicU64::new(commit_seq),
+            dirpath: dirpath.to_path_buf(),
+            file_system,
+            compaction_sender,
+            compaction_pending: AtomicBool::new(false),
+        });
+
+        let compaction_worker = CompactionWorker::start(shared.clone(), compaction_receiver)?;
+        let syncer = Arc::new(Syncer::start(sync_policy, shared.clone())?);
+        let sweeper = if read_only {
+            None
+        } else {
+            let target = StoreSweepTarget {
+                shared: shared.clone(),
+                syncer: syncer.clone(),
+            };
+            let sweeper = Sweeper::start(Arc::new(target), sweep_interval)?;
+            Some(Arc::new(sweeper))
+        };
+        for generation in unhinted_generations {
+            shared.send_to_worker(CompactionMessage::WriteHint { generation })?;
+        }
+
+        Ok(Self {
+            shared,
+            _compaction_worker: Arc::new(compaction_worker),
+            syncer,
+            _sweeper: sweeper,
+        })
+    }
+
+    /// Ask the background worker to compact every sealed log file
+    /// without waiting for it to happen
+    pub fn request_compaction(&self) -> Result<()> {
+        self.shared.check_writable()?;
+        self.shared.send_to_worker(CompactionMessage::Compact {
+            force: true,
+            done: None,
+        })
+    }
+
+    /// Compact every log file written so far and wait until it's done.
+    /// Writers are only blocked while the active log is swapped out
+    pub fn compact(&self) -> Result<()> {
+        self.shared.check_writable()?;
+        let (done, wait) = unbounded();
+        self.shared.send_to_worker(CompactionMessage::Compact {
+            force: true,
+            done: Some(done),
+        })?;
+        wait.recv().map_err(|_e| {
+            KvStoreError::LockError("Compaction worker exited before finishing".to_owned())
+        })?
+    }
+
+    /// Change when the background worker decides to compact
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) -> Result<()> {
+        let mut writer = self.shared.lock_writer()?;
+        writer.options.compaction_policy = policy;
+        self.shared.maybe_request_compaction(&writer);
+        Ok(())
+    }
+}
+
+impl KvStoreWriter {
+    /// The log new records are appended to
+    fn active_log_mut(&mut self) -> Result<&mut LogFileWriter> {
+        self.active_log.as_mut().ok_or(KvStoreError::ReadOnly)
+    }
+
+    /// Account for a record which nothing in the index points at anymore
+    fn mark_dead(&mut self, location: &RecordLocation) {
+        let (generation, _, record_size) = location;
+        if let Some(stats) = self.log_file_stats.get_mut(generation) {
+            stats.dead += record_size;
+        }
+    }
+}
+
+impl SharedKvStore {
+    fn read_index(&self) -> Result<RwLockReadGuard<'_, LogFileIndexMap>> {
+        self.log_index
+            .read()
+            .map_err(|_e| KvStoreError::LockError("Error getting read lock".to_owned()))
+    }
+
+    fn write_index(&self) -> Result<RwLockWriteGuard<'_, LogFileIndexMap>> {
+        self.log_index
+            .write()
+            .map_err(|_e| KvStoreError::LockError("Error getting write lock".to_owned()))
+    }
+
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        self.writer
+            .lock()
+            .map_err(|_e| KvStoreError::LockError("Error getting writer lock".to_owned()))
+    }
+
+    /// Fail with `KvStoreError::ReadOnly` if the store was opened read-only
+    fn check_writable(&self) -> Result<()> {
+        self.lock_writer()?.active_log_mut().map(|_| ())
+    }
+
+    fn lock_expiring(&self) -> Result<MutexGuard<'_, ExpirySchedule>> {
+        self.expiring
+            .lock()
+            .map_err(|_e| KvStoreError::LockError("Error getting expiry lock".to_owned()))
+    }
+
+    fn write_readers(&self) -> Result<RwLockWriteGuard<'_, LogFileReaderMap>> {
+        self.log_file_readers
+            .write()
+            .map_err(|_e| KvStoreError::LockError("Error getting write lock".to_owned()))
+    }
+
+    /// Read the record the index has for a key
+    fn read_key(&self, key: &[u8]) -> Result<Option<Record>> {
+        let (log_file, location) = {
+            let log_index = self.read_index()?;
+
+            match log_index.get(key) {
+                None => return Ok(None),
+                Some(location) => {
+                    // Grab the file handle while still holding the index lock so
+                    // compaction can't remove the file out from under us
+                    let log_file = self.reader(location.0)?;
+                    (log_file, *location)
+                }
+            }
+        };
+
+        read_record_at(&*log_file, &self.dirpath, &location).map(Some)
+    }
+
+    /// Read up to `limit` keys within a range along with their values.
+    /// Expired keys are skipped, reading further into the range to make up for them
+    fn read_page(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<KvPage> {
+        let mut page = Vec::with_capacity(limit);
+        let mut start = start.clone();
+        loop {
+            let wanted = limit - page.len();
+            let entries = {
+                let log_index = self.read_index()?;
+                let mut entries = Vec::new();
+                for (key, location) in log_index.range((start.clone(), end.clone())).take(wanted) {
+                    // As with `get`, grab the file handle while still holding the index lock
+                    entries.push((key.clone(), self.reader(location.0)?, *location));
+                }
+                entries
+            };
+
+            let exhausted = entries.len() < wanted;
+            if let Some((key, _, _)) = entries.last() {
+                start = Bound::Excluded(key.clone());
+            }
+            for (key, log_file, location) in entries {
+                let record = read_record_at(&*log_file, &self.dirpath, &location)?;
+                if let Some(value) = record.into_live_value() {
+                    page.push((key, value));
+                }
+            }
+
+            if exhausted || page.len() == limit || is_empty_range(&start, end) {
+                return Ok(page);
+            }
+        }
+    }
+
+    /// Sync every live log file
+    fn sync_all(&self) -> Result<()> {
+        let log_files: Vec<Arc<dyn FileHandle>> = self
+            .log_file_readers
+            .read()
+            .map_err(|_e| KvStoreError::LockError("Error getting read lock".to_owned()))?
+            .values()
+            .cloned()
+            .collect();
+        for log_file in log_files {
+            log_file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Get the shared read handle for a log generation
+    fn reader(&self, generation: u64) -> Result<Arc<dyn FileHandle>> {
+        let log_file_readers = self
+            .log_file_readers
+            .read()
+            .map_err(|_e| KvStoreError::LockError("Error getting read lock".to_owned()))?;
+        log_file_readers.get(&generation).cloned().ok_or_else(|| {
+            KvStoreError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No reader for log generation {}", generation),
+            ))
+        })
+    }
+
+    fn send_to_worker(&self, message: CompactionMessage) -> Result<()> {
+        self.compaction_sender.send(message).map_err(|_e| {
+            KvStoreError::LockError("Compaction worker is no longer running".to_owned())
+        })
+    }
+
+    /// Queue an automatic compaction if the policy says one is due
+    /// and there isn't one queued already
+    fn maybe_request_compaction(&self, writer: &KvStoreWriter) {
+        if writer.active_log.is_none() || !writer.options.compaction_policy.should_compact(writer) {
+            return;
+        }
+        if self.compaction_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // The worker only goes away once every KvStore handle has been dropped
+        let _ = self.send_to_worker(CompactionMessage::Compact {
+            force: false,
+            done: None,
+        });
+    }
+
+    /// Open a new log file for writing to. It's only written to once
+    /// the manifest lists it
+    fn open_new_log_file(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        writer.active_log_mut()?;
+        let generation = writer.log_file_counter + 1;
+
+        let file = create_log_file(
+            &*self.file_system,
+            &log_file_path(&self.dirpath, generation),
+        )?;
+
+        // Opening a store only forgives a torn write at the end of the active log,
+        // so the outgoing one has to be synced before the manifest seals it,
+        // whatever the sync policy
+        writer.active_log_mut()?.file.sync_data()?;
+
+        let mut log_generations = writer.log_generations.clone();
+        log_generations.push(generation);
+        write_manifest(&*self.file_system, &self.dirpath, &log_generations)?;
+
+        self.write_readers()?.insert(generation, file.clone());
+
+        writer.log_file_counter = generation;
+        writer.active_log = Some(LogFileWriter { file, generation });
+
+        writer
+            .log_file_stats
+            .insert(generation, LogFileStats::empty());
+        writer.log_generations = log_generations;
+
+        Ok(())
+    }
+
+    /// Get the active log file, potentially opening a new one
+    /// for writing to
+    fn setup_active_log_file(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        let max_segment_size = writer.options.max_segment_size;
+        let active_log = writer.active_log_mut()?;
+        let active_log_file_len = active_log.file.len()?;
+
+        if active_log_file_len > max_segment_size {
+            let sealed_generation = active_log.generation;
+            self.open_new_log_file(writer)?;
+            // The worker only goes away once every KvStore handle has been dropped
+            let _ = self.send_to_worker(CompactionMessage::WriteHint {
+                generation: sealed_generation,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl SyncTarget for SharedKvStore {
+    fn sync(&self) -> Result<u64> {
+        // Every log but the active one was synced as it was sealed
+        let (write_seq, active_log_file) = {
+            let writer = self.lock_writer()?;
+            match &writer.active_log {
+                Some(active_log) => (writer.write_seq, active_log.file.clone()),
+                None => return Ok(writer.write_seq),
+            }
+        };
+        active_log_file.sync_data()?;
+        Ok(write_seq)
+    }
+}
+
+/// Apply one record found while opening a store to the index and file stats
+fn index_record(
+    log_index: &mut LogFileIndexMap,
+    log_file_stats: &mut HashMap<u64, LogFileStats>,
+    key: Vec<u8>,
+    deleted: bool,
+    location: RecordLocation,
+) {
+    let (generation, _, record_size) = location;
+    let prev = if deleted {
+        // Nothing ever points at a tombstone
+        if let Some(stats) = log_file_stats.get_mut(&generation) {
+            stats.dead += record_size;
+        }
+        log_index.remove(&key)
+    } else {
+        log_index.insert(key, location)
+    };
+    if let Some((prev_generation, _, prev_record_size)) = prev {
+        if let Some(stats) = log_file_stats.get_mut(&prev_generation) {
+            stats.dead += prev_record_size;
+        }
+    }
+    if let Some(stats) = log_file_stats.get_mut(&generation) {
+        stats.len += record_size;
+    }
+}
+
+/// Count the bytes of a log which belong to no record, such as the
+/// headers of write batches, as dead
+fn count_unowned_bytes(
+    log_file_stats: &mut HashMap<u64, LogFileStats>,
+    generation: u64,
+    log_len: u64,
+) {
+    if let Some(stats) = log_file_stats.get_mut(&generation) {
+        if log_len > stats.len {
+            stats.dead += log_len - stats.len;
+            stats.len = log_len;
+        }
+    }
+}
+
+/// Path of the log file for a generation
+fn log_file_path(dirpath: &Path, generation: u64) -> PathBuf {
+    dirpath.join(format!("{}.log", generation))
+}
+
+/// Create a log holding nothing but its header. The header is synced before
+/// anything can list the log, so a listed log is never without one
+fn create_log_file(file_system: &dyn FileSystem, path: &Path) -> Result<Arc<dyn FileHandle>> {
+    // Anything already there was left by an attempt which failed before the
+    // manifest listed it
+    file_system.create(path)?;
+    let file = file_system.open_append(path)?;
+    file.append(&LOG_MAGIC)?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+/// Fail with `KvStoreError::UnknownLogFormat` unless a log `file_len` bytes
+/// long starts with `LOG_MAGIC`
+pub(super) fn check_log_header(file: &dyn FileHandle, path: &Path, file_len: u64) -> Result<()> {
+    let mut header = [0u8; LOG_HEADER_SIZE as usize];
+    if file_len < LOG_HEADER_SIZE {
+        return Err(KvStoreError::UnknownLogFormat(path.to_path_buf()));
+    }
+    file.read_exact_at(&mut header, 0)?;
+    if header != LOG_MAGIC {
+        return Err(KvStoreError::UnknownLogFormat(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Parse the generation out of a `<generation>.log` or `<generation>.hint` file name
+fn log_file_generation(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Read, verify and decode a single record with a positional read,
+/// so the handle can be shared between threads
+fn read_record_at(
+    file: &dyn FileHandle,
+    dirpath: &Path,
+    location: &RecordLocation,
+) -> Result<Record> {
+    read_versioned_at(file, dirpath, location).map(|(record, _seq)| record)
+}
+
+/// Read a single record along with the sequence number of the commit which wrote it
+fn read_versioned_at(
+    file: &dyn FileHandle,
+    dirpath: &Path,
+    location: &RecordLocation,
+) -> Result<(Record, u64)> {
+    let (generation, offset, record_size) = *location;
+    let mut buf = vec![0u8; record_size as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Record::decode_versioned(&buf).ok_or_else(|| KvStoreError::Corruption {
+        path: log_file_path(dirpath, generation),
+        offset,
+    })
+}
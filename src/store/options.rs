@@ -0,0 +1,99 @@
+use super::vfs::{FileSystem, OsFileSystem};
+use super::CompactionPolicy;
+use crate::sync::SyncPolicy;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Tunable parameters for opening a `KvStore`
+/// ```rust
+/// extern crate kvs;
+/// use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+/// use tempfile::TempDir;
+/// # use std::error::Error;
+/// #
+/// # fn main() -> Result<(), Box<Error>> {
+/// let temp_dir = TempDir::new()?;
+/// let options = KvStoreOptions::new()
+///     .max_segment_size(64 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always);
+/// let store = KvStore::open_with(temp_dir.path(), options)?;
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) max_segment_size: u64,
+    pub(super) compaction_policy: CompactionPolicy,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_buffer_size: usize,
+    pub(super) read_only: bool,
+    pub(super) sweep_interval: Duration,
+    pub(super) file_system: Arc<dyn FileSystem>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: 1024 * 1024,
+            compaction_policy: CompactionPolicy::default(),
+            sync_policy: SyncPolicy::default(),
+            read_buffer_size: 64 * 1024,
+            read_only: false,
+            sweep_interval: Duration::from_secs(1),
+            file_system: Arc::new(OsFileSystem),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Options with every parameter at its default
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a new log file once the active one grows past this many bytes
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Decide when the background worker compacts on its own
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction_policy = policy;
+        self
+    }
+
+    /// Decide when writes are forced out to disk
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Size of the buffer used to read whole log files when replaying them
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Open the store without ever writing to its directory. Writes and
+    /// compaction fail with `KvStoreError::ReadOnly`
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// How often the background sweeper removes keys whose TTL has run out.
+    /// Until then they're hidden from reads but still take up space
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Do all file I/O through something other than the OS's filesystem,
+    /// such as one which simulates crashes
+    pub fn file_system(mut self, file_system: Arc<dyn FileSystem>) -> Self {
+        self.file_system = file_system;
+        self
+    }
+}
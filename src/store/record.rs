@@ -0,0 +1,482 @@
+use crate::errors::{KvStoreError, Result};
+use crate::ttl::{has_expired, has_expired_at, now_millis};
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+
+/// Every log starts with these bytes, the last of which is the version of the
+/// record format.
+///
+/// Logs written before records were framed and checksummed hold bare bson
+/// documents and have no header. Replaying one as frames would take the first
+/// document for a torn write and truncate the log, so they're refused with
+/// `KvStoreError::UnknownLogFormat` instead. Copy the data out with a build
+/// from before the change, then load it into a fresh store
+pub(super) const LOG_MAGIC: [u8; 8] = *b"kvslog\0\x01";
+
+/// Size of the header every log starts with
+pub(super) const LOG_HEADER_SIZE: u64 = 8;
+
+/// Bytes in front of every record in a log: the payload length and the
+/// payload's CRC32, both little endian u32s
+pub(super) const RECORD_HEADER_SIZE: u64 = 8;
+
+/// The most a torn write may cut off the end of the active log. A damaged
+/// frame with more than this left after it is reported as corruption, as
+/// no crash part way through a single append could have left it
+const MAX_TORN_TAIL: u64 = 1024 * 1024;
+
+/// How many frames after a damaged one may fail their checksum before the
+/// damage is reported as corruption rather than looked into any further
+const MAX_PROBE_FAILURES: usize = 16;
+
+/// The smallest payload a record can have: its kind, commit sequence number
+/// and key length
+const MIN_RECORD_PAYLOAD: usize = 13;
+
+/// Set in a frame's length to mark a batch, whose payload is the framed
+/// records of a `WriteBatch`. The CRC covers them all, so replay applies
+/// every record of a batch or none of them
+const BATCH_FLAG: u32 = 1 << 31;
+
+const RECORD_KIND_SET: u8 = 1;
+const RECORD_KIND_DELETE: u8 = 2;
+const RECORD_KIND_EXPIRING_SET: u8 = 3;
+
+/// An enum which defines records
+#[derive(Debug)]
+pub(super) enum Record {
+    /// A key, its value and when it expires in milliseconds since the Unix epoch
+    Set(Vec<u8>, Vec<u8>, Option<u64>),
+    Delete(Vec<u8>),
+}
+
+impl Record {
+    /// When a set record's key expires
+    pub(super) fn expires_at(&self) -> Option<u64> {
+        match self {
+            Record::Set(_, _, expires_at) => *expires_at,
+            Record::Delete(_) => None,
+        }
+    }
+
+    /// Whether this is a set record whose key has expired
+    pub(super) fn has_expired(&self) -> bool {
+        self.expires_at().is_some_and(has_expired)
+    }
+
+    /// The value the record gives its key, `None` for a tombstone or
+    /// once the key has expired
+    pub(super) fn into_live_value(self) -> Option<Vec<u8>> {
+        self.into_value_at(now_millis())
+    }
+
+    /// The value the record gave its key at `at`, in milliseconds since the
+    /// Unix epoch. `None` for a tombstone or if the key had expired by then
+    pub(super) fn into_value_at(self, at: u64) -> Option<Vec<u8>> {
+        match self {
+            Record::Set(_, _, Some(expires_at)) if has_expired_at(expires_at, at) => None,
+            Record::Set(_, value, _) => Some(value),
+            Record::Delete(_) => None,
+        }
+    }
+
+    /// Serialize the record into a checksummed frame ready to be appended to a log.
+    ///
+    /// The payload is `[kind u8][commit seq u64][key length u32][key]`, followed
+    /// for sets by `[value length u32][value]` and for sets which expire by
+    /// `[expires at u64]`, all little endian. The commit sequence number is
+    /// left at zero until `stamp_seq` fills it in
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        let header_size = RECORD_HEADER_SIZE as usize;
+        let mut frame = vec![0u8; header_size];
+        match self {
+            Record::Set(key, value, expires_at) => {
+                let kind = match expires_at {
+                    Some(_) => RECORD_KIND_EXPIRING_SET,
+                    None => RECORD_KIND_SET,
+                };
+                frame.push(kind);
+                frame.extend_from_slice(&0u64.to_le_bytes());
+                put_bytes(&mut frame, key)?;
+                put_bytes(&mut frame, value)?;
+                if let Some(expires_at) = expires_at {
+                    frame.extend_from_slice(&expires_at.to_le_bytes());
+                }
+            }
+            Record::Delete(key) => {
+                frame.push(RECORD_KIND_DELETE);
+                frame.extend_from_slice(&0u64.to_le_bytes());
+                put_bytes(&mut frame, key)?;
+            }
+        }
+
+        let payload_len = frame.len() - header_size;
+        if payload_len >= BATCH_FLAG as usize {
+            return Err(KvStoreError::SerializationError(
+                "Record is too large".to_owned(),
+            ));
+        }
+        let checksum = crc32fast::hash(&frame[header_size..]);
+        frame[..4].copy_from_slice(&(payload_len as u32).to_le_bytes());
+        frame[4..header_size].copy_from_slice(&checksum.to_le_bytes());
+        Ok(frame)
+    }
+
+    /// Verify and decode a whole frame as written by `encode`.
+    /// Returns `None` if the frame is damaged in any way
+    pub(super) fn decode(frame: &[u8]) -> Option<Self> {
+        Self::decode_versioned(frame).map(|(record, _seq)| record)
+    }
+
+    /// Decode a frame along with the sequence number of the commit which wrote it
+    pub(super) fn decode_versioned(frame: &[u8]) -> Option<(Self, u64)> {
+        let header_size = RECORD_HEADER_SIZE as usize;
+        if frame.len() < header_size {
+            return None;
+        }
+        let (header, payload) = frame.split_at(header_size);
+        let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if payload_len as usize != payload.len() || crc32fast::hash(payload) != checksum {
+            return None;
+        }
+
+        let mut payload = payload;
+        let kind = take(&mut payload, 1)?[0];
+        let seq = take_u64(&mut payload)?;
+        let key = take_bytes(&mut payload)?.to_vec();
+        let record = match kind {
+            RECORD_KIND_SET | RECORD_KIND_EXPIRING_SET => {
+                let value = take_bytes(&mut payload)?.to_vec();
+                let expires_at = if kind == RECORD_KIND_EXPIRING_SET {
+                    Some(take_u64(&mut payload)?)
+                } else {
+                    None
+                };
+                Record::Set(key, value, expires_at)
+            }
+            RECORD_KIND_DELETE => Record::Delete(key),
+            _ => return None,
+        };
+        if !payload.is_empty() {
+            return None;
+        }
+        Some((record, seq))
+    }
+
+    /// The record's key and whether it's a tombstone
+    pub(super) fn into_key(self) -> (Vec<u8>, bool) {
+        match self {
+            Record::Set(key, _value, _expires_at) => (key, false),
+            Record::Delete(key) => (key, true),
+        }
+    }
+}
+
+/// Append a little endian u32 length prefixed byte string
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    if bytes.len() > u32::MAX as usize {
+        return Err(KvStoreError::SerializationError(
+            "Key or value is too large".to_owned(),
+        ));
+    }
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Split `len` bytes off the front of a buffer, `None` if it's too short
+pub(super) fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(taken)
+}
+
+pub(super) fn take_u32(buf: &mut &[u8]) -> Option<u32> {
+    let b = take(buf, 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub(super) fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+    let b = take(buf, 8)?;
+    Some(u64::from_le_bytes([
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+    ]))
+}
+
+/// Split a little endian u32 length prefixed byte string off a buffer
+pub(super) fn take_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(buf)?;
+    take(buf, len as usize)
+}
+
+/// Wrap already framed records in a batch frame. Each record keeps its own
+/// frame, starting `RECORD_HEADER_SIZE` bytes into the batch
+pub(super) fn encode_batch(frames: &[u8]) -> Result<Vec<u8>> {
+    if frames.len() >= BATCH_FLAG as usize {
+        return Err(KvStoreError::SerializationError(
+            "Write batch is too large".to_owned(),
+        ));
+    }
+    let mut batch = Vec::with_capacity(RECORD_HEADER_SIZE as usize + frames.len());
+    batch.extend_from_slice(&(frames.len() as u32 | BATCH_FLAG).to_le_bytes());
+    batch.extend_from_slice(&crc32fast::hash(frames).to_le_bytes());
+    batch.extend_from_slice(frames);
+    Ok(batch)
+}
+
+/// Fill in the commit sequence number of every record in a frame written by
+/// `encode` or `encode_batch`, then recompute its checksums
+pub(super) fn stamp_seq(frame: &mut [u8], seq: u64) {
+    let header_size = RECORD_HEADER_SIZE as usize;
+    let raw_len = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    if raw_len & BATCH_FLAG != 0 {
+        let mut offset = header_size;
+        while offset < frame.len() {
+            let payload_len = u32::from_le_bytes([
+                frame[offset],
+                frame[offset + 1],
+                frame[offset + 2],
+                frame[offset + 3],
+            ]);
+            let record_size = header_size + payload_len as usize;
+            stamp_seq(&mut frame[offset..offset + record_size], seq);
+            offset += record_size;
+        }
+    } else {
+        frame[header_size + 1..header_size + 9].copy_from_slice(&seq.to_le_bytes());
+    }
+    let checksum = crc32fast::hash(&frame[header_size..]);
+    frame[4..header_size].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// What `RecordReader` found at the current position of a log
+#[derive(Debug)]
+pub(super) enum NextRecord {
+    /// An intact record along with its offset and size on disk, header included
+    Record(Record, u64, u64),
+    /// The log ended cleanly on a record boundary
+    End,
+    /// The last record in the log is incomplete or damaged, which is
+    /// what a crash part way through an append leaves behind
+    Torn,
+    /// A damaged record with more data after it
+    Corrupt,
+}
+
+/// Reads the records of a log one after another from its start,
+/// including the records inside batches
+#[derive(Debug)]
+pub(super) struct RecordReader<R> {
+    reader: R,
+    offset: u64,
+    len: u64,
+    /// Records of the last batch read which haven't been returned yet
+    batched: VecDeque<(Record, u64, u64)>,
+    /// Highest commit sequence number of any record read so far
+    max_seq: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// Read records from a log which is `len` bytes long. The log's header
+    /// has already been checked, and `reader` starts just after it
+    pub(super) fn new(reader: R, len: u64) -> Self {
+        Self {
+            reader,
+            offset: LOG_HEADER_SIZE,
+            len,
+            batched: VecDeque::new(),
+            max_seq: 0,
+        }
+    }
+
+    /// Highest commit sequence number of any record read so far
+    pub(super) fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    /// Offset of the end of the last record or batch read. After a torn
+    /// record this is where the intact part of the log ends
+    pub(super) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read the next record. Once anything but a `NextRecord::Record`
+    /// is returned the reader shouldn't be used again
+    pub(super) fn next(&mut self) -> io::Result<NextRecord> {
+        if let Some((record, offset, size)) = self.batched.pop_front() {
+            return Ok(NextRecord::Record(record, offset, size));
+        }
+
+        let remaining = self.len - self.offset;
+        if remaining == 0 {
+            return Ok(NextRecord::End);
+        }
+        if remaining < RECORD_HEADER_SIZE {
+            return Ok(NextRecord::Torn);
+        }
+
+        let mut frame = vec![0u8; RECORD_HEADER_SIZE as usize];
+        self.reader.read_exact(&mut frame)?;
+        let raw_len = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let payload_len = u64::from(raw_len & !BATCH_FLAG);
+        let record_size = RECORD_HEADER_SIZE + payload_len;
+        if record_size > remaining {
+            return self.torn_or_corrupt(frame, remaining);
+        }
+
+        frame.resize(record_size as usize, 0);
+        self.reader
+            .read_exact(&mut frame[RECORD_HEADER_SIZE as usize..])?;
+
+        if raw_len & BATCH_FLAG != 0 {
+            return self.read_batch(frame, remaining);
+        }
+
+        match Record::decode_versioned(&frame) {
+            Some((record, seq)) => {
+                self.max_seq = self.max_seq.max(seq);
+                let offset = self.offset;
+                self.offset += record_size;
+                Ok(NextRecord::Record(record, offset, record_size))
+            }
+            None => self.torn_or_corrupt(frame, remaining),
+        }
+    }
+
+    /// Tell whether a damaged frame at the current offset is the torn end of
+    /// the log or damage in the middle of it. A failed append is cut back off
+    /// the log, so only a crash can leave a damaged frame behind, and nothing
+    /// written by a later commit ever follows one. `read` is what's been read
+    /// of the frame so far, out of the `remaining` bytes left in the log.
+    ///
+    /// Only a bounded tail is ever looked through. Frames which check out are
+    /// stepped over whole, and after `MAX_PROBE_FAILURES` candidates which
+    /// don't the damage is reported rather than probed byte by byte
+    fn torn_or_corrupt(&mut self, mut read: Vec<u8>, remaining: u64) -> io::Result<NextRecord> {
+        if remaining > MAX_TORN_TAIL {
+            return Ok(NextRecord::Corrupt);
+        }
+        let unread = remaining - read.len() as u64;
+        (&mut self.reader).take(unread).read_to_end(&mut read)?;
+        // The records inside a torn batch are intact, but they were all written
+        // by the same commit as the batch
+        let damaged_seq = unverified_seq(&read);
+        let mut failures = 0;
+        let mut start = 1;
+        while start < read.len() {
+            match probe_frame(&read[start..]) {
+                Probe::Intact(seq, _) if Some(seq) != damaged_seq => {
+                    return Ok(NextRecord::Corrupt);
+                }
+                Probe::Intact(_, size) => {
+                    start += size;
+                    continue;
+                }
+                Probe::Damaged => {
+                    failures += 1;
+                    if failures == MAX_PROBE_FAILURES {
+                        return Ok(NextRecord::Corrupt);
+                    }
+                }
+                Probe::Implausible => {}
+            }
+            start += 1;
+        }
+        Ok(NextRecord::Torn)
+    }
+
+    /// Verify a whole batch frame before returning the first of its records
+    fn read_batch(&mut self, frame: Vec<u8>, remaining: u64) -> io::Result<NextRecord> {
+        if !matches!(probe_frame(&frame), Probe::Intact(..)) {
+            return self.torn_or_corrupt(frame, remaining);
+        }
+
+        let mut frames = &frame[RECORD_HEADER_SIZE as usize..];
+        let mut offset = self.offset + RECORD_HEADER_SIZE;
+        while !frames.is_empty() {
+            // The batch's checksum passed, so anything wrong in here wasn't a torn write
+            if frames.len() < RECORD_HEADER_SIZE as usize {
+                self.batched.clear();
+                return Ok(NextRecord::Corrupt);
+            }
+            let payload_len = u32::from_le_bytes([frames[0], frames[1], frames[2], frames[3]]);
+            let record_size = RECORD_HEADER_SIZE as usize + payload_len as usize;
+            let record = match frames.get(..record_size).and_then(Record::decode_versioned) {
+                Some((record, seq)) => {
+                    self.max_seq = self.max_seq.max(seq);
+                    record
+                }
+                None => {
+                    self.batched.clear();
+                    return Ok(NextRecord::Corrupt);
+                }
+            };
+            self.batched.push_back((record, offset, record_size as u64));
+            offset += record_size as u64;
+            frames = &frames[record_size..];
+        }
+
+        // Empty batches are never written, but would simply have nothing to apply
+        self.offset += frame.len() as u64;
+        self.next()
+    }
+}
+
+/// What `probe_frame` found at the start of a buffer
+enum Probe {
+    /// A whole frame which checks out, along with the sequence number of the
+    /// commit which wrote it and its size. A batch's is that of its first record
+    Intact(u64, usize),
+    /// A header for a frame which fits in the buffer but fails its checksum
+    Damaged,
+    /// Nothing which could be the start of a frame
+    Implausible,
+}
+
+/// Look for a whole frame at the start of `buf`
+fn probe_frame(buf: &[u8]) -> Probe {
+    let mut header = buf;
+    let (raw_len, checksum) = match (take_u32(&mut header), take_u32(&mut header)) {
+        (Some(raw_len), Some(checksum)) => (raw_len, checksum),
+        _ => return Probe::Implausible,
+    };
+    let payload_len = (raw_len & !BATCH_FLAG) as usize;
+    let record_size = RECORD_HEADER_SIZE as usize + payload_len;
+    let frame = match buf.get(..record_size) {
+        Some(frame) if payload_len >= MIN_RECORD_PAYLOAD => frame,
+        _ => return Probe::Implausible,
+    };
+    let seq = if raw_len & BATCH_FLAG == 0 {
+        Record::decode_versioned(frame).map(|(_record, seq)| seq)
+    } else {
+        let frames = &frame[RECORD_HEADER_SIZE as usize..];
+        if crc32fast::hash(frames) == checksum {
+            unverified_seq(frames)
+        } else {
+            None
+        }
+    };
+    match seq {
+        Some(seq) => Probe::Intact(seq, record_size),
+        None => Probe::Damaged,
+    }
+}
+
+/// The commit sequence number a frame claims, without checking any of it
+fn unverified_seq(buf: &[u8]) -> Option<u64> {
+    let raw_len = take_u32(&mut &buf[..])?;
+    // A batch's records start after its own header
+    let header_size = if raw_len & BATCH_FLAG != 0 {
+        2 * RECORD_HEADER_SIZE as usize
+    } else {
+        RECORD_HEADER_SIZE as usize
+    };
+    let mut payload = buf.get(header_size..)?;
+    take(&mut payload, 1)?;
+    take_u64(&mut payload)
+}
@@ -0,0 +1,440 @@
+use super::hint::hint_file_path;
+use super::vfs::FileHandle;
+use super::{log_file_path, read_record_at, LogFileIndexMap, RecordLocation, SharedKvStore};
+use crate::errors::{KvStoreError, Result};
+use crate::kv::{KvBytePairs, KvsSnapshot};
+use crate::scan::{is_empty_range, owned_bounds, KvPage, PagedScan};
+use crate::ttl::{has_expired_at, now_millis};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+/// The live snapshots, and the log generations compaction has replaced
+/// which are kept on disk for them
+#[derive(Debug, Default)]
+pub(super) struct PinnedGenerations {
+    /// The sequence number of every live snapshot by its id. Snapshots are
+    /// numbered in the order they're taken, so the oldest comes first
+    snapshots: BTreeMap<u64, u64>,
+    next_id: u64,
+    /// Generations compaction replaced while snapshots were live, kept until
+    /// every snapshot taken before then is released
+    retired: HashMap<u64, RetiredLog>,
+}
+
+#[derive(Debug)]
+struct RetiredLog {
+    len: u64,
+    reader: Arc<dyn FileHandle>,
+    /// Id of the newest snapshot which was live when it was replaced
+    newest_snapshot: u64,
+}
+
+impl PinnedGenerations {
+    /// Number of live snapshots
+    pub(super) fn snapshots(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Bytes of log files kept on disk only because a snapshot reads from them
+    pub(super) fn retired_bytes(&self) -> u64 {
+        self.retired.values().map(|retired| retired.len).sum()
+    }
+
+    /// Sequence number of the oldest live snapshot
+    fn oldest_seq(&self) -> Option<u64> {
+        self.snapshots.values().next().copied()
+    }
+
+    /// Add a snapshot which sees commits up to `seq`, returning its id
+    fn register(&mut self, seq: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.snapshots.insert(id, seq);
+        id
+    }
+
+    /// Remove a snapshot. Returns the retired generations which no snapshot
+    /// can read from anymore, whose files can now be removed
+    fn release(&mut self, id: u64) -> Vec<u64> {
+        self.snapshots.remove(&id);
+        let oldest = self.snapshots.keys().next().copied();
+        let released: Vec<u64> = self
+            .retired
+            .iter()
+            .filter(|(_, retired)| oldest.is_none_or(|oldest| oldest > retired.newest_snapshot))
+            .map(|(generation, _)| *generation)
+            .collect();
+        for generation in &released {
+            self.retired.remove(generation);
+        }
+        released
+    }
+}
+
+/// What keys held before later commits replaced them, kept while a snapshot
+/// taken before the commit is live. Together with the index they give
+/// every key's value as of any live snapshot
+#[derive(Debug, Default)]
+pub(super) struct SupersededVersions {
+    versions: BTreeMap<Vec<u8>, VecDeque<Superseded>>,
+    /// The keys each commit replaced a version of, by its sequence number
+    replaced_by: BTreeMap<u64, Vec<Vec<u8>>>,
+}
+
+#[derive(Debug)]
+struct Superseded {
+    /// Sequence number of the commit which replaced it
+    until: u64,
+    /// Where its record is, `None` if the key didn't exist
+    location: Option<RecordLocation>,
+    /// When it expires, if it was dropped by compaction for having expired.
+    /// Snapshots taken since then don't need to read its record, which only
+    /// stays on disk for older ones
+    expires_at: Option<u64>,
+}
+
+impl SupersededVersions {
+    /// Keep what a key held before the commit numbered `until` replaced it
+    pub(super) fn keep(
+        &mut self,
+        key: &[u8],
+        until: u64,
+        location: Option<RecordLocation>,
+        expires_at: Option<u64>,
+    ) {
+        self.versions
+            .entry(key.to_vec())
+            .or_default()
+            .push_back(Superseded {
+                until,
+                location,
+                expires_at,
+            });
+        self.replaced_by
+            .entry(until)
+            .or_default()
+            .push(key.to_vec());
+    }
+
+    /// Every key within a range with a superseded version, in key order
+    fn keys_in(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> impl Iterator<Item = &Vec<u8>> {
+        self.versions.range(range).map(|(key, _)| key)
+    }
+
+    /// Forget everything, once there are no snapshots left to read it
+    pub(super) fn clear(&mut self) {
+        self.versions.clear();
+        self.replaced_by.clear();
+    }
+
+    /// Where the record a key held as of commit `seq` is, if a later
+    /// commit has replaced it. `Some(None)` if it didn't exist
+    fn location_at(&self, key: &[u8], seq: u64, taken_at: u64) -> Option<Option<RecordLocation>> {
+        let version = self
+            .versions
+            .get(key)?
+            .iter()
+            .find(|version| version.until > seq)?;
+        match version.expires_at {
+            Some(expires_at) if has_expired_at(expires_at, taken_at) => Some(None),
+            _ => Some(version.location),
+        }
+    }
+
+    /// Forget every version no snapshot from commit `oldest_seq` onwards sees
+    fn prune(&mut self, oldest_seq: Option<u64>) {
+        let oldest_seq = match oldest_seq {
+            Some(oldest_seq) => oldest_seq,
+            None => return self.clear(),
+        };
+        let kept = self.replaced_by.split_off(&(oldest_seq + 1));
+        let pruned = mem::replace(&mut self.replaced_by, kept);
+        for key in pruned.into_values().flatten() {
+            if let Some(versions) = self.versions.get_mut(&key) {
+                while versions
+                    .front()
+                    .is_some_and(|version| version.until <= oldest_seq)
+                {
+                    versions.pop_front();
+                }
+                if versions.is_empty() {
+                    self.versions.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` as it was when the snapshot was taken.
+///
+/// Taking one only notes the latest commit. While it's live, commits keep
+/// what they replace for it and compaction leaves the log files it may read
+/// from on disk until it's dropped. Clones share the same snapshot
+/// ```rust
+/// extern crate kvs;
+/// use kvs::{KvStore, KvsEngine, KvsSnapshot};
+/// use tempfile::TempDir;
+/// # use std::error::Error;
+/// #
+/// # fn main() -> Result<(), Box<Error>> {
+/// let temp_dir = TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("key".to_owned(), "1".to_owned())?;
+/// let snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "2".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("1".to_owned()));
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreSnapshot {
+    state: Arc<SnapshotState>,
+}
+
+#[derive(Debug)]
+struct SnapshotState {
+    shared: Arc<SharedKvStore>,
+    id: u64,
+    /// When the snapshot was taken in milliseconds since the Unix epoch,
+    /// which decides whether keys set with a TTL have expired
+    taken_at: u64,
+    /// Sequence number of the latest commit the snapshot sees
+    seq: u64,
+}
+
+impl KvStoreSnapshot {
+    /// Note the latest commit and add the snapshot to the live ones
+    pub(super) fn new(shared: Arc<SharedKvStore>) -> Result<Self> {
+        // Commits land in the index with its write lock held, so holding the
+        // read lock keeps every commit either seen or keeping its old versions
+        let (id, taken_at, seq) = {
+            let _log_index = shared.read_index()?;
+            let taken_at = now_millis();
+            let seq = shared.committed_seq.load(Ordering::SeqCst);
+            let id = shared.lock_pins()?.register(seq);
+            (id, taken_at, seq)
+        };
+
+        Ok(Self {
+            state: Arc::new(SnapshotState {
+                shared,
+                id,
+                taken_at,
+                seq,
+            }),
+        })
+    }
+
+    /// Sequence number of the latest commit the snapshot sees
+    pub(super) fn seq(&self) -> u64 {
+        self.state.seq
+    }
+
+    /// Whether the snapshot has a record for a key, even one which had expired
+    pub(super) fn has_key(&self, key: &[u8]) -> Result<bool> {
+        let shared = &self.state.shared;
+        let log_index = shared.read_index()?;
+        let superseded = shared.read_superseded()?;
+        Ok(self.state.locate(&log_index, &superseded, key).is_some())
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let state = &self.state;
+        let (log_file, location) = {
+            let log_index = state.shared.read_index()?;
+            let superseded = state.shared.read_superseded()?;
+            match state.locate(&log_index, &superseded, key) {
+                // As with `get`, grab the file handle while still holding the index lock
+                Some(location) => (state.shared.pinned_reader(location.0)?, location),
+                None => return Ok(None),
+            }
+        };
+        let record = read_record_at(&*log_file, &state.shared.dirpath, &location)?;
+        Ok(record.into_value_at(state.taken_at))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvBytePairs> {
+        let state = self.state.clone();
+        Ok(Box::new(PagedScan::new(
+            owned_bounds(&range),
+            move |start, end, limit| state.read_page(start, end, limit),
+        )))
+    }
+}
+
+impl SnapshotState {
+    /// Where the record the snapshot sees for a key is, `None` if it
+    /// didn't exist when the snapshot was taken
+    fn locate(
+        &self,
+        log_index: &LogFileIndexMap,
+        superseded: &SupersededVersions,
+        key: &[u8],
+    ) -> Option<RecordLocation> {
+        match superseded.location_at(key, self.seq, self.taken_at) {
+            Some(location) => location,
+            None => log_index.get(key).copied(),
+        }
+    }
+
+    /// Read up to `limit` keys within a range which were live when the
+    /// snapshot was taken, along with their values. Keys written since are
+    /// in the index or among the superseded versions, so both are walked
+    fn read_page(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<KvPage> {
+        let mut page = Vec::with_capacity(limit);
+        let mut start = start.clone();
+        loop {
+            let wanted = limit - page.len();
+            let mut last = None;
+            let mut entries = Vec::new();
+            let exhausted = {
+                let log_index = self.shared.read_index()?;
+                let superseded = self.shared.read_superseded()?;
+                let range = (start.clone(), end.clone());
+                let mut live = log_index
+                    .range(range.clone())
+                    .map(|(key, _)| key)
+                    .peekable();
+                let mut replaced = superseded.keys_in(range).peekable();
+                let mut walked = 0;
+                loop {
+                    if walked == wanted {
+                        break false;
+                    }
+                    let key = match (live.peek(), replaced.peek()) {
+                        (Some(live_key), Some(replaced_key)) => live_key.min(replaced_key),
+                        (Some(key), None) | (None, Some(key)) => key,
+                        (None, None) => break true,
+                    };
+                    let key: Vec<u8> = key.to_vec();
+                    if live.peek() == Some(&&key) {
+                        live.next();
+                    }
+                    if replaced.peek() == Some(&&key) {
+                        replaced.next();
+                    }
+                    walked += 1;
+                    if let Some(location) = self.locate(&log_index, &superseded, &key) {
+                        // As with `get`, grab the file handle while still holding the index lock
+                        let log_file = self.shared.pinned_reader(location.0)?;
+                        entries.push((key.clone(), log_file, location));
+                    }
+                    last = Some(key);
+                }
+            };
+
+            if let Some(key) = last {
+                start = Bound::Excluded(key);
+            }
+            for (key, log_file, location) in entries {
+                let record = read_record_at(&*log_file, &self.shared.dirpath, &location)?;
+                if let Some(value) = record.into_value_at(self.taken_at) {
+                    page.push((key, value));
+                }
+            }
+
+            if exhausted || page.len() == limit || is_empty_range(&start, end) {
+                return Ok(page);
+            }
+        }
+    }
+}
+
+impl Drop for SnapshotState {
+    fn drop(&mut self) {
+        let released = {
+            let mut superseded = match self.shared.write_superseded() {
+                Ok(superseded) => superseded,
+                Err(_) => return,
+            };
+            let mut pins = match self.shared.lock_pins() {
+                Ok(pins) => pins,
+                Err(_) => return,
+            };
+            let released = pins.release(self.id);
+            superseded.prune(pins.oldest_seq());
+            released
+        };
+        // Anything left behind is removed the next time the store is opened
+        for generation in released {
+            let file_system = &self.shared.file_system;
+            let _ = file_system.remove_file(&log_file_path(&self.shared.dirpath, generation));
+            let _ = file_system.remove_file(&hint_file_path(&self.shared.dirpath, generation));
+        }
+    }
+}
+
+impl SharedKvStore {
+    pub(super) fn lock_pins(&self) -> Result<MutexGuard<'_, PinnedGenerations>> {
+        self.pins
+            .lock()
+            .map_err(|_e| KvStoreError::LockError("Error getting snapshot pins lock".to_owned()))
+    }
+
+    fn read_superseded(&self) -> Result<RwLockReadGuard<'_, SupersededVersions>> {
+        self.superseded
+            .read()
+            .map_err(|_e| KvStoreError::LockError("Error getting read lock".to_owned()))
+    }
+
+    pub(super) fn write_superseded(&self) -> Result<RwLockWriteGuard<'_, SupersededVersions>> {
+        self.superseded
+            .write()
+            .map_err(|_e| KvStoreError::LockError("Error getting write lock".to_owned()))
+    }
+
+    /// A reader for a generation, including one compaction has replaced
+    /// but which is kept on disk for snapshots
+    fn pinned_reader(&self, generation: u64) -> Result<Arc<dyn FileHandle>> {
+        match self.reader(generation) {
+            Ok(reader) => Ok(reader),
+            Err(e) => match self.lock_pins()?.retired.get(&generation) {
+                Some(retired) => Ok(retired.reader.clone()),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Stop reading from generations compaction has replaced and remove their
+    /// files, or keep them until every snapshot which may read them is released
+    pub(super) fn retire_generations(&self, generations: &HashSet<u64>) -> Result<()> {
+        // Each reader moves to the retired ones with both locks held, so a
+        // snapshot looking in one and then the other always finds it
+        let mut pins = self.lock_pins()?;
+        let mut log_file_readers = self.write_readers()?;
+        let newest_snapshot = pins.snapshots.keys().next_back().copied();
+        for generation in generations {
+            let reader = log_file_readers.remove(generation);
+            if let (Some(newest_snapshot), Some(reader)) = (newest_snapshot, reader) {
+                let len = reader.len()?;
+                pins.retired.insert(
+                    *generation,
+                    RetiredLog {
+                        len,
+                        reader,
+                        newest_snapshot,
+                    },
+                );
+                continue;
+            }
+            self.file_system
+                .remove_file(&log_file_path(&self.dirpath, *generation))?;
+            // Not every sealed generation has had its hint written yet
+            let _ = self
+                .file_system
+                .remove_file(&hint_file_path(&self.dirpath, *generation));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use super::KvStore;
+use crate::errors::Result;
+
+/// A point-in-time summary of a `KvStore`'s size on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KvStoreStats {
+    /// Number of live log files, the active one included
+    pub log_files: usize,
+    /// Total bytes written to the live log files
+    pub log_bytes: u64,
+    /// Bytes of the live log files belonging to overwritten or removed records,
+    /// which compaction would reclaim
+    pub dead_bytes: u64,
+    /// Number of keys in the index, including any which have expired but
+    /// haven't been swept yet
+    pub keys: usize,
+    /// Number of snapshots which haven't been released yet
+    pub snapshots: usize,
+    /// Bytes of log files compaction has replaced but which are kept on
+    /// disk because a snapshot still reads from them
+    pub pinned_bytes: u64,
+}
+
+impl KvStore {
+    /// Summarize the store's log files, keys and snapshots
+    /// ```rust
+    /// extern crate kvs;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// # use std::error::Error;
+    /// #
+    /// # fn main() -> Result<(), Box<Error>> {
+    /// let temp_dir = TempDir::new()?;
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key".to_owned(), "value".to_owned())?;
+    /// let snapshot = store.snapshot()?;
+    /// assert_eq!(store.stats()?.snapshots, 1);
+    /// drop(snapshot);
+    /// assert_eq!(store.stats()?.snapshots, 0);
+    /// #
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        let mut stats = KvStoreStats::default();
+        {
+            let writer = self.shared.lock_writer()?;
+            stats.log_files = writer.log_generations.len();
+            for file_stats in writer.log_file_stats.values() {
+                stats.log_bytes += file_stats.len;
+                stats.dead_bytes += file_stats.dead;
+            }
+        }
+        stats.keys = self.shared.read_index()?.len();
+        {
+            let pins = self.shared.lock_pins()?;
+            stats.snapshots = pins.snapshots();
+            stats.pinned_bytes = pins.retired_bytes();
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,31 @@
+use super::SharedKvStore;
+use crate::errors::Result;
+use crate::sweeper::SweepTarget;
+use crate::sync::Syncer;
+use crate::ttl::now_millis;
+use std::mem;
+use std::sync::Arc;
+
+/// Sweeps a `KvStore` for the sweeper, waiting on the syncer for the
+/// tombstones it writes
+pub(super) struct StoreSweepTarget {
+    pub(super) shared: Arc<SharedKvStore>,
+    pub(super) syncer: Arc<Syncer>,
+}
+
+impl SweepTarget for StoreSweepTarget {
+    /// Write a tombstone for every key whose expiry has passed. A key this
+    /// fails to remove stays hidden until compaction drops it
+    fn sweep(&self) -> Result<()> {
+        let due = {
+            let mut expiring = self.shared.lock_expiring()?;
+            let later = expiring.split_off(&(now_millis() + 1, Vec::new()));
+            mem::replace(&mut *expiring, later)
+        };
+        if due.is_empty() {
+            return Ok(());
+        }
+        self.shared
+            .commit_expired(&self.syncer, due.into_iter().map(|(_, key)| key).collect())
+    }
+}
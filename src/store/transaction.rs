@@ -0,0 +1,100 @@
+use super::commit::{Committed, WriteCondition};
+use super::record::Record;
+use super::{KvStore, KvStoreSnapshot};
+use crate::errors::{KvStoreError, Result};
+use crate::kv::{KvsSnapshot, KvsTransaction};
+use std::collections::BTreeMap;
+
+/// An optimistic transaction over a `KvStore`.
+///
+/// Beginning one only notes the latest commit. It reads through a snapshot
+/// as of that commit, which keeps every record version it can see until
+/// it's done. Its writes are committed as a single batch, which
+/// the commit leader only appends if none of their keys have a record from a
+/// later commit than the snapshot
+/// ```rust
+/// extern crate kvs;
+/// use kvs::{KvStore, KvsEngine, KvsTransaction};
+/// use tempfile::TempDir;
+/// # use std::error::Error;
+/// #
+/// # fn main() -> Result<(), Box<Error>> {
+/// let temp_dir = TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "10".to_owned())?;
+///
+/// let mut transaction = store.begin()?;
+/// transaction.set("from".to_owned(), "5".to_owned())?;
+/// transaction.set("to".to_owned(), "5".to_owned())?;
+/// assert_eq!(store.get("to".to_owned())?, None);
+/// transaction.commit()?;
+/// assert_eq!(store.get("to".to_owned())?, Some("5".to_owned()));
+/// #
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KvStoreTransaction {
+    store: KvStore,
+    snapshot: KvStoreSnapshot,
+    /// Every key written so far and its new value, `None` to remove it
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvStoreTransaction {
+    pub(super) fn new(store: KvStore) -> Result<Self> {
+        store.shared.check_writable()?;
+        let snapshot = KvStoreSnapshot::new(store.shared.clone())?;
+        Ok(Self {
+            store,
+            snapshot,
+            writes: BTreeMap::new(),
+        })
+    }
+}
+
+impl KvsTransaction for KvStoreTransaction {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.snapshot.get_bytes(key),
+        }
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::with_capacity(self.writes.len());
+        let mut existed = Vec::with_capacity(self.writes.len());
+        for (key, value) in self.writes {
+            existed.push(self.snapshot.has_key(&key)?);
+            records.push(match value {
+                Some(value) => Record::Set(key, value, None),
+                None => Record::Delete(key),
+            });
+        }
+        let condition = WriteCondition::Unchanged {
+            since: self.snapshot.seq(),
+            existed,
+        };
+        match self
+            .store
+            .shared
+            .commit_batch(&self.store.syncer, records, condition)?
+        {
+            Committed::Written(_) => Ok(()),
+            _ => Err(KvStoreError::TransactionConflict),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Everything a `KvStore` does to its directory goes through one of these,
+/// so tests can swap in a filesystem which loses unsynced writes or fails on demand.
+///
+/// Every file the store writes to is only ever appended to, and files are
+/// replaced by writing a new one and renaming it over the old
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// Open an existing file for reading
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>>;
+
+    /// Open a file for reading and appending to, creating it if it's missing
+    fn open_append(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>>;
+
+    /// Create an empty file for appending to, truncating it if it exists
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>>;
+
+    /// Paths of every file in a directory
+    fn read_dir(&self, dirpath: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Atomically replace `to` with `from`
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Cut a file down to `len` bytes
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Make files created, renamed or removed in a directory durable
+    fn sync_dir(&self, dirpath: &Path) -> io::Result<()>;
+
+    /// Read a whole file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path)?;
+        let mut contents = vec![0u8; file.len()? as usize];
+        file.read_exact_at(&mut contents, 0)?;
+        Ok(contents)
+    }
+}
+
+/// An open file. Reads are positional and writes always go on the end, so
+/// a single handle can be shared by any number of threads
+pub trait FileHandle: fmt::Debug + Send + Sync {
+    /// Read from `offset`, returning how many bytes were read
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Write all of `buf` to the end of the file
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Force the file's contents out to disk
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Force the file's contents and metadata out to disk
+    fn sync_all(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    /// Current length of the file
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether the file is empty
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Fill `buf` from `offset`, failing if the file ends first
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    let rest = buf;
+                    buf = &mut rest[n..];
+                    offset += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The filesystem of the machine we're running on, which stores use by default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(Arc::new(OsFile(file)))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        Ok(Arc::new(OsFile(file)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Arc::new(OsFile(file)))
+    }
+
+    fn read_dir(&self, dirpath: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dirpath)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.set_len(len)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dirpath: &Path) -> io::Result<()> {
+        File::open(dirpath)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dirpath: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+#[derive(Debug)]
+struct OsFile(File);
+
+impl FileHandle for OsFile {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        self.0.read_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+        self.0.seek_read(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        // Opened in append or freshly truncated, so every write lands on the end
+        (&self.0).write_all(buf)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+}
+
+/// Reads a file through to its end with positional reads, for replaying logs
+#[derive(Debug)]
+pub(super) struct SequentialReader {
+    file: Arc<dyn FileHandle>,
+    offset: u64,
+}
+
+impl SequentialReader {
+    /// Read a file from `offset` onwards
+    pub(super) fn new(file: Arc<dyn FileHandle>, offset: u64) -> Self {
+        Self { file, offset }
+    }
+}
+
+impl Read for SequentialReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Appends to a file through `io::Write`, so it can be buffered
+#[derive(Debug)]
+pub(super) struct Appender(pub(super) Arc<dyn FileHandle>);
+
+impl Write for Appender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use crate::errors::Result;
+use crossbeam::crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// An engine whose expired keys a `Sweeper` removes
+pub(crate) trait SweepTarget: Send + Sync + 'static {
+    /// Remove every key whose expiry has passed
+    fn sweep(&self) -> Result<()>;
+}
+
+/// Owns the background thread which removes expired keys, stopping and
+/// joining it on drop
+#[derive(Debug)]
+pub(crate) struct Sweeper {
+    /// Dropped to tell the thread to stop
+    shutdown: Option<Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub(crate) fn start(target: Arc<dyn SweepTarget>, interval: Duration) -> Result<Self> {
+        let (shutdown, stopped) = bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // A key this fails to remove stays hidden until it's swept again
+                    let _ = target.sweep();
+                }
+            })?;
+
+        Ok(Self {
+            shutdown: Some(shutdown),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
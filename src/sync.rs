@@ -0,0 +1,257 @@
+use crate::errors::{KvStoreError, Result};
+use std::fmt;
+use std::io;
+use std::result;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// When writes are forced out to disk. A write is only acknowledged
+/// once it's as durable as the policy asks for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS. Acknowledged writes can be lost on power failure
+    #[default]
+    Never,
+    /// Sync before acknowledging every write. Writers arriving while a sync
+    /// is running share the next one
+    Always,
+    /// Sync in the background at most this often, with every write waiting
+    /// for the next sync. Trades write latency for far fewer syncs. A zero
+    /// interval syncs every write, as `Always` does
+    Every(Duration),
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Every(interval) => write!(f, "{}ms", interval.as_millis()),
+        }
+    }
+}
+
+/// Parses `never`, `always` or a non-zero interval in milliseconds such as `10ms`
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => match s.trim_end_matches("ms").parse() {
+                Ok(0) => Err(format!(
+                    "Invalid sync policy {:?}, use always to sync every write",
+                    s
+                )),
+                Ok(millis) => Ok(SyncPolicy::Every(Duration::from_millis(millis))),
+                Err(_) => Err(format!("Invalid sync policy {:?}", s)),
+            },
+        }
+    }
+}
+
+/// An engine whose writes a `Syncer` makes durable. Writes are numbered in
+/// the order they're handed to the OS, starting from 1
+pub(crate) trait SyncTarget: Send + Sync + 'static {
+    /// Force every write made so far to disk, returning the number of the
+    /// latest write that's now durable
+    fn sync(&self) -> Result<u64>;
+}
+
+#[derive(Debug, Default)]
+struct SyncProgress {
+    /// Every write up to this number is durable
+    synced: u64,
+    /// The highest write number anyone is waiting on
+    wanted: u64,
+    /// Set while a thread is running a sync
+    syncing: bool,
+    /// Why the last sync failed, cleared once one succeeds
+    error: Option<String>,
+    shutdown: bool,
+}
+
+struct SyncState {
+    progress: Mutex<SyncProgress>,
+    /// Signalled whenever a sync finishes or the syncer is shutting down
+    changed: Condvar,
+    target: Arc<dyn SyncTarget>,
+}
+
+/// Makes writes durable according to a `SyncPolicy`, batching writers
+/// together so they share syncs
+pub(crate) struct Syncer {
+    policy: SyncPolicy,
+    state: Arc<SyncState>,
+    /// The background syncer for `SyncPolicy::Every`
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl fmt::Debug for Syncer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Syncer({:?})", self.policy)
+    }
+}
+
+impl Syncer {
+    pub(crate) fn start(policy: SyncPolicy, target: Arc<dyn SyncTarget>) -> Result<Self> {
+        // The background syncer would never wait between syncs, and so never
+        // let go of the progress lock for writers to get at
+        let policy = match policy {
+            SyncPolicy::Every(interval) if interval == Duration::ZERO => SyncPolicy::Always,
+            policy => policy,
+        };
+        let state = Arc::new(SyncState {
+            progress: Mutex::new(SyncProgress::default()),
+            changed: Condvar::new(),
+            target,
+        });
+
+        let handle = match policy {
+            SyncPolicy::Every(interval) => {
+                let state = state.clone();
+                Some(
+                    thread::Builder::new()
+                        .name("kvs-syncer".to_owned())
+                        .spawn(move || state.run_every(interval))?,
+                )
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            policy,
+            state,
+            handle,
+        })
+    }
+
+    /// Block until the write numbered `write` is as durable as the policy asks for
+    pub(crate) fn wait_for(&self, write: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Never => Ok(()),
+            SyncPolicy::Always => self.state.sync_through(write),
+            SyncPolicy::Every(_) => self.state.wait_for_background(write),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        if let Ok(mut progress) = self.state.lock_progress() {
+            progress.shutdown = true;
+        }
+        self.state.changed.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl SyncState {
+    fn lock_progress(&self) -> Result<MutexGuard<'_, SyncProgress>> {
+        self.progress
+            .lock()
+            .map_err(|_e| KvStoreError::LockError("Error getting sync lock".to_owned()))
+    }
+
+    fn wait<'a>(
+        &self,
+        progress: MutexGuard<'a, SyncProgress>,
+    ) -> Result<MutexGuard<'a, SyncProgress>> {
+        self.changed
+            .wait(progress)
+            .map_err(|_e| KvStoreError::LockError("Error getting sync lock".to_owned()))
+    }
+
+    /// Run a sync with the progress lock released, recording the outcome
+    fn run_sync<'a>(
+        &'a self,
+        mut progress: MutexGuard<'a, SyncProgress>,
+    ) -> Result<MutexGuard<'a, SyncProgress>> {
+        progress.syncing = true;
+        drop(progress);
+        let result = self.target.sync();
+
+        let mut progress = self.lock_progress()?;
+        progress.syncing = false;
+        match result {
+            Ok(synced) => {
+                progress.synced = progress.synced.max(synced);
+                progress.error = None;
+            }
+            Err(e) => progress.error = Some(e.to_string()),
+        }
+        self.changed.notify_all();
+        Ok(progress)
+    }
+
+    /// `SyncPolicy::Always`: the first writer to find no sync running starts
+    /// one, and everyone who arrived in the meantime waits for it
+    fn sync_through(&self, write: u64) -> Result<()> {
+        let mut progress = self.lock_progress()?;
+        loop {
+            if progress.synced >= write {
+                return Ok(());
+            }
+            if progress.syncing {
+                progress = self.wait(progress)?;
+                continue;
+            }
+            progress = self.run_sync(progress)?;
+            if let Some(error) = &progress.error {
+                return Err(sync_error(error));
+            }
+        }
+    }
+
+    /// `SyncPolicy::Every`: wait for the background syncer to cover `write`
+    fn wait_for_background(&self, write: u64) -> Result<()> {
+        let mut progress = self.lock_progress()?;
+        progress.wanted = progress.wanted.max(write);
+        while progress.synced < write {
+            progress = self.wait(progress)?;
+            if progress.synced < write {
+                if let Some(error) = &progress.error {
+                    return Err(sync_error(error));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Body of the background syncer thread
+    fn run_every(&self, interval: Duration) {
+        let mut progress = match self.lock_progress() {
+            Ok(progress) => progress,
+            Err(_) => return,
+        };
+        let mut next_sync = Instant::now() + interval;
+
+        while !progress.shutdown {
+            let now = Instant::now();
+            if now < next_sync {
+                progress = match self.changed.wait_timeout(progress, next_sync - now) {
+                    Ok((progress, _)) => progress,
+                    Err(_) => return,
+                };
+                continue;
+            }
+
+            next_sync = now + interval;
+            if progress.wanted > progress.synced {
+                progress = match self.run_sync(progress) {
+                    Ok(progress) => progress,
+                    Err(_) => return,
+                };
+            }
+        }
+    }
+}
+
+fn sync_error(message: &str) -> KvStoreError {
+    KvStoreError::Io(io::Error::other(message.to_owned()))
+}
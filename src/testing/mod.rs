@@ -0,0 +1,746 @@
+//! A conformance suite for `KvsEngine` implementations, and a filesystem
+//! for checking what a `KvStore` recovers after crashing.
+//!
+//! Every check takes a function which opens the engine in a directory, so
+//! it can reopen the engine to check what persisted. Each check works in a
+//! fresh temporary directory and panics on the first thing it finds wrong
+//! ```rust
+//! extern crate kvs;
+//! use kvs::{testing, KvStore};
+//!
+//! testing::run_all(|path| KvStore::open(path)).unwrap();
+//! ```
+
+pub use self::simulated_fs::{SimulatedFileSystem, Syscall};
+
+use crate::batch::WriteBatch;
+use crate::errors::{KvStoreError, Result};
+use crate::kv::{KvsEngine, KvsSnapshot, KvsTransaction};
+use crate::sled::SledKvsEngine;
+use crate::sync::SyncPolicy;
+use crate::ttl::Ttl;
+use std::ops::{Bound, RangeFull};
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod simulated_fs;
+
+/// Threads the concurrency checks write from
+const THREADS: usize = 8;
+
+/// Keys each of those threads writes
+const KEYS_PER_THREAD: usize = 100;
+
+/// Size of the value the large value check writes
+const LARGE_VALUE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Run every check in the suite
+pub fn run_all<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    get_stored_value(&open)?;
+    overwrite_value(&open)?;
+    get_non_existent_value(&open)?;
+    remove_key(&open)?;
+    remove_non_existent_key(&open)?;
+    reopen_persists(&open)?;
+    concurrent_writes(&open)?;
+    concurrent_reads(&open)?;
+    large_values(&open)?;
+    unicode_keys_and_values(&open)?;
+    empty_keys_and_values(&open)?;
+    binary_keys_and_values(&open)?;
+    write_batches(&open)?;
+    scans(&open)?;
+    compare_and_swap(&open)?;
+    ttls(&open)?;
+    snapshots(&open)?;
+    transactions(&open)?;
+    Ok(())
+}
+
+/// Open a `SledKvsEngine` for the suite. Sled releases its lock on the db from
+/// a background thread a moment after the last handle is dropped, so reopening
+/// straight away is retried for a while
+pub fn open_sled(path: &Path, sync_policy: SyncPolicy) -> Result<SledKvsEngine> {
+    let started = Instant::now();
+    loop {
+        match SledKvsEngine::open_with(path, sync_policy) {
+            Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            opened => return opened,
+        }
+    }
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+/// A key which was set reads back its value
+pub fn get_stored_value<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Setting a key again replaces its value
+pub fn overwrite_value<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// A key which was never set reads as `None`
+pub fn get_non_existent_value<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// A removed key reads as `None`
+pub fn remove_key<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+/// Removing a key which doesn't exist, or which was already removed, fails
+/// with `KvStoreError::NonExistentKeyError`
+pub fn remove_non_existent_key<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    match engine.remove("key1".to_owned()) {
+        Err(KvStoreError::NonExistentKeyError(_)) => {}
+        other => panic!("expected a missing key error, got {:?}", other),
+    }
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    match engine.remove("key1".to_owned()) {
+        Err(KvStoreError::NonExistentKeyError(_)) => {}
+        other => panic!("expected a missing key error, got {:?}", other),
+    }
+    Ok(())
+}
+
+/// Sets, overwrites and removes are all still there after reopening
+pub fn reopen_persists<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // Writes made after reopening persist too
+    engine.set("key1".to_owned(), "value5".to_owned())?;
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+/// Threads setting and removing their own keys at the same time all see
+/// their writes applied, before and after reopening
+pub fn concurrent_writes<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..KEYS_PER_THREAD {
+                    engine.set(format!("key{}-{}", thread_id, key_id), "value".to_owned())?;
+                }
+                for key_id in (0..KEYS_PER_THREAD).step_by(2) {
+                    engine.remove(format!("key{}-{}", thread_id, key_id))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    check_concurrent_writes(&engine)?;
+    drop(engine);
+    check_concurrent_writes(&open(dir.path())?)
+}
+
+fn check_concurrent_writes<E: KvsEngine>(engine: &E) -> Result<()> {
+    for thread_id in 0..THREADS {
+        for key_id in 0..KEYS_PER_THREAD {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some("value".to_owned())
+            };
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, key_id))?,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Threads reading at the same time all see every value
+pub fn concurrent_reads<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    for key_id in 0..KEYS_PER_THREAD {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS_PER_THREAD {
+                    let key_id = (i + thread_id) % KEYS_PER_THREAD;
+                    assert_eq!(
+                        engine.get(format!("key{}", key_id))?,
+                        Some(format!("value{}", key_id))
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("reader thread panicked")?;
+    }
+    Ok(())
+}
+
+/// A value of several megabytes round trips, before and after reopening
+pub fn large_values<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    let value: Vec<u8> = (0..LARGE_VALUE_SIZE).map(|i| (i % 251) as u8).collect();
+    engine.set_bytes(b"large".to_vec(), value.clone())?;
+    engine.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get_bytes(b"large")?.as_ref(), Some(&value));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(b"large")?, Some(value));
+    assert_eq!(engine.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+/// Keys and values outside ASCII round trip, before and after reopening
+pub fn unicode_keys_and_values<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let pairs = [
+        ("ключ", "значение"),
+        ("キー", "値"),
+        ("🔑", "💾"),
+        ("e\u{301}", "é"),
+    ];
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    for (key, value) in &pairs {
+        engine.set((*key).to_owned(), (*value).to_owned())?;
+    }
+    // Composed and decomposed forms are different keys
+    assert_eq!(engine.get("é".to_owned())?, None);
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    for (key, value) in &pairs {
+        assert_eq!(engine.get((*key).to_owned())?, Some((*value).to_owned()));
+    }
+    Ok(())
+}
+
+/// The empty key and empty values are ordinary keys and values, and an empty
+/// value is different from a missing key
+pub fn empty_keys_and_values<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(b"")?, None);
+    engine.set_bytes(Vec::new(), b"value".to_vec())?;
+    engine.set_bytes(b"key".to_vec(), Vec::new())?;
+    assert_eq!(engine.get_bytes(b"")?, Some(b"value".to_vec()));
+    assert_eq!(engine.get_bytes(b"key")?, Some(Vec::new()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(b"")?, Some(b"value".to_vec()));
+    assert_eq!(engine.get_bytes(b"key")?, Some(Vec::new()));
+    engine.remove_bytes(Vec::new())?;
+    assert_eq!(engine.get_bytes(b"")?, None);
+    Ok(())
+}
+
+/// Keys and values are stored byte for byte, and the `String` methods refuse
+/// values which aren't UTF-8 rather than mangling them
+pub fn binary_keys_and_values<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x9f, 0x92, 0xff, 0x0a];
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(b"binary".to_vec(), value.clone())?;
+    engine.set_bytes(b"text".to_vec(), b"value".to_vec())?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value.clone()));
+    match engine.get("binary".to_owned()) {
+        Err(KvStoreError::InvalidUtf8(_)) => {}
+        other => panic!("expected an invalid UTF-8 error, got {:?}", other),
+    }
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+
+    let pairs = engine
+        .scan_bytes::<RangeFull>(..)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"binary".to_vec(), value.clone()),
+            (b"text".to_vec(), b"value".to_vec()),
+            (key.clone(), value.clone()),
+        ]
+    );
+
+    assert_eq!(
+        engine.compare_and_swap_bytes(key.clone(), Some(vec![0x00]), None)?,
+        Err(Some(value.clone()))
+    );
+    assert_eq!(
+        engine.compare_and_swap_bytes(key.clone(), Some(value.clone()), None)?,
+        Ok(())
+    );
+    assert_eq!(engine.get_bytes(&key)?, None);
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(b"binary")?, Some(value));
+    assert_eq!(engine.get_bytes(&key)?, None);
+    Ok(())
+}
+
+/// A batch's writes all apply, in order, and persist across reopening
+pub fn write_batches<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "first".to_owned());
+    batch.set("key2".to_owned(), "second".to_owned());
+    batch.remove("missing".to_owned());
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key4".to_owned());
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    check_write_batch(&engine)?;
+    drop(engine);
+    check_write_batch(&open(dir.path())?)
+}
+
+fn check_write_batch<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("second".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    assert_eq!(engine.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+/// Scans return every live key in a range or with a prefix in order, across
+/// enough keys to span several pages, before and after reopening
+pub fn scans<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    for i in 0..700 {
+        let key = format!("key{:04}", i);
+        engine.set(key.clone(), format!("value-{}", key))?;
+    }
+    // Removed keys and keys outside the range never show up
+    for i in 600..700 {
+        engine.remove(format!("key{:04}", i))?;
+    }
+    engine.set("aaa".to_owned(), "before".to_owned())?;
+    engine.remove("aaa".to_owned())?;
+    engine.set("zzz".to_owned(), "after".to_owned())?;
+    engine.remove("zzz".to_owned())?;
+
+    check_scans(&engine)?;
+    drop(engine);
+    check_scans(&open(dir.path())?)
+}
+
+fn scanned<I: Iterator<Item = Result<(String, String)>>>(pairs: I) -> Result<Vec<String>> {
+    pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+}
+
+fn check_scans<E: KvsEngine>(engine: &E) -> Result<()> {
+    let keys: Vec<String> = (0..600).map(|i| format!("key{:04}", i)).collect();
+    for pair in engine.scan::<RangeFull>(..)? {
+        let (key, value) = pair?;
+        assert_eq!(value, format!("value-{}", key));
+    }
+    assert_eq!(scanned(engine.scan::<RangeFull>(..)?)?, keys);
+    assert_eq!(
+        scanned(engine.scan("key0100".to_owned().."key0400".to_owned())?)?,
+        &keys[100..400]
+    );
+    assert_eq!(
+        scanned(engine.scan((
+            Bound::Excluded("key0100".to_owned()),
+            Bound::Included("key0400".to_owned())
+        ))?)?,
+        &keys[101..401]
+    );
+    assert_eq!(
+        scanned(engine.scan("key0500".to_owned().."key0100".to_owned())?)?,
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        scanned(engine.scan_prefix("key03".to_owned())?)?,
+        &keys[300..400]
+    );
+    assert_eq!(scanned(engine.scan_prefix("key".to_owned())?)?, keys);
+    assert_eq!(
+        scanned(engine.scan_prefix("missing".to_owned())?)?,
+        Vec::<String>::new()
+    );
+    Ok(())
+}
+
+/// Compare and swap and set if absent only write when the current value
+/// matches, even with threads contending for the same key
+pub fn compare_and_swap<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let key = || "key".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+
+    assert!(engine.set_if_absent(key(), "1".to_owned())?);
+    assert!(!engine.set_if_absent(key(), "2".to_owned())?);
+    assert_eq!(engine.get(key())?, value("1"));
+
+    assert_eq!(
+        engine.compare_and_swap(key(), None, value("3"))?,
+        Err(value("1"))
+    );
+    assert_eq!(
+        engine.compare_and_swap(key(), value("2"), value("3"))?,
+        Err(value("1"))
+    );
+    assert_eq!(
+        engine.compare_and_swap(key(), value("1"), value("3"))?,
+        Ok(())
+    );
+    assert_eq!(engine.get(key())?, value("3"));
+
+    assert_eq!(engine.compare_and_swap(key(), value("3"), None)?, Ok(()));
+    assert_eq!(engine.get(key())?, None);
+    assert_eq!(engine.compare_and_swap(key(), value("3"), None)?, Err(None));
+    assert_eq!(engine.compare_and_swap(key(), None, None)?, Ok(()));
+
+    // Increment a counter from every thread, retrying whenever another got in first
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for _ in 0..KEYS_PER_THREAD {
+                    let mut current = engine.get("counter".to_owned())?;
+                    loop {
+                        let count: usize = current
+                            .as_ref()
+                            .and_then(|count| count.parse().ok())
+                            .expect("counter isn't a number");
+                        let new = Some((count + 1).to_string());
+                        match engine.compare_and_swap("counter".to_owned(), current, new)? {
+                            Ok(()) => break,
+                            Err(actual) => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("incrementing thread panicked")?;
+    }
+    let total = (THREADS * KEYS_PER_THREAD).to_string();
+    assert_eq!(engine.get("counter".to_owned())?, Some(total.clone()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get(key())?, None);
+    assert_eq!(engine.get("counter".to_owned())?, Some(total));
+    Ok(())
+}
+
+/// Keys set with a TTL read as missing once it runs out, and setting a key
+/// again without one keeps it forever
+pub fn ttls<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("forever".to_owned(), "value".to_owned())?;
+    engine.set_with_ttl(
+        "later".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "soon".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "kept".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set("kept".to_owned(), "kept".to_owned())?;
+
+    assert_eq!(engine.get("soon".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.ttl("forever".to_owned())?, Some(Ttl::Forever));
+    assert_eq!(engine.ttl("kept".to_owned())?, Some(Ttl::Forever));
+    assert_eq!(engine.ttl("missing".to_owned())?, None);
+    match engine.ttl("later".to_owned())? {
+        Some(Ttl::Remaining(remaining)) => assert!(remaining > Duration::from_secs(3500)),
+        other => panic!("expected a remaining TTL, got {:?}", other),
+    }
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("soon".to_owned())?, None);
+    assert_eq!(engine.ttl("soon".to_owned())?, None);
+    assert_eq!(engine.get("kept".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(
+        scanned(engine.scan::<RangeFull>(..)?)?,
+        vec!["forever", "kept", "later"]
+    );
+    match engine.remove("soon".to_owned()) {
+        Err(KvStoreError::NonExistentKeyError(_)) => {}
+        other => panic!("expected a missing key error, got {:?}", other),
+    }
+    assert_eq!(
+        engine.compare_and_swap("soon".to_owned(), None, Some("again".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(engine.ttl("soon".to_owned())?, Some(Ttl::Forever));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("soon".to_owned())?, Some("again".to_owned()));
+    match engine.ttl("later".to_owned())? {
+        Some(Ttl::Remaining(remaining)) => assert!(remaining > Duration::from_secs(3500)),
+        other => panic!("expected a remaining TTL, got {:?}", other),
+    }
+    Ok(())
+}
+
+/// A snapshot never sees writes made after it was taken, nor loses keys
+/// which expire after it was taken
+pub fn snapshots<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "lived".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    let snapshot = engine.snapshot()?;
+
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(engine.get("short".to_owned())?, None);
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(snapshot.get("short".to_owned())?, Some("lived".to_owned()));
+    let pairs = snapshot
+        .scan_prefix("key".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    drop(snapshot);
+    assert_eq!(engine.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// Transactions read a consistent view of the engine, commit all their
+/// writes or none, and fail on write-write conflicts
+pub fn transactions<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = temp_dir();
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut transaction = engine.begin()?;
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    transaction.set("key1".to_owned(), "value2".to_owned())?;
+    transaction.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    transaction.commit()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The first of two transactions writing the same key to commit wins
+    let mut first = engine.begin()?;
+    let mut second = engine.begin()?;
+    first.set("key1".to_owned(), "first".to_owned())?;
+    second.set("key1".to_owned(), "second".to_owned())?;
+    first.commit()?;
+    match second.commit() {
+        Err(KvStoreError::TransactionConflict) => {}
+        other => panic!("expected a transaction conflict, got {:?}", other),
+    }
+    assert_eq!(engine.get("key1".to_owned())?, Some("first".to_owned()));
+
+    // Removing a key counts as writing it
+    let mut transaction = engine.begin()?;
+    transaction.set("key2".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    match transaction.commit() {
+        Err(KvStoreError::TransactionConflict) => {}
+        other => panic!("expected a transaction conflict, got {:?}", other),
+    }
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // Reads keep seeing what they saw first, and nothing is written on abort
+    let mut transaction = engine.begin()?;
+    assert_eq!(transaction.get("key3".to_owned())?, None);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(transaction.get("key3".to_owned())?, None);
+    transaction.remove("key1".to_owned())?;
+    transaction.abort();
+    assert_eq!(engine.get("key1".to_owned())?, Some("first".to_owned()));
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("first".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
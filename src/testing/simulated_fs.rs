@@ -0,0 +1,408 @@
+use crate::store::{FileHandle, FileSystem};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The kinds of call a `SimulatedFileSystem` can be told to fail at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    /// Opening or creating a file
+    Open,
+    /// Listing a directory
+    ReadDir,
+    /// Reading from a file
+    Read,
+    /// Appending to a file
+    Append,
+    /// Syncing a file
+    Sync,
+    /// Getting a file's length
+    Stat,
+    /// Renaming a file
+    Rename,
+    /// Removing a file
+    Remove,
+    /// Truncating a file
+    Truncate,
+    /// Syncing a directory
+    SyncDir,
+}
+
+/// An in-memory filesystem which only keeps what was synced when it crashes.
+///
+/// File contents are durable once the file is synced, and files created,
+/// renamed or removed only stay that way once their directory is synced.
+/// It can be told to fail at a chosen call, after which every call fails
+/// as if the machine had died, until `crash` brings it back, or to fail just
+/// the one call. A failed append still writes the first half of its buffer,
+/// as a short write would
+/// ```rust
+/// extern crate kvs;
+/// use kvs::testing::SimulatedFileSystem;
+/// use kvs::{KvStore, KvStoreOptions, KvsEngine, SyncPolicy};
+/// use std::path::Path;
+/// use std::sync::Arc;
+///
+/// let file_system = Arc::new(SimulatedFileSystem::new());
+/// let options = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::Always)
+///     .file_system(file_system.clone());
+/// let store = KvStore::open_with(Path::new("/store"), options.clone()).unwrap();
+/// store.set("key".to_owned(), "value".to_owned()).unwrap();
+/// drop(store);
+///
+/// file_system.crash();
+/// let store = KvStore::open_with(Path::new("/store"), options).unwrap();
+/// assert_eq!(store.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+/// ```
+#[derive(Debug, Default)]
+pub struct SimulatedFileSystem {
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+#[derive(Debug, Default)]
+struct SimulatedState {
+    files: BTreeMap<u64, SimulatedFile>,
+    next_file: u64,
+    /// The file each path points at now
+    entries: BTreeMap<PathBuf, u64>,
+    /// The file each path will point at after a crash
+    durable_entries: BTreeMap<PathBuf, u64>,
+    calls: u64,
+    fault: Option<Fault>,
+    /// Set once the fault has been hit
+    failed: bool,
+    /// Bumped by every crash so handles opened before it stop working
+    epoch: u64,
+}
+
+#[derive(Debug, Default)]
+struct SimulatedFile {
+    data: Vec<u8>,
+    /// What the file held when it was last synced
+    durable: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Fault {
+    /// Only calls of this kind count towards the fault, or every call if `None`
+    syscall: Option<Syscall>,
+    /// How many more counted calls succeed
+    remaining: u64,
+    /// Whether every call after the one which fails succeeds again
+    transient: bool,
+}
+
+/// A handle on one of a `SimulatedFileSystem`'s files
+#[derive(Debug)]
+struct SimulatedHandle {
+    state: Arc<Mutex<SimulatedState>>,
+    file: u64,
+    epoch: u64,
+}
+
+impl SimulatedFileSystem {
+    /// An empty filesystem
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many calls have been made so far, failed ones included
+    pub fn calls(&self) -> u64 {
+        self.lock().calls
+    }
+
+    /// Let `calls` more calls through, then fail every call until the next crash
+    pub fn fail_after(&self, calls: u64) {
+        self.lock().fault = Some(Fault {
+            syscall: None,
+            remaining: calls,
+            transient: false,
+        });
+    }
+
+    /// Let `calls` more calls of one kind through, then fail every call
+    /// of any kind until the next crash
+    pub fn fail_after_calls_to(&self, syscall: Syscall, calls: u64) {
+        self.lock().fault = Some(Fault {
+            syscall: Some(syscall),
+            remaining: calls,
+            transient: false,
+        });
+    }
+
+    /// Let `calls` more calls of one kind through, then fail only the next
+    /// one, as a disk which briefly filled up would
+    pub fn fail_one_call_to(&self, syscall: Syscall, calls: u64) {
+        self.lock().fault = Some(Fault {
+            syscall: Some(syscall),
+            remaining: calls,
+            transient: true,
+        });
+    }
+
+    /// Put a file in place as if it had been written and synced before
+    /// anything else used the filesystem
+    pub fn write_file(&self, path: &Path, contents: &[u8]) {
+        let mut state = self.lock();
+        let file = state.create(path);
+        *state.file(file) = SimulatedFile {
+            data: contents.to_vec(),
+            durable: contents.to_vec(),
+        };
+        state.durable_entries.insert(path.to_path_buf(), file);
+    }
+
+    /// Whether a fault has been hit since the last crash
+    pub fn has_failed(&self) -> bool {
+        self.lock().failed
+    }
+
+    /// Lose everything which wasn't synced, and clear any fault
+    pub fn crash(&self) {
+        self.lock().crash(None);
+    }
+
+    /// Crash, but keep part of what was appended to each file since it was last
+    /// synced, as if the disk had torn the writes. `seed` picks where each
+    /// file is torn
+    pub fn crash_torn(&self, seed: u64) {
+        self.lock().crash(Some(seed));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimulatedState> {
+        lock(&self.state)
+    }
+
+    fn handle(&self, state: &SimulatedState, file: u64) -> Arc<dyn FileHandle> {
+        Arc::new(SimulatedHandle {
+            state: self.state.clone(),
+            file,
+            epoch: state.epoch,
+        })
+    }
+}
+
+// A panic while holding the lock leaves nothing half changed, so carry on past poisoning
+fn lock(state: &Mutex<SimulatedState>) -> MutexGuard<'_, SimulatedState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn simulated_failure(syscall: Syscall) -> io::Error {
+    io::Error::other(format!("Simulated failure of {:?}", syscall))
+}
+
+fn opened_before_crash() -> io::Error {
+    io::Error::other("File was opened before the filesystem crashed")
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such file {:?}", path))
+}
+
+impl SimulatedState {
+    /// Count a call, failing it if the fault is due or has already been hit
+    fn call(&mut self, syscall: Syscall) -> io::Result<()> {
+        self.calls += 1;
+        if let Some(fault) = &mut self.fault {
+            if fault.syscall.is_none() || fault.syscall == Some(syscall) {
+                if fault.remaining > 0 {
+                    fault.remaining -= 1;
+                } else if fault.transient {
+                    self.fault = None;
+                    return Err(simulated_failure(syscall));
+                } else {
+                    self.failed = true;
+                }
+            }
+        }
+        if self.failed {
+            return Err(simulated_failure(syscall));
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, file: u64) -> &mut SimulatedFile {
+        self.files.get_mut(&file).expect("open file was dropped")
+    }
+
+    fn create(&mut self, path: &Path) -> u64 {
+        if let Some(file) = self.entries.get(path) {
+            return *file;
+        }
+        let file = self.next_file;
+        self.next_file += 1;
+        self.files.insert(file, SimulatedFile::default());
+        self.entries.insert(path.to_path_buf(), file);
+        file
+    }
+
+    fn crash(&mut self, seed: Option<u64>) {
+        let mut rng = seed;
+        self.entries = self.durable_entries.clone();
+        let live: Vec<u64> = self.entries.values().cloned().collect();
+        self.files.retain(|file, _| live.contains(file));
+        for file in self.files.values_mut() {
+            let unsynced = if file.data.starts_with(&file.durable) {
+                file.data.len() - file.durable.len()
+            } else {
+                // Truncated since the last sync, which is lost along with what followed
+                0
+            };
+            let kept = match &mut rng {
+                Some(rng) if unsynced > 0 => (next_random(rng) % (unsynced as u64 + 1)) as usize,
+                _ => 0,
+            };
+            let torn = &file.data[file.durable.len()..file.durable.len() + kept];
+            file.durable.extend_from_slice(torn);
+            file.data = file.durable.clone();
+        }
+        self.fault = None;
+        self.failed = false;
+        self.epoch += 1;
+    }
+}
+
+/// Splitmix64, plenty for picking where writes tear
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl FileSystem for SimulatedFileSystem {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let mut state = self.lock();
+        state.call(Syscall::Open)?;
+        let file = *state.entries.get(path).ok_or_else(|| not_found(path))?;
+        Ok(self.handle(&state, file))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let mut state = self.lock();
+        state.call(Syscall::Open)?;
+        let file = state.create(path);
+        Ok(self.handle(&state, file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn FileHandle>> {
+        let mut state = self.lock();
+        state.call(Syscall::Open)?;
+        let file = state.create(path);
+        state.file(file).data.clear();
+        Ok(self.handle(&state, file))
+    }
+
+    fn read_dir(&self, dirpath: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut state = self.lock();
+        state.call(Syscall::ReadDir)?;
+        Ok(state
+            .entries
+            .keys()
+            .filter(|path| path.parent() == Some(dirpath))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.call(Syscall::Rename)?;
+        let file = state.entries.remove(from).ok_or_else(|| not_found(from))?;
+        state.entries.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.call(Syscall::Remove)?;
+        state.entries.remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        state.call(Syscall::Truncate)?;
+        let file = *state.entries.get(path).ok_or_else(|| not_found(path))?;
+        state.file(file).data.truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_dir(&self, dirpath: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.call(Syscall::SyncDir)?;
+        let state = &mut *state;
+        state
+            .durable_entries
+            .retain(|path, _| path.parent() != Some(dirpath));
+        for (path, file) in &state.entries {
+            if path.parent() == Some(dirpath) {
+                state.durable_entries.insert(path.clone(), *file);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SimulatedHandle {
+    /// Count a call against the filesystem, failing it for handles opened before a crash
+    fn call(&self, syscall: Syscall) -> io::Result<MutexGuard<'_, SimulatedState>> {
+        let mut state = lock(&self.state);
+        state.call(syscall)?;
+        if state.epoch != self.epoch || !state.files.contains_key(&self.file) {
+            return Err(opened_before_crash());
+        }
+        Ok(state)
+    }
+}
+
+impl FileHandle for SimulatedHandle {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.call(Syscall::Read)?;
+        let data = &state.file(self.file).data;
+        let start = (offset as usize).min(data.len());
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        let already_failed = state.failed;
+        let result = state.call(Syscall::Append);
+        let current = state.epoch == self.epoch;
+        let file = match state.files.get_mut(&self.file) {
+            Some(file) if current => file,
+            _ => return Err(opened_before_crash()),
+        };
+        match result {
+            Ok(()) => {
+                file.data.extend_from_slice(buf);
+                Ok(())
+            }
+            Err(e) => {
+                // Only the call which hits the fault gets partway, as a short write would
+                if !already_failed {
+                    file.data.extend_from_slice(&buf[..buf.len() / 2]);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut state = self.call(Syscall::Sync)?;
+        let file = state.file(self.file);
+        file.durable = file.data.clone();
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        let mut state = self.call(Syscall::Stat)?;
+        Ok(state.file(self.file).data.len() as u64)
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a key has left before it expires, as returned by `KvsEngine::ttl`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ttl {
+    /// The key was set without a TTL and never expires
+    Forever,
+    /// The key expires once this much more time has passed
+    Remaining(Duration),
+}
+
+/// The current time in milliseconds since the Unix epoch, which is how
+/// expiry times are stored
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// When something set now with a TTL expires
+pub(crate) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Whether an expiry time has passed
+pub(crate) fn has_expired(expires_at: u64) -> bool {
+    has_expired_at(expires_at, now_millis())
+}
+
+/// Whether an expiry time had passed at `at`, in milliseconds since the Unix epoch
+pub(crate) fn has_expired_at(expires_at: u64, at: u64) -> bool {
+    expires_at <= at
+}
+
+/// What's left of the TTL of something expiring at `expires_at`, if ever.
+/// `None` if it has already expired
+pub(crate) fn remaining(expires_at: Option<u64>) -> Option<Ttl> {
+    let expires_at = match expires_at {
+        Some(expires_at) => expires_at,
+        None => return Some(Ttl::Forever),
+    };
+    let now = now_millis();
+    if expires_at <= now {
+        None
+    } else {
+        Some(Ttl::Remaining(Duration::from_millis(expires_at - now)))
+    }
+}
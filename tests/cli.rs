@@ -212,18 +212,324 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-client scan` lists keys in order, optionally a page at a time
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap the killed server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("b1", "value3"), ("a2", "value2"), ("a1", "value1")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tvalue1\na2\tvalue2\nb1\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tvalue1\na2\tvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tvalue1\n")
+        .stderr(contains("Next cursor: a1"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--limit", "5", "--cursor", "a1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a2\tvalue2\nb1\tvalue3\n")
+        .stderr(is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_cas() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap the killed server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "key1",
+            "value2",
+            "--expected",
+            "value1",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_ttl() {
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap the killed server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "soon", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid TTL"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// Values can be given in hex, base64 or a file, and come back untouched
+#[test]
+fn cli_binary_values() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap the killed server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "00ff0a", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("AP8K\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(&b"\x00\xff\x0a\n"[..]);
+
+    let value_path = temp_dir.path().join("value");
+    fs::write(&value_path, [0x00, 0x01, 0x02, 0xff]).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "--addr", addr, "--file"])
+        .arg(&value_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("000102ff\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "cas",
+            "key2",
+            "AQI=",
+            "--expected",
+            "AAEC/w==",
+            "--base64",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0102\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "0g", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid value"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
+    // The server's killed rather than stopped, and by default sled only
+    // flushes its writes every so often
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr, "--sync", "always"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // The server reopened below needs the db lock this one holds until it exits
+        child.wait().expect("unable to reap the killed server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -272,7 +578,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -305,6 +611,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to reap the killed server");
     });
     thread::sleep(Duration::from_secs(1));
 
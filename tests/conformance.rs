@@ -0,0 +1,17 @@
+use kvs::{testing, KvStore, KvStoreOptions, Result, SyncPolicy};
+
+#[test]
+fn kv_store_conformance() -> Result<()> {
+    testing::run_all(KvStore::open)
+}
+
+// Small segments so the suite also runs across sealed logs and compaction
+#[test]
+fn kv_store_small_segments_conformance() -> Result<()> {
+    testing::run_all(|path| KvStore::open_with(path, KvStoreOptions::new().max_segment_size(1024)))
+}
+
+#[test]
+fn sled_conformance() -> Result<()> {
+    testing::run_all(|path| testing::open_sled(path, SyncPolicy::default()))
+}
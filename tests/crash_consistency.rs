@@ -0,0 +1,295 @@
+use kvs::testing::{SimulatedFileSystem, Syscall};
+use kvs::{
+    CompactionPolicy, KvStore, KvStoreError, KvStoreOptions, KvsEngine, Result, SyncPolicy,
+    WriteBatch,
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Something the workload does to the store
+#[derive(Debug, Clone)]
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    /// Set the first pair and remove the second key atomically
+    Batch(Vec<u8>, Vec<u8>, Vec<u8>),
+    Compact,
+}
+
+impl Op {
+    fn apply(&self, model: &mut Model) {
+        match self {
+            Op::Set(key, value) => {
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Remove(key) => {
+                model.remove(key);
+            }
+            Op::Batch(key, value, removed) => {
+                model.insert(key.clone(), value.clone());
+                model.remove(removed);
+            }
+            Op::Compact => {}
+        }
+    }
+
+    fn run(&self, store: &KvStore) -> Result<()> {
+        let result = match self {
+            Op::Set(key, value) => store.set_bytes(key.clone(), value.clone()),
+            Op::Remove(key) => store.remove_bytes(key.clone()),
+            Op::Batch(key, value, removed) => {
+                let mut batch = WriteBatch::new();
+                batch.set(key.clone(), value.clone());
+                batch.remove(removed.clone());
+                store.write_batch(batch)
+            }
+            Op::Compact => store.compact(),
+        };
+        match result {
+            // Removing a missing key is a successful no-op as far as the model's concerned
+            Err(KvStoreError::NonExistentKeyError(_)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn key(id: usize) -> Vec<u8> {
+    format!("key{}", id % 8).into_bytes()
+}
+
+fn value(id: usize) -> Vec<u8> {
+    format!("value{}", id).repeat(id % 5 + 1).into_bytes()
+}
+
+// Overwrites and removes over a handful of keys, with segments small enough
+// that logs get sealed, hinted and compacted along the way
+fn workload() -> Vec<Op> {
+    (0..60)
+        .map(|i| match i % 10 {
+            0..=4 => Op::Set(key(i * 7), value(i)),
+            5 | 6 => Op::Remove(key(i * 3)),
+            7 | 8 => Op::Batch(key(i * 5), value(i), key(i * 5 + 1)),
+            _ => Op::Compact,
+        })
+        .collect()
+}
+
+fn options(file_system: &Arc<SimulatedFileSystem>, sync_policy: SyncPolicy) -> KvStoreOptions {
+    KvStoreOptions::new()
+        .max_segment_size(256)
+        .sync_policy(sync_policy)
+        .compaction_policy(CompactionPolicy {
+            dead_bytes_ratio: 0.1,
+            min_dead_bytes: 0,
+            max_log_files: 3,
+        })
+        .file_system(file_system.clone())
+}
+
+fn contents(store: &KvStore) -> Result<Model> {
+    store.scan_bytes(..)?.collect()
+}
+
+fn dirpath() -> &'static Path {
+    Path::new("/store")
+}
+
+/// Run the workload until something fails, returning what the store has
+/// acknowledged and the operation which failed, if any
+fn run_until_failure(file_system: &Arc<SimulatedFileSystem>) -> (Model, Option<Op>) {
+    let mut model = Model::new();
+    let store = match KvStore::open_with(dirpath(), options(file_system, SyncPolicy::Always)) {
+        Ok(store) => store,
+        Err(_) => return (model, None),
+    };
+    for op in workload() {
+        if op.run(&store).is_err() {
+            return (model, Some(op));
+        }
+        op.apply(&mut model);
+    }
+    (model, None)
+}
+
+/// Reopen after a crash and check the store holds every acknowledged write,
+/// and nothing else besides the write which was in flight, then check it
+/// can still be written to
+fn check_recovery(file_system: &Arc<SimulatedFileSystem>, model: Model, in_flight: Option<Op>) {
+    let store = KvStore::open_with(dirpath(), options(file_system, SyncPolicy::Always))
+        .expect("store didn't reopen after a crash");
+    let recovered = contents(&store).unwrap();
+    let mut applied = model.clone();
+    if let Some(op) = &in_flight {
+        op.apply(&mut applied);
+    }
+    assert!(
+        recovered == model || recovered == applied,
+        "recovered {:?} but acknowledged {:?} with {:?} in flight",
+        recovered,
+        model,
+        in_flight
+    );
+
+    store.set("after".to_owned(), "crash".to_owned()).unwrap();
+    drop(store);
+    file_system.crash();
+    let store = KvStore::open_with(dirpath(), options(file_system, SyncPolicy::Always)).unwrap();
+    assert_eq!(
+        store.get("after".to_owned()).unwrap(),
+        Some("crash".to_owned())
+    );
+}
+
+// Crashing at any call, whether the writes it left unsynced are lost or torn,
+// should never lose an acknowledged write or bring back a removed key
+#[test]
+fn crash_at_every_call() {
+    let file_system = Arc::new(SimulatedFileSystem::new());
+    let (model, in_flight) = run_until_failure(&file_system);
+    assert!(in_flight.is_none());
+    let calls = file_system.calls();
+
+    for fail_after in 0..calls {
+        let file_system = Arc::new(SimulatedFileSystem::new());
+        file_system.fail_after(fail_after);
+        let (model, in_flight) = run_until_failure(&file_system);
+        if fail_after % 2 == 0 {
+            file_system.crash();
+        } else {
+            file_system.crash_torn(fail_after);
+        }
+        check_recovery(&file_system, model, in_flight);
+    }
+
+    // And with nothing failing, everything is still there after a crash
+    file_system.crash();
+    check_recovery(&file_system, model, None);
+}
+
+// Failing each kind of call the first few times it's made mid-compaction
+// never loses or resurrects anything
+#[test]
+fn crash_during_compaction() {
+    let syscalls = [
+        Syscall::Open,
+        Syscall::Append,
+        Syscall::Sync,
+        Syscall::Rename,
+        Syscall::Remove,
+        Syscall::SyncDir,
+    ];
+    for syscall in &syscalls {
+        for calls in 0..4 {
+            let file_system = Arc::new(SimulatedFileSystem::new());
+            let store =
+                KvStore::open_with(dirpath(), options(&file_system, SyncPolicy::Always)).unwrap();
+            let mut model = Model::new();
+            for id in 0..40 {
+                let op = if id % 3 == 0 {
+                    Op::Remove(key(id + 1))
+                } else {
+                    Op::Set(key(id), value(id))
+                };
+                op.run(&store).unwrap();
+                op.apply(&mut model);
+            }
+
+            file_system.fail_after_calls_to(*syscall, calls);
+            let _ = store.compact();
+            drop(store);
+            file_system.crash_torn(calls);
+            check_recovery(&file_system, model, None);
+        }
+    }
+}
+
+// An append which fails partway through is cut back off the log, so writes
+// acknowledged after it are still there once the store's reopened
+#[test]
+fn failed_append_is_undone() -> Result<()> {
+    let file_system = Arc::new(SimulatedFileSystem::new());
+    // One log and no compaction, so the store's own writes are the only appends
+    let options = options(&file_system, SyncPolicy::Always)
+        .max_segment_size(1024 * 1024)
+        .compaction_policy(CompactionPolicy {
+            dead_bytes_ratio: 2.0,
+            min_dead_bytes: u64::MAX,
+            max_log_files: usize::MAX,
+        });
+    let store = KvStore::open_with(dirpath(), options.clone())?;
+    let mut model = Model::new();
+    for id in 0..40 {
+        if id % 10 == 5 {
+            file_system.fail_one_call_to(Syscall::Append, 0);
+            assert!(store.set_bytes(key(id), value(id)).is_err());
+        } else {
+            store.set_bytes(key(id), value(id))?;
+            model.insert(key(id), value(id));
+        }
+    }
+    drop(store);
+
+    file_system.crash();
+    let store = KvStore::open_with(dirpath(), options)?;
+    assert_eq!(contents(&store)?, model);
+    Ok(())
+}
+
+// The simulated filesystem really does lose writes which were never synced
+#[test]
+fn unsynced_writes_are_lost() -> Result<()> {
+    let file_system = Arc::new(SimulatedFileSystem::new());
+    let store = KvStore::open_with(dirpath(), options(&file_system, SyncPolicy::Never))?;
+    store.set("synced".to_owned(), "value".to_owned())?;
+    store.flush()?;
+    store.set("unsynced".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    file_system.crash();
+    let store = KvStore::open_with(dirpath(), options(&file_system, SyncPolicy::Never))?;
+    assert_eq!(store.get("synced".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("unsynced".to_owned())?, None);
+    Ok(())
+}
+
+// Writes torn anywhere, including in logs sealed since the last sync, only
+// ever lose what wasn't synced
+#[test]
+fn torn_writes_lose_only_unsynced() -> Result<()> {
+    for seed in 0..32 {
+        let file_system = Arc::new(SimulatedFileSystem::new());
+        // Without compaction every log sealed since the sync is still around to be torn
+        let options =
+            options(&file_system, SyncPolicy::Never).compaction_policy(CompactionPolicy {
+                dead_bytes_ratio: 2.0,
+                min_dead_bytes: u64::MAX,
+                max_log_files: usize::MAX,
+            });
+        let store = KvStore::open_with(dirpath(), options.clone())?;
+        for id in 0..10 {
+            store.set_bytes(key(id), value(id))?;
+        }
+        store.flush()?;
+        for id in 10..40 {
+            store.set_bytes(key(id), value(id))?;
+        }
+        drop(store);
+
+        file_system.crash_torn(seed);
+        let store = KvStore::open_with(dirpath(), options)?;
+        // Each key holds its last synced value or one written after it
+        for id in 0..8 {
+            let recovered = store.get_bytes(&key(id))?;
+            let possible: Vec<Vec<u8>> = (id + 8..40).step_by(8).map(value).collect();
+            assert!(
+                recovered.is_some_and(|value| possible.contains(&value)),
+                "{:?} wasn't recovered as any value it was set to since the last sync",
+                key(id)
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    testing, CompactionPolicy, KvStore, KvStoreError, KvStoreOptions, KvStoreSnapshot, KvsEngine,
+    KvsSnapshot, KvsTransaction, Result, SledKvsEngine, SyncPolicy, Ttl, WriteBatch,
+};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +136,828 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+fn dir_size(dir: &TempDir) -> u64 {
+    let entries = WalkDir::new(dir.path()).into_iter();
+    let len: walkdir::Result<u64> = entries
+        .map(|res| {
+            res.and_then(|entry| entry.metadata())
+                .map(|metadata| metadata.len())
+        })
+        .sum();
+    len.expect("fail to get directory size")
+}
+
+// Compacts on every open, so a suite check reopening the store finds its
+// writes in the compacted log file
+fn open_compacted(path: &Path) -> Result<KvStore> {
+    let store = KvStore::open(path)?;
+    store.compact()?;
+    Ok(store)
+}
+
+// Compaction should only happen when asked for once the policy can't trigger it
+#[test]
+fn explicit_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_policy(CompactionPolicy {
+        dead_bytes_ratio: 2.0,
+        min_dead_bytes: u64::MAX,
+        max_log_files: usize::MAX,
+    })?;
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let size_before = dir_size(&temp_dir);
+    store.compact()?;
+    let size_after = dir_size(&temp_dir);
+    assert!(size_after < size_before);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Writes racing with background compaction must never be lost
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..10 {
+                    store
+                        .set(format!("key{}-{}", thread_id, key_id), format!("{}", iter))
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for _ in 0..20 {
+        store.compact()?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    store.compact()?;
+
+    for thread_id in 0..4 {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, key_id))?,
+                Some("199".to_owned())
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..4 {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, key_id))?,
+                Some("199".to_owned())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Every `<generation>.log` file in the store's directory, oldest first
+fn log_files(dir: &TempDir) -> Vec<PathBuf> {
+    let mut paths: Vec<(u64, PathBuf)> = fs::read_dir(dir.path())
+        .expect("unable to read store directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|path| {
+            let generation = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((generation, path))
+        })
+        .collect();
+    paths.sort();
+    paths.into_iter().map(|(_, path)| path).collect()
+}
+
+// Log files the manifest doesn't list, like the output of a compaction which
+// crashed before finishing, should never be replayed
+#[test]
+fn unlisted_log_files_are_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // A stale copy with a newer generation than anything the store has written
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let stale_contents = fs::read(&active_log)?;
+    let stray_log = temp_dir.path().join("100.log");
+    fs::write(&stray_log, &stale_contents)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!stray_log.exists());
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    fs::write(&stray_log, &stale_contents)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Every `.hint` file in the store's directory
+fn hint_files(dir: &TempDir) -> Vec<PathBuf> {
+    fs::read_dir(dir.path())
+        .expect("unable to read store directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect()
+}
+
+// Fill a store with overwritten and removed keys spread over many sealed logs
+fn write_hinted_store(dir: &TempDir) -> Result<()> {
+    let options = KvStoreOptions::new()
+        .max_segment_size(20 * 1024)
+        .compaction_policy(CompactionPolicy {
+            dead_bytes_ratio: 2.0,
+            min_dead_bytes: u64::MAX,
+            max_log_files: usize::MAX,
+        });
+    let store = KvStore::open_with(dir.path(), options)?;
+    for iter in 0..5 {
+        for key_id in 0..500 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", iter, key_id),
+            )?;
+        }
+    }
+    for key_id in (0..500).step_by(7) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    Ok(())
+}
+
+fn check_hinted_store(dir: &TempDir) -> Result<()> {
+    let store = KvStore::open(dir.path())?;
+    for key_id in 0..500 {
+        let expected = if key_id % 7 == 0 {
+            None
+        } else {
+            Some(format!("value4-{}", key_id))
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// A store opened from hint files should be identical to one opened by replaying its logs
+#[test]
+fn hint_files_match_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_hinted_store(&temp_dir)?;
+
+    let hints = hint_files(&temp_dir);
+    assert!(!hints.is_empty());
+    check_hinted_store(&temp_dir)?;
+
+    for hint in hints {
+        fs::remove_file(hint)?;
+    }
+    check_hinted_store(&temp_dir)?;
+
+    // Replaying wrote the hints back out
+    assert!(!hint_files(&temp_dir).is_empty());
+
+    Ok(())
+}
+
+// Damaged hint files should be ignored in favour of replaying the log
+#[test]
+fn invalid_hint_files_fall_back_to_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_hinted_store(&temp_dir)?;
+
+    let hints = hint_files(&temp_dir);
+    assert!(!hints.is_empty());
+    for hint in hints {
+        let mut contents = fs::read(&hint)?;
+        let middle = contents.len() / 2;
+        contents[middle] ^= 0xff;
+        fs::write(&hint, &contents)?;
+    }
+    check_hinted_store(&temp_dir)?;
+
+    Ok(())
+}
+
+// A record cut short by a crash should be dropped, keeping everything before it
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let file = OpenOptions::new().write(true).open(&active_log)?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..9 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+
+    // The torn tail is gone, so new writes land on a clean record boundary
+    store.set("key9".to_owned(), "value9".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Batches the store has compacted read the same as when they were written
+#[test]
+fn write_batches() -> Result<()> {
+    testing::write_batches(&open_compacted)
+}
+
+// A batch cut short by a crash should be dropped as a whole
+#[test]
+fn torn_write_batch_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), "value".to_owned())?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..10 {
+        batch.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    batch.remove("before".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Only the batch's final record is damaged, but none of it may survive
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let file = OpenOptions::new().write(true).open(&active_log)?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    // The torn batch is gone, so new writes land on a clean record boundary
+    store.set("after".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("after".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Damage in the middle of an older log file should be reported rather than skipped
+#[test]
+fn mid_file_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(20 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "v".repeat(100);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    drop(store);
+
+    // Opening from a hint file never reads the log, so make sure it's replayed
+    for hint in hint_files(&temp_dir) {
+        fs::remove_file(hint)?;
+    }
+
+    let paths = log_files(&temp_dir);
+    assert!(paths.len() > 1);
+    let oldest_log = &paths[0];
+    let mut file = OpenOptions::new().read(true).write(true).open(oldest_log)?;
+    let middle = file.metadata()?.len() / 2;
+    let mut byte = [0u8; 1];
+    file.seek(SeekFrom::Start(middle))?;
+    file.read_exact(&mut byte)?;
+    file.seek(SeekFrom::Start(middle))?;
+    file.write_all(&[byte[0] ^ 0xff])?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::Corruption { path, offset }) => {
+            assert_eq!(&path, oldest_log);
+            assert!(offset <= middle);
+        }
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A damaged length in the middle of the active log mustn't be mistaken for a
+// torn write and have every record after it truncated away
+#[test]
+fn mid_active_log_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    drop(store);
+
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let mut contents = fs::read(&active_log)?;
+    // Step over the log's header and the first half of the records by their lengths
+    let mut offset = 8;
+    for _ in 0..50 {
+        let len = u32::from_le_bytes([
+            contents[offset],
+            contents[offset + 1],
+            contents[offset + 2],
+            contents[offset + 3],
+        ]);
+        offset += 8 + len as usize;
+    }
+    // Enough to send the record's length well past the end of the log
+    contents[offset + 2] ^= 0x40;
+    fs::write(&active_log, &contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::Corruption { path, offset: at }) => {
+            assert_eq!(path, active_log);
+            assert_eq!(at, offset as u64);
+        }
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::read(&active_log)?, contents);
+
+    Ok(())
+}
+
+// A bson document as the store wrote `Record::Set` before records were framed
+fn unframed_set(key: &str, value: &str) -> Vec<u8> {
+    fn document(elements: &[u8]) -> Vec<u8> {
+        let mut document = ((elements.len() + 5) as i32).to_le_bytes().to_vec();
+        document.extend_from_slice(elements);
+        document.push(0);
+        document
+    }
+    fn string(name: &str, value: &str) -> Vec<u8> {
+        let mut element = vec![0x02];
+        element.extend_from_slice(name.as_bytes());
+        element.push(0);
+        element.extend_from_slice(&((value.len() + 1) as i32).to_le_bytes());
+        element.extend_from_slice(value.as_bytes());
+        element.push(0);
+        element
+    }
+
+    let array = document(&[string("0", key), string("1", value)].concat());
+    let mut set = vec![0x04];
+    set.extend_from_slice(b"Set\0");
+    set.extend_from_slice(&array);
+    document(&set)
+}
+
+// A log from before records were framed mustn't be mistaken for a torn write
+// and truncated, but refused untouched
+#[test]
+fn unframed_logs_are_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("0.log");
+    let contents = [
+        unframed_set("key1", "value1"),
+        unframed_set("key2", "value2"),
+    ]
+    .concat();
+    assert_eq!(contents.len(), 82);
+    fs::write(&log, &contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::UnknownLogFormat(path)) => assert_eq!(path, log),
+        other => panic!(
+            "expected an unknown format error, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+    assert_eq!(fs::read(&log)?, contents);
+
+    Ok(())
+}
+
+// A damaged record with more after it than a torn write could leave is
+// reported, however much it looks like one
+#[test]
+fn torn_write_recovery_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "v".repeat(2 * 1024 * 1024))?;
+    drop(store);
+
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let file = OpenOptions::new().write(true).open(&active_log)?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::Corruption { path, .. }) => assert_eq!(path, active_log),
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&active_log)?.len(), len - 3);
+
+    Ok(())
+}
+
+// Looking past a damaged record for later ones gives up after a few frames
+// which fail their checksums, rather than trying every byte
+#[test]
+fn damage_probe_gives_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+    // Each a frame header whose payload fits but doesn't match its checksum
+    let decoy = [&13u32.to_le_bytes()[..], &[0; 17]].concat();
+    store.set_bytes(b"key2".to_vec(), decoy.repeat(100))?;
+    drop(store);
+
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let file = OpenOptions::new().write(true).open(&active_log)?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 3)?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::Corruption { path, .. }) => assert_eq!(path, active_log),
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// The active log should roll over once it grows past the configured segment size
+#[test]
+fn max_segment_size_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    drop(store);
+
+    let log_count = log_files(&temp_dir).len();
+    assert!(log_count >= 9, "only {} log files written", log_count);
+
+    Ok(())
+}
+
+// A read-only store should serve reads without changing its directory
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let size_before = dir_size(&temp_dir);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvStoreError::ReadOnly) => {}
+        other => panic!("expected a read-only error, got {:?}", other),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvStoreError::ReadOnly) => {}
+        other => panic!("expected a read-only error, got {:?}", other),
+    }
+    match store.compact() {
+        Err(KvStoreError::ReadOnly) => {}
+        other => panic!("expected a read-only error, got {:?}", other),
+    }
+    drop(store);
+    assert_eq!(dir_size(&temp_dir), size_before);
+
+    Ok(())
+}
+
+// Every sync policy should behave the same for both engines apart from durability
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Every(Duration::from_millis(5)),
+        SyncPolicy::Every(Duration::from_millis(0)),
+    ];
+    for policy in policies.iter().cloned() {
+        testing::concurrent_writes(&|path: &Path| {
+            KvStore::open_with(path, KvStoreOptions::new().sync_policy(policy))
+        })?;
+        testing::concurrent_writes(&|path: &Path| testing::open_sled(path, policy))?;
+    }
+
+    assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+    assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+    assert_eq!(
+        "10ms".parse(),
+        Ok(SyncPolicy::Every(Duration::from_millis(10)))
+    );
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+    assert!("0ms".parse::<SyncPolicy>().is_err());
+    assert!("0".parse::<SyncPolicy>().is_err());
+
+    Ok(())
+}
+
+fn scanned<I: Iterator<Item = Result<(String, String)>>>(pairs: I) -> Result<Vec<String>> {
+    pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+}
+
+// Scans read keys in order from the log files compaction writes
+#[test]
+fn scans() -> Result<()> {
+    testing::scans(&open_compacted)
+}
+
+// Writers committed together in one batch must still apply in order, and a
+// bad remove only fails the writer who made it
+#[test]
+fn group_commit_keeps_write_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().sync_policy(SyncPolicy::Always),
+    )?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let key = format!("key{}", thread_id);
+                for i in 0..100 {
+                    store.set(key.clone(), format!("value{}", i)).unwrap();
+                    if i % 3 == 1 {
+                        store.remove(key.clone()).unwrap();
+                        assert!(store.remove(key.clone()).is_err());
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get(format!("key{}", thread_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get(format!("key{}", thread_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Compare and swap only writes when the value matches, even when contending
+// writers are committed together in one batch
+#[test]
+fn compare_and_swap() -> Result<()> {
+    testing::compare_and_swap(&|path: &Path| {
+        KvStore::open_with(path, KvStoreOptions::new().sync_policy(SyncPolicy::Always))
+    })
+}
+
+// Sled swaps keys with no expiry and no snapshot to keep them for in place,
+// and still drops expiries and keeps old values when there are
+#[test]
+fn sled_compare_and_swap_bookkeeping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), SyncPolicy::Never)?;
+    let value = |v: &str| Some(v.to_owned());
+
+    engine.set("plain".to_owned(), "1".to_owned())?;
+    engine.set_with_ttl(
+        "expiring".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(
+        engine.compare_and_swap("plain".to_owned(), value("1"), value("2"))?,
+        Ok(())
+    );
+    assert_eq!(
+        engine.compare_and_swap("expiring".to_owned(), value("1"), value("2"))?,
+        Ok(())
+    );
+    assert_eq!(engine.ttl("expiring".to_owned())?, Some(Ttl::Forever));
+    assert_eq!(engine.stats()?.pinned_bytes, 0);
+
+    let snapshot = engine.snapshot()?;
+    assert_eq!(
+        engine.compare_and_swap("plain".to_owned(), value("2"), None)?,
+        Ok(())
+    );
+    assert!(engine.set_if_absent("absent".to_owned(), "3".to_owned())?);
+    assert_eq!(snapshot.get("plain".to_owned())?, value("2"));
+    assert_eq!(snapshot.get("absent".to_owned())?, None);
+    drop(snapshot);
+
+    assert_eq!(engine.get("plain".to_owned())?, None);
+    assert_eq!(engine.get("absent".to_owned())?, value("3"));
+    Ok(())
+}
+
+// Sled hides expired keys from reads without writing anything, and its
+// sweeper removes them. A snapshot keeps what's removed under it, which
+// shows when each removal happened
+#[test]
+fn sled_sweeps_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), SyncPolicy::Never)?;
+    for key in &["read", "unread"] {
+        engine.set_with_ttl(
+            (*key).to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    let snapshot = engine.snapshot()?;
+    thread::sleep(Duration::from_millis(150));
+
+    assert_eq!(engine.get("read".to_owned())?, None);
+    assert_eq!(engine.ttl("read".to_owned())?, None);
+    assert_eq!(engine.stats()?.pinned_bytes, 0);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(engine.stats()?.pinned_bytes > 0);
+    assert_eq!(snapshot.get("read".to_owned())?, Some("value".to_owned()));
+    assert_eq!(snapshot.get("unread".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("unread".to_owned())?, None);
+
+    Ok(())
+}
+
+// Sled's errors keep their cause rather than being flattened into a message,
+// so an I/O failure in sled still reads as one
+#[test]
+fn sled_errors_keep_their_cause() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A file where the db's directory should be
+    let path = temp_dir.path().join("file");
+    fs::write(&path, b"not a db").unwrap();
+
+    let err = SledKvsEngine::open(&path).unwrap_err();
+    match &err {
+        KvStoreError::SledError(_) => {}
+        err => panic!("unexpected error {:?}", err),
+    }
+    let source = err.source().expect("sled error lost its cause");
+    assert_eq!(source.to_string(), err.to_string());
+    assert!(std::io::Error::from(err).raw_os_error().is_some());
+}
+
+// Expiry times survive reopening, through both replay and hint files, and
+// compaction drops expired keys rather than rewriting them
+#[test]
+fn ttls_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "later".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(200),
+        )?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("later".to_owned())? != Some(Ttl::Forever));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    let log_len = |dir: &TempDir| -> u64 {
+        log_files(dir)
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let len_before = log_len(&temp_dir);
+    store.compact()?;
+    // Only the key which hasn't expired is left
+    assert!(log_len(&temp_dir) < len_before / 50);
+    drop(store);
+
+    // The compacted log is reopened from its hint file
+    let store = KvStore::open(temp_dir.path())?;
+    match store.ttl("later".to_owned())? {
+        Some(Ttl::Remaining(remaining)) => assert!(remaining > Duration::from_secs(3500)),
+        ttl => panic!("unexpected TTL {:?}", ttl),
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("later".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The sweeper writes a tombstone for every key that expires, without anything
+// having to read it
+#[test]
+fn sweeper_removes_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().sweep_interval(Duration::from_millis(50)),
+    )?;
+    store.set_with_ttl(
+        "key".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "rewritten".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("rewritten".to_owned(), "value".to_owned())?;
+    let active_log = log_files(&temp_dir).pop().expect("no log files written");
+    let len = fs::metadata(&active_log)?.len();
+
+    thread::sleep(Duration::from_millis(500));
+    let swept_len = fs::metadata(&active_log)?.len();
+    assert!(swept_len > len);
+    // Only the key which is still expiring gets a tombstone, and only once
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(fs::metadata(&active_log)?.len(), swept_len);
+    assert_eq!(store.get("rewritten".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("rewritten".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +1040,261 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A snapshot keeps reading the store as it was, even once compaction has
+// replaced the log files it reads from
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let old_logs = log_files(&temp_dir);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.snapshots, 1);
+    assert!(stats.pinned_bytes > 0);
+    assert!(old_logs.iter().all(|path| path.exists()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.scan::<std::ops::RangeFull>(..)?.count(), 2);
+
+    drop(snapshot);
+    let stats = store.stats()?;
+    assert_eq!(stats.snapshots, 0);
+    assert_eq!(stats.pinned_bytes, 0);
+    assert!(old_logs.iter().all(|path| !path.exists()));
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), SyncPolicy::Never)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let snapshot = engine.snapshot()?;
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.snapshots, 1);
+    assert!(stats.pinned_bytes > 0);
+
+    drop(snapshot);
+    let stats = engine.stats()?;
+    assert_eq!(stats.snapshots, 0);
+    assert_eq!(stats.pinned_bytes, 0);
+    assert_eq!(engine.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+// Each snapshot sees the versions from when it was taken, through later
+// commits, compaction and the release of other snapshots
+#[test]
+fn snapshots_see_their_own_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |v: &str| Some(v.to_owned());
+    let keys = |snapshot: &KvStoreSnapshot| -> Result<Vec<String>> {
+        scanned(snapshot.scan::<std::ops::RangeFull>(..)?)
+    };
+
+    store.set("key".to_owned(), "1".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "lived".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    let first = store.snapshot()?;
+    store.set("key".to_owned(), "2".to_owned())?;
+    store.set("added".to_owned(), "2".to_owned())?;
+    let second = store.snapshot()?;
+    store.set("key".to_owned(), "3".to_owned())?;
+    store.remove("added".to_owned())?;
+
+    // Compaction drops the expired key while the first snapshot can still see it
+    thread::sleep(Duration::from_millis(150));
+    store.compact()?;
+    let third = store.snapshot()?;
+    store.set("key".to_owned(), "4".to_owned())?;
+
+    assert_eq!(first.get("key".to_owned())?, value("1"));
+    assert_eq!(first.get("short".to_owned())?, value("lived"));
+    assert_eq!(keys(&first)?, vec!["key", "short"]);
+    assert_eq!(second.get("key".to_owned())?, value("2"));
+    assert_eq!(second.get("added".to_owned())?, value("2"));
+    assert_eq!(keys(&second)?, vec!["added", "key", "short"]);
+
+    // Releasing the oldest snapshot removes the files only it needed
+    drop(first);
+    assert_eq!(second.get("key".to_owned())?, value("2"));
+    assert_eq!(keys(&second)?, vec!["added", "key", "short"]);
+    assert_eq!(third.get("key".to_owned())?, value("3"));
+    assert_eq!(third.get("short".to_owned())?, None);
+    assert_eq!(keys(&third)?, vec!["key"]);
+
+    drop(second);
+    drop(third);
+    let stats = store.stats()?;
+    assert_eq!(stats.snapshots, 0);
+    assert_eq!(stats.pinned_bytes, 0);
+    assert_eq!(store.get("key".to_owned())?, value("4"));
+
+    // Scans spanning several pages merge the index with superseded versions
+    let paged: Vec<String> = (0..600).map(|i| format!("paged{:04}", i)).collect();
+    for key in &paged {
+        store.set(key.clone(), "value".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    for (i, key) in paged.iter().enumerate() {
+        if i % 2 == 0 {
+            store.remove(key.clone())?;
+        } else {
+            store.set(format!("{}+", key), "added".to_owned())?;
+        }
+    }
+    assert_eq!(scanned(snapshot.scan_prefix("paged".to_owned())?)?, paged);
+
+    Ok(())
+}
+
+// Sled writes go straight to the db while there's no snapshot to keep old
+// values for, and still drop the expiries of keys which had one
+#[test]
+fn sled_writes_without_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), SyncPolicy::Never)?;
+    let forever = Duration::from_secs(3600);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl("key2".to_owned(), "value2".to_owned(), forever)?;
+    engine.set_with_ttl("key3".to_owned(), "value3".to_owned(), forever)?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "batched1");
+    batch.set("key2", "batched2");
+    engine.write_batch(batch)?;
+    engine.set("key3".to_owned(), "set3".to_owned())?;
+    assert_eq!(engine.ttl("key2".to_owned())?, Some(Ttl::Forever));
+    assert_eq!(engine.ttl("key3".to_owned())?, Some(Ttl::Forever));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "batched4");
+    batch.remove("key3");
+    engine.write_batch(batch)?;
+    engine.remove("key1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_err());
+    assert_eq!(engine.stats()?.pinned_bytes, 0);
+
+    let keys = scanned(engine.scan::<std::ops::RangeFull>(..)?)?;
+    assert_eq!(keys, vec!["key2", "key4"]);
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("batched2".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, Some("batched4".to_owned()));
+
+    Ok(())
+}
+
+// A sled snapshot reads through to the live db, so it must stay consistent
+// while keys it has read or has yet to read are changed under it
+#[test]
+fn sled_snapshot_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), SyncPolicy::Never)?;
+    let expected: Vec<_> = (0..1000)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    for (key, value) in &expected {
+        engine.set(key.clone(), value.clone())?;
+    }
+    let snapshot = engine.snapshot()?;
+
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..3 {
+                for i in 0..1000 {
+                    let key = format!("key{:04}", i);
+                    match (i + round) % 3 {
+                        0 => engine.remove(key)?,
+                        1 => engine.set(key, format!("round{}", round))?,
+                        _ => engine.set(format!("{}+", key), "new".to_owned())?,
+                    }
+                }
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..5 {
+        let pairs = snapshot
+            .scan::<std::ops::RangeFull>(..)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, expected);
+    }
+    writer.join().unwrap()?;
+
+    let pairs = snapshot
+        .scan::<std::ops::RangeFull>(..)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, expected);
+    assert_eq!(
+        snapshot.get("key0001".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(snapshot.get("key0001+".to_owned())?, None);
+
+    Ok(())
+}
+
+// Transactions keep reading the versions they began with through compaction,
+// and still conflict with commits made before reopening
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "first".to_owned())?;
+
+    // Older versions stay readable through compaction until the transaction is done
+    let mut transaction = store.begin()?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("first".to_owned())
+    );
+    transaction.abort();
+    assert_eq!(store.stats()?.snapshots, 0);
+
+    // Open transactions only hold on to the versions later commits replace,
+    // and each still conflicts with commits made since it began
+    let transactions = (0..100)
+        .map(|i| {
+            store.set("key1".to_owned(), i.to_string())?;
+            store.begin()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(store.stats()?.snapshots, 100);
+    for (i, mut transaction) in transactions.into_iter().enumerate() {
+        assert_eq!(transaction.get("key1".to_owned())?, Some(i.to_string()));
+        transaction.set("key1".to_owned(), "stale".to_owned())?;
+        if i < 99 {
+            assert!(transaction.commit().is_err());
+        } else {
+            transaction.commit()?;
+        }
+    }
+    assert_eq!(store.stats()?.snapshots, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("stale".to_owned()));
+    drop(store);
+
+    // Commit sequence numbers carry on after reopening, from hint files and replay alike
+    let store = KvStore::open(temp_dir.path())?;
+    let mut transaction = store.begin()?;
+    transaction.set("key1".to_owned(), "reopened".to_owned())?;
+    transaction.commit()?;
+    let mut transaction = store.begin()?;
+    store.set("key1".to_owned(), "later".to_owned())?;
+    transaction.set("key1".to_owned(), "stale".to_owned())?;
+    assert!(transaction.commit().is_err());
+
+    Ok(())
+}
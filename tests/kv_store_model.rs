@@ -0,0 +1,104 @@
+use kvs::{CompactionPolicy, KvStore, KvStoreError, KvStoreOptions, KvsEngine, Result};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+/// Something done to the store, checked against the model
+#[derive(Debug, Clone)]
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Get(Vec<u8>),
+    Reopen,
+    Compact,
+}
+
+// Few enough keys that most operations hit one which was written before
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    (0u8..8).prop_map(|id| format!("key{}", id).into_bytes())
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (key(), prop::collection::vec(any::<u8>(), 0..96))
+            .prop_map(|(key, value)| Op::Set(key, value)),
+        2 => key().prop_map(Op::Remove),
+        2 => key().prop_map(Op::Get),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+// Tiny segments and a policy which compacts as soon as anything is dead, so
+// rollover and background compaction happen all the time
+fn options(max_segment_size: u64) -> KvStoreOptions {
+    KvStoreOptions::new()
+        .max_segment_size(max_segment_size)
+        .compaction_policy(CompactionPolicy {
+            dead_bytes_ratio: 0.1,
+            min_dead_bytes: 0,
+            max_log_files: 4,
+        })
+}
+
+// Every key in the store, in order, which should be exactly the model
+fn check_contents(store: &KvStore, model: &BTreeMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+    let pairs = store.scan_bytes(..)?.collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = model.clone().into_iter().collect();
+    assert_eq!(pairs, expected);
+    Ok(())
+}
+
+fn run_model(max_segment_size: u64, ops: Vec<Op>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), options(max_segment_size))?;
+    let mut model = BTreeMap::new();
+
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                store.set_bytes(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            Op::Remove(key) => match (store.remove_bytes(key.clone()), model.remove(&key)) {
+                (Ok(()), Some(_)) => {}
+                (Err(KvStoreError::NonExistentKeyError(_)), None) => {}
+                (result, expected) => panic!(
+                    "removing {:?} gave {:?} but the model had {:?}",
+                    key, result, expected
+                ),
+            },
+            Op::Get(key) => assert_eq!(store.get_bytes(&key)?, model.get(&key).cloned()),
+            Op::Reopen => {
+                drop(store);
+                store = KvStore::open_with(temp_dir.path(), options(max_segment_size))?;
+                check_contents(&store, &model)?;
+            }
+            Op::Compact => {
+                store.compact()?;
+                check_contents(&store, &model)?;
+            }
+        }
+    }
+
+    check_contents(&store, &model)?;
+    drop(store);
+    check_contents(
+        &KvStore::open_with(temp_dir.path(), options(max_segment_size))?,
+        &model,
+    )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Any sequence of operations should leave the store agreeing with a map
+    // which had the same operations applied
+    #[test]
+    fn kv_store_matches_model(
+        max_segment_size in prop_oneof![Just(64u64), Just(512), Just(1024 * 1024)],
+        ops in prop::collection::vec(op(), 1..120),
+    ) {
+        run_model(max_segment_size, ops).unwrap();
+    }
+}
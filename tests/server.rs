@@ -0,0 +1,598 @@
+use kvs::{
+    Command, KvStore, KvStoreError, KvStoreOptions, KvsClient, KvsEngine, KvsServer, Response,
+    SharedQueueThreadPool, ThreadPool, Ttl, WriteBatch,
+};
+use slog::{o, Discard, Logger};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Start a server on a free port, returning the address it's listening on
+fn start_server(temp_dir: &TempDir) -> String {
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    let logger = Logger::root(Discard, o!());
+    let mut server = KvsServer::new("127.0.0.1:0".to_owned(), store, logger);
+    server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .expect("can't start server");
+    thread::sleep(Duration::from_millis(500));
+    server.local_addr().expect("no bound address").to_string()
+}
+
+// Keys and values containing the old text protocol's delimiters should round trip
+#[test]
+fn binary_protocol_round_trip() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let key = "key:with\ncolons:and\nnewlines".as_bytes().to_vec();
+    let value = "value:\n:\r\n💾".as_bytes().to_vec();
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client
+            .send(Command::Set(key.clone(), value.clone()))
+            .unwrap(),
+        Response::Ok
+    );
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client.send(Command::Get(key.clone())).unwrap(),
+        Response::Value(Some(value))
+    );
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client.send(Command::Remove(key.clone())).unwrap(),
+        Response::Ok
+    );
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client.send(Command::Get(key)).unwrap(),
+        Response::Value(None)
+    );
+}
+
+// Clients speaking the legacy text protocol should still be served
+#[test]
+fn text_protocol_compatibility() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let send_text = |line: &str| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(line.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    assert_eq!(send_text("SET:key1:value1\n"), "OK:");
+    assert_eq!(
+        send_text("GET:key1\n"),
+        format!("OK:{}", base64::encode("value1"))
+    );
+    assert_eq!(
+        send_text("GET:key2\n"),
+        format!("OK:{}", base64::encode("NONE"))
+    );
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client.send(Command::Get("key1".into())).unwrap(),
+        Response::Value(Some("value1".into()))
+    );
+}
+
+// Malformed text requests should get an error back rather than take down the connection's thread
+#[test]
+fn text_protocol_malformed_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let send_text = |line: &[u8]| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.write_all(line).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let err = |message: &str| format!("ERR:{}", base64::encode(message));
+
+    assert_eq!(send_text(b"SET:key1\n"), err("Missing value in request"));
+    assert_eq!(send_text(b"GET\n"), err("Missing key in request"));
+    assert_eq!(send_text(b"REMOVE\n"), err("Missing key in request"));
+    assert_eq!(
+        send_text(b"GET:\xff\xfe\n"),
+        err("Invalid UTF-8 in request")
+    );
+
+    // The server's still up afterwards
+    assert_eq!(send_text(b"SET:key1:value1\n"), "OK:");
+}
+
+// A single client connection should serve many requests, including pipelined ones
+#[test]
+fn persistent_connection_pipelining() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    for i in 0..10 {
+        assert_eq!(
+            client
+                .send(Command::Set(
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes()
+                ))
+                .unwrap(),
+            Response::Ok
+        );
+    }
+
+    let mut commands: Vec<Command> = (0..1000)
+        .map(|i| {
+            Command::Set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    commands.extend((0..1000).map(|i| Command::Get(format!("key{}", i).into_bytes())));
+    commands.push(Command::Remove("missing".into()));
+    commands.push(Command::Get("missing".into()));
+
+    let responses = client.pipeline(commands).unwrap();
+    assert_eq!(responses.len(), 2002);
+    for response in &responses[..1000] {
+        assert_eq!(response.as_ref().unwrap(), &Response::Ok);
+    }
+    for (i, response) in responses[1000..2000].iter().enumerate() {
+        assert_eq!(
+            response.as_ref().unwrap(),
+            &Response::Value(Some(format!("value{}", i).into_bytes()))
+        );
+    }
+    assert!(responses[2000].is_err());
+    assert_eq!(responses[2001].as_ref().unwrap(), &Response::Value(None));
+
+    // The connection is still usable after a pipeline
+    assert_eq!(
+        client.send(Command::Get("key1".into())).unwrap(),
+        Response::Value(Some("value1".into()))
+    );
+}
+
+// SCAN should page through keys with a prefix using the returned cursor
+#[test]
+fn scan_pagination() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    let mut commands: Vec<Command> = (0..25)
+        .map(|i| {
+            Command::Set(
+                format!("a{:02}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    commands.push(Command::Set("b".into(), "other".into()));
+    for response in client.pipeline(commands).unwrap() {
+        assert_eq!(response.unwrap(), Response::Ok);
+    }
+
+    let mut pairs = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let response = client
+            .send(Command::Scan {
+                prefix: "a".into(),
+                cursor: cursor.take(),
+                limit: 10,
+            })
+            .unwrap();
+        pages += 1;
+        match response {
+            Response::Pairs {
+                pairs: page,
+                cursor: next_cursor,
+            } => {
+                assert!(page.len() <= 10);
+                pairs.extend(page);
+                cursor = next_cursor;
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(pages, 3);
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..25)
+        .map(|i| {
+            (
+                format!("a{:02}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    assert_eq!(pairs, expected);
+}
+
+// A BATCH command applies all of its writes
+#[test]
+fn batch_command() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client
+            .send(Command::Set("key1".into(), "value1".into()))
+            .unwrap(),
+        Response::Ok
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    assert_eq!(client.send(Command::Batch(batch)).unwrap(), Response::Ok);
+
+    assert_eq!(
+        client.send(Command::Get("key1".into())).unwrap(),
+        Response::Value(None)
+    );
+    assert_eq!(
+        client.send(Command::Get("key2".into())).unwrap(),
+        Response::Value(Some("value2".into()))
+    );
+    assert_eq!(
+        client.send(Command::Get("key3".into())).unwrap(),
+        Response::Value(Some("value3".into()))
+    );
+}
+
+// A CAS command only writes when the key has the expected value, and
+// otherwise returns the value it has
+#[test]
+fn cas_command() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    let mut cas = |expected: Option<&str>, new: Option<&str>| {
+        client
+            .send(Command::Cas {
+                key: "key".into(),
+                expected: expected.map(Vec::from),
+                new: new.map(Vec::from),
+            })
+            .unwrap()
+    };
+    assert_eq!(cas(None, Some("value1")), Response::Ok);
+    assert_eq!(
+        cas(None, Some("value2")),
+        Response::Mismatch(Some("value1".into()))
+    );
+    assert_eq!(cas(Some("value1"), Some("value2")), Response::Ok);
+    assert_eq!(cas(Some("value2"), None), Response::Ok);
+    assert_eq!(cas(Some("value2"), None), Response::Mismatch(None));
+
+    assert_eq!(
+        client.send(Command::Get("key".into())).unwrap(),
+        Response::Value(None)
+    );
+}
+
+// Keys set with a TTL report what's left of it and disappear once it runs out
+#[test]
+fn ttl_commands() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    let ttl = Duration::from_millis(300);
+    assert_eq!(
+        client
+            .send(Command::SetWithTtl("key".into(), "value".into(), ttl))
+            .unwrap(),
+        Response::Ok
+    );
+    match client.send(Command::Ttl("key".into())).unwrap() {
+        Response::Ttl(Some(Ttl::Remaining(remaining))) => assert!(remaining <= ttl),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert_eq!(
+        client.send(Command::Ttl("missing".into())).unwrap(),
+        Response::Ttl(None)
+    );
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(
+        client.send(Command::Get("key".into())).unwrap(),
+        Response::Value(None)
+    );
+    assert_eq!(
+        client.send(Command::Ttl("key".into())).unwrap(),
+        Response::Ttl(None)
+    );
+}
+
+// Keys and values which aren't UTF-8 go over the wire untouched
+#[test]
+fn binary_values() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x9f, 0x92, 0xff, 0x0a];
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client
+            .send(Command::Set(key.clone(), value.clone()))
+            .unwrap(),
+        Response::Ok
+    );
+    assert_eq!(
+        client.send(Command::Get(key.clone())).unwrap(),
+        Response::Value(Some(value.clone()))
+    );
+    assert_eq!(
+        client
+            .send(Command::Scan {
+                prefix: vec![0xff],
+                cursor: None,
+                limit: 10,
+            })
+            .unwrap(),
+        Response::Pairs {
+            pairs: vec![(key, value)],
+            cursor: None,
+        }
+    );
+}
+
+// A transaction is bound to the connection which began it, and its writes are
+// only seen by other connections once it commits
+#[test]
+fn transaction_commands() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    let mut other = KvsClient::new(addr.clone()).unwrap();
+    assert!(client.send(Command::Commit).is_err());
+
+    assert_eq!(client.send(Command::Begin).unwrap(), Response::Ok);
+    assert!(client.send(Command::Begin).is_err());
+    assert_eq!(
+        client
+            .send(Command::Set("key".into(), "value1".into()))
+            .unwrap(),
+        Response::Ok
+    );
+    assert_eq!(
+        client.send(Command::Get("key".into())).unwrap(),
+        Response::Value(Some("value1".into()))
+    );
+    assert_eq!(
+        other.send(Command::Get("key".into())).unwrap(),
+        Response::Value(None)
+    );
+    assert_eq!(client.send(Command::Commit).unwrap(), Response::Ok);
+    assert_eq!(
+        other.send(Command::Get("key".into())).unwrap(),
+        Response::Value(Some("value1".into()))
+    );
+
+    // A write committed since BEGIN makes the transaction fail
+    assert_eq!(client.send(Command::Begin).unwrap(), Response::Ok);
+    client
+        .send(Command::Set("key".into(), "value2".into()))
+        .unwrap();
+    other
+        .send(Command::Set("key".into(), "value3".into()))
+        .unwrap();
+    match client.send(Command::Commit) {
+        Err(KvStoreError::TransactionConflict) => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    assert_eq!(client.send(Command::Begin).unwrap(), Response::Ok);
+    client.send(Command::Remove("key".into())).unwrap();
+    assert_eq!(client.send(Command::Abort).unwrap(), Response::Ok);
+    assert_eq!(
+        client.send(Command::Get("key".into())).unwrap(),
+        Response::Value(Some("value3".into()))
+    );
+}
+
+// Stopping should wake the listener without another client connecting, let
+// idle connections go and flush the store before the server thread returns
+#[test]
+fn stop_is_prompt() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    let mut server = KvsServer::new("127.0.0.1:0".to_owned(), store, Logger::root(Discard, o!()));
+    let handle = server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .expect("can't start server");
+    let addr = server.local_addr().unwrap().to_string();
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    assert_eq!(
+        client
+            .send(Command::Set(b"key".to_vec(), b"value".to_vec()))
+            .unwrap(),
+        Response::Ok
+    );
+
+    let started = Instant::now();
+    server.stop();
+    handle.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(client.send(Command::Get(b"key".to_vec())).is_err());
+    assert!(TcpStream::connect(&addr).is_err());
+
+    drop(server);
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    assert_eq!(store.get_bytes(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+// Port 0 should pick a free port which the server reports once started
+#[test]
+fn listens_on_bound_addr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    let mut server = KvsServer::new("127.0.0.1:0".to_owned(), store, Logger::root(Discard, o!()));
+    assert_eq!(server.local_addr(), None);
+    let handle = server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .expect("can't start server");
+
+    let addr = server.local_addr().expect("no bound address");
+    assert_ne!(addr.port(), 0);
+    let mut client = KvsClient::new(addr.to_string()).unwrap();
+    assert_eq!(
+        client.send(Command::Get(b"key".to_vec())).unwrap(),
+        Response::Value(None)
+    );
+
+    drop(client);
+    server.stop();
+    handle.join().unwrap();
+}
+
+// Binding an address which is already in use should fail `start`
+#[test]
+fn start_reports_bind_error() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let mut server = KvsServer::new(addr, store, Logger::root(Discard, o!()));
+    assert!(server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .is_err());
+    assert_eq!(server.local_addr(), None);
+}
+
+// Error responses should carry a code which the client turns into the
+// matching error variant
+#[test]
+fn error_codes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir);
+
+    let mut client = KvsClient::new(addr.clone()).unwrap();
+    match client.send(Command::Remove(b"missing".to_vec())) {
+        Err(KvStoreError::NonExistentKeyError(message)) => assert_eq!(message, "Key not found"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    match client.send(Command::Commit) {
+        Err(KvStoreError::InvalidRequest(_)) => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    // An unknown command tag is rejected without closing the connection
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream.write_all(&[0xB5, 1, 0, 0, 0, 1, 0xFF]).unwrap();
+    let mut response = [0u8; 7];
+    stream.read_exact(&mut response).unwrap();
+    // Version, length, then the error tag and the invalid request code
+    assert_eq!(response[0], 1);
+    assert_eq!(&response[5..], &[8, 4]);
+}
+
+// Writes to a read-only server are invalid requests rather than I/O errors
+#[test]
+fn read_only_writes_are_invalid_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())
+        .and_then(|store| store.set("key1".to_owned(), "value1".to_owned()))
+        .expect("can't write KvStore");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))
+        .expect("can't open KvStore");
+    let mut server = KvsServer::new("127.0.0.1:0".to_owned(), store, Logger::root(Discard, o!()));
+    server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .expect("can't start server");
+    let addr = server.local_addr().unwrap().to_string();
+
+    let mut client = KvsClient::new(addr).unwrap();
+    assert_eq!(
+        client.send(Command::Get(b"key1".to_vec())).unwrap(),
+        Response::Value(Some(b"value1".to_vec()))
+    );
+    match client.send(Command::Set(b"key2".to_vec(), b"value2".to_vec())) {
+        Err(KvStoreError::InvalidRequest(message)) => {
+            assert!(message.ends_with("Store was opened read-only"))
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+// Idle connections give up their pool threads, so more clients than there
+// are threads can stay connected without starving a new one
+#[test]
+fn idle_connections_dont_starve_new_clients() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("can't open KvStore");
+    let mut server = KvsServer::new("127.0.0.1:0".to_owned(), store, Logger::root(Discard, o!()));
+    let handle = server
+        .start(SharedQueueThreadPool::new(2).unwrap())
+        .expect("can't start server");
+    let addr = server.local_addr().unwrap().to_string();
+
+    // A starved client blocks forever, so give up on it from here
+    let (finished, finish) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let mut idle_clients: Vec<_> = (0..4)
+            .map(|i| {
+                let mut client = KvsClient::new(addr.clone()).unwrap();
+                let key = format!("idle{}", i).into_bytes();
+                assert_eq!(
+                    client.send(Command::Set(key, b"value".to_vec())).unwrap(),
+                    Response::Ok
+                );
+                client
+            })
+            .collect();
+        // One which hasn't even picked a protocol yet
+        let _silent = TcpStream::connect(&addr).unwrap();
+
+        let mut client = KvsClient::new(addr.clone()).unwrap();
+        assert_eq!(
+            client.send(Command::Get(b"idle0".to_vec())).unwrap(),
+            Response::Value(Some(b"value".to_vec()))
+        );
+
+        // The idle clients pick up where they left off
+        for (i, client) in idle_clients.iter_mut().enumerate() {
+            let key = format!("idle{}", i).into_bytes();
+            assert_eq!(
+                client.send(Command::Get(key)).unwrap(),
+                Response::Value(Some(b"value".to_vec()))
+            );
+        }
+        finished.send(()).unwrap();
+    });
+    finish
+        .recv_timeout(Duration::from_secs(10))
+        .expect("a client wasn't answered");
+
+    let started = Instant::now();
+    server.stop();
+    handle.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,54 @@
+// Limits the size of files the whole process can write, so this has to be
+// its own test binary
+#![cfg(unix)]
+
+use kvs::{
+    Command, KvStoreError, KvsClient, KvsServer, Response, SharedQueueThreadPool, SledKvsEngine,
+    ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// A sled write which fails on disk reaches the client as an I/O error, not
+// as a missing key or an internal error
+#[test]
+fn sled_write_failure_is_an_io_error() {
+    // Writing past the limit fails with EFBIG instead of killing the process
+    unsafe {
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        let limit = libc::rlimit {
+            rlim_cur: 4 * 1024 * 1024,
+            rlim_max: 4 * 1024 * 1024,
+        };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limit), 0);
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path()).expect("can't open SledKvsEngine");
+    let mut server = KvsServer::new(
+        "127.0.0.1:0".to_owned(),
+        engine,
+        Logger::root(Discard, o!()),
+    );
+    server
+        .start(SharedQueueThreadPool::new(4).unwrap())
+        .expect("can't start server");
+    let addr = server.local_addr().unwrap().to_string();
+
+    let mut client = KvsClient::new(addr).unwrap();
+    assert_eq!(
+        client
+            .send(Command::Set(b"key".to_vec(), b"value".to_vec()))
+            .unwrap(),
+        Response::Ok
+    );
+
+    let value = vec![b'v'; 8 * 1024 * 1024];
+    // The client only turns an `ErrorCode::Io` response into `RemoteIo`
+    match client.send(Command::Set(b"big".to_vec(), value)) {
+        Err(KvStoreError::RemoteIo(_)) => {}
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    // Sled blocks forever flushing a db that failed a write, which dropping it does
+    std::mem::forget(server);
+}